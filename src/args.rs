@@ -1,7 +1,11 @@
 use std::fmt::{self, Display, Formatter};
 use std::path::PathBuf;
 
-use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use typst_tikz::tikz::{
+    ArrowPreset, Calibration, ExportFormat, FailurePolicy, LanguagePackage, LockMode,
+    Pipeline, Verbosity,
+};
 
 /// typst creates PDF files from .typ files
 #[derive(Debug, Clone, Parser)]
@@ -35,6 +39,8 @@ pub struct CliArguments {
 pub enum DiagnosticFormat {
     Human,
     Short,
+    /// One JSON object per line, for editors and CI annotators.
+    Json,
 }
 
 impl Display for DiagnosticFormat {
@@ -58,8 +64,36 @@ pub enum Command {
     #[command(visible_alias = "w")]
     Watch(CompileCommand),
 
+    /// Watches the input file and shows it in the browser, reloading on changes
+    Preview(PreviewCommand),
+
     /// List all discovered fonts in system and custom font paths
     Fonts(FontsCommand),
+
+    /// Removes TikZ figures from the shared cache
+    Prune(PruneCommand),
+
+    /// Removes all compiled TikZ figures from the shared cache
+    CleanCache(CleanCacheCommand),
+
+    /// Exports the shared cache of TikZ figures into an archive, or imports one
+    #[command(subcommand)]
+    Cache(CacheCommand),
+
+    /// Lists the TikZ figures in Typst files without compiling them
+    List(ListCommand),
+
+    /// Serves compiled TikZ figures over HTTP
+    Serve(ServeCommand),
+
+    /// Compiles TikZ code from stdin and writes the SVG to stdout
+    Render(RenderCommand),
+
+    /// Replaces the TikZ figures in a Typst file from stdin and writes it to stdout
+    Preprocess(PreprocessCommand),
+
+    /// Compiles the TikZ figures in a Typst file cold and warm and reports how long they take
+    Bench(BenchCommand),
 }
 
 impl Command {
@@ -68,13 +102,22 @@ impl Command {
         match self {
             Command::Compile(cmd) => Some(cmd),
             Command::Watch(cmd) => Some(cmd),
-            Command::Fonts(_) => None,
+            Command::Preview(cmd) => Some(&cmd.compile),
+            Command::Fonts(_)
+            | Command::Prune(_)
+            | Command::CleanCache(_)
+            | Command::Cache(_)
+            | Command::List(_)
+            | Command::Serve(_)
+            | Command::Render(_)
+            | Command::Preprocess(_)
+            | Command::Bench(_) => None,
         }
     }
 
-    /// Returns whether this is a watch command.
+    /// Returns whether this is a watch or preview command.
     pub fn is_watch(&self) -> bool {
-        matches!(self, Command::Watch(_))
+        matches!(self, Command::Watch(_) | Command::Preview(_))
     }
 }
 
@@ -98,6 +141,7 @@ pub struct CompileCommand {
     /// In which format to emit diagnostics
     #[clap(
         long,
+        alias = "format",
         default_value_t = DiagnosticFormat::Human,
         value_parser = clap::value_parser!(DiagnosticFormat)
     )]
@@ -106,6 +150,227 @@ pub struct CompileCommand {
     /// Produces a flamegraph of the compilation process
     #[arg(long = "flamegraph", value_name = "OUTPUT_SVG")]
     pub flamegraph: Option<Option<PathBuf>>,
+
+    /// Compiles all TikZ figures again instead of taking them from the cache
+    #[arg(long = "force")]
+    pub force: bool,
+
+    /// Prints the complete LaTeX log of the TikZ figure with this hash after compiling
+    #[arg(long = "show-log", value_name = "HASH")]
+    pub show_log: Option<u64>,
+
+    /// How to compile the TikZ figures
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Watches the input file and shows it in the browser, reloading on changes
+///
+/// Editors may POST the lines they show, e.g. `{"path": "notes.typ",
+/// "start": 10, "end": 60}`, to /visible, so that the TikZ figures on them are
+/// compiled first.
+#[derive(Debug, Clone, Parser)]
+pub struct PreviewCommand {
+    /// How to compile the input file
+    #[clap(flatten)]
+    pub compile: CompileCommand,
+
+    /// The address on which the preview is served
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:8472")]
+    pub address: String,
+}
+
+/// Options for compiling TikZ figures
+///
+/// Defaults can be set per project in a typst-tikz.toml next to the input or
+/// in one of its parent directories, and overridden by TYPST_TIKZ_ENGINE,
+/// TYPST_TIKZ_CACHE_DIR, TYPST_TIKZ_TIMEOUT and the like.
+#[derive(Debug, Clone, Args)]
+pub struct TikzArgs {
+    /// Converts the text of TikZ figures into paths (requires dvisvgm)
+    #[arg(long = "tikz-outline-text")]
+    pub outline_text: bool,
+
+    /// The font family for text in TikZ figures, usually the document's font
+    #[arg(long = "tikz-main-font", value_name = "FAMILY")]
+    pub main_font: Option<String>,
+
+    /// The font family for math in TikZ figures, usually the document's math font
+    #[arg(long = "tikz-math-font", value_name = "FAMILY")]
+    pub math_font: Option<String>,
+
+    /// The language of text in TikZ figures, e.g. german or [bidi=basic]arabic, for its hyphenation and script
+    #[arg(long = "tikz-language", value_name = "LANGUAGE")]
+    pub language: Option<String>,
+
+    /// The package that sets up the language of TikZ figures: babel or polyglossia [default: babel]
+    #[arg(long = "tikz-language-package", value_name = "PACKAGE")]
+    pub language_package: Option<LanguagePackage>,
+
+    /// The look of the arrows in tikzcd figures: quiver or thick-arrows [default: tikz-cd's own]
+    #[arg(long = "tikz-cd-preset", value_name = "PRESET")]
+    pub tikzcd_preset: Option<ArrowPreset>,
+
+    /// Scales the size of all TikZ figures by the given factor, on top of the calibration
+    #[arg(long = "tikz-scale", value_name = "FACTOR", default_value_t = 1.0)]
+    pub scale: f64,
+
+    /// Corrects the absolute sizes of TikZ figures so that TeX points match Typst points, by a factor or measured automatically (auto), before they are scaled [default: 1]
+    #[arg(long = "tikz-calibration", value_name = "FACTOR")]
+    pub calibration: Option<Calibration>,
+
+    /// The maximum number of TikZ figures to compile in parallel [default: number of CPUs]
+    #[arg(long = "tikz-jobs", value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// The directory in which compiled TikZ figures are shared across projects
+    #[arg(long = "tikz-cache-dir", value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Only caches compiled TikZ figures in memory
+    #[arg(long = "tikz-no-cache", conflicts_with = "cache_dir")]
+    pub no_cache: bool,
+
+    /// Writes the rewritten sources, the SVGs of all TikZ figures, and a manifest into this directory
+    #[arg(long = "tikz-export", value_name = "DIR")]
+    pub export: Option<PathBuf>,
+
+    /// The formats in which TikZ figures are exported, e.g. svg,pdf,png; PDFs and PNGs need no further compilation [default: svg]
+    #[arg(long = "tikz-export-format", value_name = "FORMAT", value_delimiter = ',')]
+    pub export_formats: Vec<ExportFormat>,
+
+    /// Writes a Makefile-style depfile with all files the output depends on, including those referenced by TikZ figures
+    #[arg(long = "tikz-depfile", value_name = "PATH")]
+    pub depfile: Option<PathBuf>,
+
+    /// Pins the SVGs of TikZ figures in typst-tikz.lock next to the input, and warns (warn) or fails (deny) when they change, or records the current ones (update)
+    #[arg(long = "tikz-lock", value_name = "MODE")]
+    pub lock: Option<LockMode>,
+
+    /// Reports warnings from the LaTeX logs of TikZ figures, like overfull boxes and missing characters
+    #[arg(long = "tikz-latex-warnings")]
+    pub latex_warnings: bool,
+
+    /// The path under which rewritten sources refer to TikZ figures, where {hash} is replaced by the hash of the figure
+    #[arg(long = "tikz-filename", value_name = "TEMPLATE")]
+    pub filename: Option<String>,
+
+    /// The Typst code that embeds TikZ figures, with {filename}, {hash}, {width}, {height} and {attributes} as placeholders [default: image("{filename}", {attributes})]
+    #[arg(long = "tikz-template", value_name = "TYPST")]
+    pub template: Option<String>,
+
+    /// Adds a directory in which LaTeX looks for style files and other inputs
+    #[clap(
+        long = "tikz-input-path",
+        env = "TYPST_TIKZ_INPUT_PATHS",
+        value_name = "DIR",
+        action = ArgAction::Append,
+    )]
+    pub input_paths: Vec<PathBuf>,
+
+    /// Replaces the bundled preamble of TikZ figures with the contents of this file, can be given multiple times
+    #[arg(long = "tikz-preamble", value_name = "FILE", action = ArgAction::Append)]
+    pub preamble: Vec<PathBuf>,
+
+    /// Loads an extra LaTeX package in TikZ figures, optionally with options in brackets, e.g. [compat=1.18]pgfplots
+    #[arg(long = "tikz-package", value_name = "PACKAGE", action = ArgAction::Append)]
+    pub packages: Vec<String>,
+
+    /// Loads a TikZ library in TikZ figures, e.g. arrows.meta
+    #[arg(long = "tikz-library", value_name = "LIBRARY", action = ArgAction::Append)]
+    pub libraries: Vec<String>,
+
+    /// Adds an option of the standalone document class for all TikZ figures, e.g. border=4pt or varwidth
+    #[arg(long = "tikz-class-option", value_name = "OPTION", action = ArgAction::Append)]
+    pub class_options: Vec<String>,
+
+    /// The LaTeX engine for TikZ figures; if given multiple times, the next engine is tried whenever one is missing or crashes [default: lualatex]
+    #[arg(long = "tikz-engine", value_name = "PROGRAM", action = ArgAction::Append)]
+    pub engines: Vec<String>,
+
+    /// The program that converts TikZ figures into SVGs, pdf2svg or dvisvgm [default: pdf2svg, or dvisvgm with --tikz-outline-text]
+    #[arg(long = "tikz-converter", value_name = "PROGRAM")]
+    pub converter: Option<String>,
+
+    /// Kills LaTeX and the converter if they run for longer than this many seconds on a TikZ figure
+    #[arg(long = "tikz-timeout", value_name = "SECONDS")]
+    pub timeout: Option<f64>,
+
+    /// Compiles TikZ figures on a typst-tikz server at this address instead of with a local LaTeX installation, e.g. http://tex.example.org:8471
+    #[arg(long = "tikz-remote", env = "TYPST_TIKZ_REMOTE", value_name = "URL")]
+    pub remote: Option<String>,
+
+    /// How TikZ figures become SVGs: through a PDF (pdf) or, faster and tighter, through a DVI file and dvisvgm (dvi) [default: pdf]
+    #[arg(long = "tikz-pipeline", value_name = "PIPELINE")]
+    pub pipeline: Option<Pipeline>,
+
+    /// Shows gray placeholders in place of TikZ figures if TeX is not installed, instead of failing
+    #[arg(long = "tikz-placeholders")]
+    pub placeholders: bool,
+
+    /// Leaves whitespace that TeX ignores, like indentation, out of the hashes of TikZ figures
+    #[arg(long = "tikz-normalize-whitespace")]
+    pub normalize_whitespace: bool,
+
+    /// Makes the SVGs of TikZ figures byte-identical across machines and runs [default: if SOURCE_DATE_EPOCH is set]
+    #[arg(long = "tikz-reproducible")]
+    pub reproducible: bool,
+
+    /// Keeps the LaTeX document, PDF, log and SVGs of each compiled TikZ figure in a subdirectory of DIR named by its hash
+    #[arg(long = "tikz-keep-intermediates", value_name = "DIR")]
+    pub keep_intermediates: Option<PathBuf>,
+
+    /// How much to log about TikZ figures: quiet, normal, verbose or debug [default: depends on -v]
+    #[arg(long = "tikz-verbosity", value_name = "LEVEL")]
+    pub verbosity: Option<Verbosity>,
+
+    /// Limits the memory of LaTeX and the converter to this many megabytes per TikZ figure (Unix only)
+    #[arg(
+        long = "tikz-max-memory",
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(..=u64::MAX / 1_000_000)
+    )]
+    pub max_memory: Option<u64>,
+
+    /// Limits the processor time of LaTeX and the converter to this many seconds per TikZ figure (Unix only)
+    #[arg(long = "tikz-max-cpu-time", value_name = "SECONDS")]
+    pub max_cpu_time: Option<u64>,
+
+    /// Limits each file that LaTeX and the converter write to this many megabytes (Unix only)
+    #[arg(
+        long = "tikz-max-output-size",
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(..=u64::MAX / 1_000_000)
+    )]
+    pub max_output_size: Option<u64>,
+
+    /// Embeds TikZ figures whose SVGs are larger than this many kilobytes as high-resolution PNGs instead
+    #[arg(long = "tikz-raster-max-size", value_name = "KB")]
+    pub raster_max_size: Option<u64>,
+
+    /// Embeds TikZ figures whose SVGs have more than this many elements as high-resolution PNGs instead
+    #[arg(long = "tikz-raster-max-elements", value_name = "N")]
+    pub raster_max_elements: Option<usize>,
+
+    /// The resolution of TikZ figures that are embedded as PNGs [default: 300]
+    #[arg(long = "tikz-raster-dpi", value_name = "DPI")]
+    pub raster_dpi: Option<u32>,
+
+    /// Passes all TikZ figures through the `tikz-figure` function of this Typst module next to the source, e.g. tikz.typ, which is generated unless it exists
+    #[arg(long = "tikz-helper", value_name = "PATH")]
+    pub helper: Option<String>,
+
+    /// Translates simple TikZ pictures into CeTZ code instead of compiling them (experimental)
+    #[arg(long = "tikz-cetz")]
+    pub cetz: bool,
+
+    /// The Typst function that shows the frames of animated TikZ figures one after another [default: alternatives]
+    #[arg(long = "tikz-reveal", value_name = "FUNCTION")]
+    pub reveal: Option<String>,
+
+    /// What to do about TikZ figures that fail to compile: leave them to Typst (defer), stop at the first (fail-fast), or report all at once (collect-all) [default: defer]
+    #[arg(long = "tikz-failure-policy", value_name = "POLICY")]
+    pub failure_policy: Option<FailurePolicy>,
 }
 
 /// List all discovered fonts in system and custom font paths
@@ -115,3 +380,165 @@ pub struct FontsCommand {
     #[arg(long)]
     pub variants: bool,
 }
+
+/// Removes TikZ figures from the shared cache
+#[derive(Debug, Clone, Parser)]
+pub struct PruneCommand {
+    /// Typst files, or directories with Typst files, whose TikZ figures are kept
+    pub inputs: Vec<PathBuf>,
+
+    /// Removes the figures that were not used for this many days
+    #[arg(
+        long = "max-age",
+        value_name = "DAYS",
+        value_parser = clap::value_parser!(u64).range(..=u64::MAX / (24 * 60 * 60))
+    )]
+    pub max_age: Option<u64>,
+
+    /// Removes the least recently used figures until the cache fits into this many megabytes
+    #[arg(
+        long = "max-size",
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(..=u64::MAX / 1_000_000)
+    )]
+    pub max_size: Option<u64>,
+
+    /// Also prunes the default cache, which the TikZ figures of all projects share
+    #[arg(long = "force")]
+    pub force: bool,
+
+    /// How the TikZ figures are compiled, which determines their hashes
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Removes all compiled TikZ figures from the shared cache
+#[derive(Debug, Clone, Parser)]
+pub struct CleanCacheCommand {
+    /// Which cache to clean
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Exports the shared cache of TikZ figures into an archive, or imports one
+#[derive(Debug, Clone, Subcommand)]
+pub enum CacheCommand {
+    /// Writes all TikZ figures in the shared cache into a zstd-compressed tarball
+    Export(CacheArchiveCommand),
+
+    /// Adds the TikZ figures in a tarball from `cache export` to the shared cache, so that they need no TeX
+    Import(CacheArchiveCommand),
+}
+
+/// The archive of a cache command
+#[derive(Debug, Clone, Parser)]
+pub struct CacheArchiveCommand {
+    /// The archive, e.g. tikz-cache.tar.zst
+    pub archive: PathBuf,
+
+    /// Which cache to use
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Lists the TikZ figures in Typst files without compiling them
+#[derive(Debug, Clone, Parser)]
+pub struct ListCommand {
+    /// Typst files, or directories with Typst files
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Prints the figures as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// How the TikZ figures are compiled, which determines their hashes
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Compiles TikZ code from stdin and writes the SVG to stdout
+///
+/// The code is the body of the environment, e.g. `\draw (0,0) -- (1,1);` for
+/// a tikzpicture. If it fails to compile, the LaTeX logs are printed instead.
+#[derive(Debug, Clone, Parser)]
+pub struct RenderCommand {
+    /// The LaTeX environment of the figure: tikzpicture, tikzcd or displaymath
+    #[arg(long, value_name = "ENVIRONMENT", default_value = "tikzpicture")]
+    pub environment: String,
+
+    /// Appends this LaTeX code to the preamble of the figure, e.g. \usepackage{pgfplots}
+    #[arg(long, value_name = "LATEX")]
+    pub preamble: Option<String>,
+
+    /// The options of the figure, like in Typst files, e.g. "border: 2pt"
+    #[arg(long, value_name = "OPTIONS", default_value = "")]
+    pub options: String,
+
+    /// How the TikZ figure is compiled
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Replaces the TikZ figures in a Typst file from stdin and writes it to stdout
+///
+/// The images of the figures are written into a directory, under the paths by
+/// which the rewritten file refers to them, so that it compiles with plain
+/// Typst once it is saved next to them.
+#[derive(Debug, Clone, Parser)]
+pub struct PreprocessCommand {
+    /// The directory into which the images are written, i.e. the one in which the rewritten file is saved
+    #[arg(long = "images", value_name = "DIR", default_value = ".")]
+    pub images: PathBuf,
+
+    /// The directory against which files that TikZ figures reference are resolved, i.e. that of the original file
+    #[arg(long = "dir", value_name = "DIR", default_value = ".")]
+    pub dir: PathBuf,
+
+    /// Follows each TikZ figure with metadata labelled <tikz-diagram> for `typst query`. The rewritten file then needs Typst 0.8 or later, and typst-tikz itself cannot compile it
+    #[arg(long = "tikz-metadata")]
+    pub metadata: bool,
+
+    /// How the TikZ figures are compiled
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Compiles the TikZ figures in a Typst file cold and warm and reports how long they take
+///
+/// The cold run compiles every figure from scratch, bypassing the shared
+/// cache, and reports the time each one spent in LaTeX, in the conversion into
+/// SVGs and on file access. The warm run replaces the figures again from the
+/// cache. The slowest figures are the first candidates for simplifying or for
+/// externalizing.
+#[derive(Debug, Clone, Parser)]
+pub struct BenchCommand {
+    /// The Typst file whose figures are compiled
+    pub input: PathBuf,
+
+    /// How many of the slowest figures to highlight
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub slowest: usize,
+
+    /// How the TikZ figures are compiled
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
+
+/// Serves compiled TikZ figures over HTTP
+///
+/// POST a JSON object with the `code` of a figure, and optionally its
+/// `environment` (tikzpicture, tikzcd or displaymath) and a `preamble`, to
+/// /render, and receive the SVG or the LaTeX logs. Other instances compile
+/// their figures here with `--tikz-remote`, through /document, and
+/// /toolchain reports the fingerprint of the LaTeX installation.
+#[derive(Debug, Clone, Parser)]
+pub struct ServeCommand {
+    /// The address to listen on
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:8471")]
+    pub address: String,
+
+    /// How the TikZ figures are compiled
+    #[clap(flatten)]
+    pub tikz: TikzArgs,
+}
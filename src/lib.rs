@@ -0,0 +1,6 @@
+//! TikZ support for Typst.
+//!
+//! Replaces the TikZ figures in Typst sources with images that are compiled
+//! by LaTeX. The `typst-tikz` command line interface is built on top of this.
+
+pub mod tikz;
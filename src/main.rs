@@ -1,5 +1,6 @@
 mod args;
-mod tikz;
+mod preview;
+mod serve;
 mod trace;
 
 use std::cell::{Cell, RefCell, RefMut};
@@ -7,8 +8,8 @@ use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File};
 use std::hash::Hash;
-use std::io::{self, IsTerminal, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process::ExitCode;
 
 use chrono::Datelike;
@@ -19,6 +20,7 @@ use comemo::Prehashed;
 use memmap2::Mmap;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use same_file::{is_same_file, Handle};
+use serde::Serialize;
 use siphasher::sip128::{Hasher128, SipHasher13};
 use std::cell::OnceCell;
 use termcolor::{ColorChoice, StandardStream, WriteColor};
@@ -28,15 +30,25 @@ use typst::diag::{
 use typst::doc::Document;
 use typst::eval::{eco_format, Datetime, Library};
 use typst::file::{FileId, PackageSpec};
-use typst::font::{Font, FontBook, FontInfo, FontVariant};
+use typst::font::{
+    Font, FontBook, FontInfo, FontStretch, FontStyle, FontVariant, FontWeight,
+};
 use typst::geom::Color;
 use typst::syntax::Source;
 use typst::util::{Bytes, PathExt};
 use typst::World;
+use typst_tikz::tikz::{
+    excerpt, BlockDiagnostic, BlockReport, BlockStatus, CachePolicy, FailurePolicy,
+    LockMode, Lockfile, Manifest, Progress, ProjectConfig, PruneStats, RasterFallback,
+    ReplaceReport, Replacement, ResourceLimits, ScannedBlock, Stage, StageTimings, Tikz,
+    TikzConfig, TikzError, TikzFont, Verbosity, LOCK_FILE,
+};
 use walkdir::WalkDir;
 
-use crate::args::{CliArguments, Command, CompileCommand, DiagnosticFormat};
-use crate::tikz::Tikz;
+use crate::args::{
+    BenchCommand, CacheCommand, CliArguments, Command, CompileCommand, DiagnosticFormat,
+    ListCommand, PreprocessCommand, PruneCommand, RenderCommand, ServeCommand, TikzArgs,
+};
 
 type CodespanResult<T> = Result<T, CodespanError>;
 type CodespanError = codespan_reporting::files::Error;
@@ -57,10 +69,25 @@ fn main() -> ExitCode {
     };
 
     let res = match &arguments.command {
-        Command::Compile(_) | Command::Watch(_) => {
-            compile(CompileSettings::with_arguments(arguments))
+        Command::Compile(_) | Command::Watch(_) | Command::Preview(_) => {
+            CompileSettings::with_arguments(arguments).and_then(compile)
         }
         Command::Fonts(_) => fonts(FontsSettings::with_arguments(arguments)),
+        Command::Prune(_) => PruneSettings::with_arguments(arguments).and_then(prune),
+        Command::CleanCache(command) => {
+            tikz_config(command.tikz.clone(), Path::new(""), arguments.verbosity)
+                .and_then(clean_cache)
+        }
+        Command::Cache(_) => CacheSettings::with_arguments(arguments).and_then(cache),
+        Command::List(_) => ListSettings::with_arguments(arguments).and_then(list),
+        Command::Serve(_) => {
+            ServeSettings::with_arguments(arguments).and_then(serve::serve)
+        }
+        Command::Render(_) => RenderSettings::with_arguments(arguments).and_then(render),
+        Command::Preprocess(_) => {
+            PreprocessSettings::with_arguments(arguments).and_then(preprocess)
+        }
+        Command::Bench(_) => BenchSettings::with_arguments(arguments).and_then(bench),
     };
 
     if let Err(msg) = res {
@@ -111,6 +138,21 @@ struct CompileSettings {
     ppi: Option<f32>,
     /// In which format to emit diagnostics.
     diagnostic_format: DiagnosticFormat,
+    /// How to compile the TikZ figures.
+    tikz: TikzConfig,
+    /// Where to export the rewritten sources and the TikZ figures.
+    tikz_export: Option<PathBuf>,
+    /// Where to write the depfile.
+    tikz_depfile: Option<PathBuf>,
+    /// Whether to report the warnings in the LaTeX logs of TikZ figures.
+    tikz_latex_warnings: bool,
+    /// Whether to check the TikZ figures against the lockfile, and what to
+    /// do if they drifted.
+    tikz_lock: Option<LockMode>,
+    /// The address on which to serve a live preview.
+    preview: Option<String>,
+    /// The TikZ figure whose LaTeX log to print after compiling.
+    show_log: Option<u64>,
 }
 
 impl CompileSettings {
@@ -125,6 +167,9 @@ impl CompileSettings {
         open: Option<Option<String>>,
         ppi: Option<f32>,
         diagnostic_format: DiagnosticFormat,
+        tikz: TikzConfig,
+        tikz_export: Option<PathBuf>,
+        tikz_depfile: Option<PathBuf>,
     ) -> Self {
         let output = match output {
             Some(path) => path,
@@ -139,23 +184,49 @@ impl CompileSettings {
             open,
             diagnostic_format,
             ppi,
+            tikz,
+            tikz_export,
+            tikz_depfile,
+            tikz_latex_warnings: false,
+            tikz_lock: None,
+            preview: None,
+            show_log: None,
         }
     }
 
     /// Create a new compile settings from the CLI arguments and a compile command.
     ///
     /// # Panics
-    /// Panics if the command is not a compile or watch command.
-    fn with_arguments(args: CliArguments) -> Self {
-        let watch = matches!(args.command, Command::Watch(_));
-        let CompileCommand { input, output, open, ppi, diagnostic_format, .. } =
-            match args.command {
-                Command::Compile(command) => command,
-                Command::Watch(command) => command,
-                _ => unreachable!(),
-            };
+    /// Panics if the command is not a compile, watch, or preview command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        let watch = args.command.is_watch();
+        let (command, preview) = match args.command {
+            Command::Compile(command) => (command, None),
+            Command::Watch(command) => (command, None),
+            Command::Preview(command) => (command.compile, Some(command.address)),
+            _ => unreachable!(),
+        };
 
-        Self::new(
+        let CompileCommand {
+            input,
+            output,
+            open,
+            ppi,
+            diagnostic_format,
+            force,
+            show_log,
+            tikz,
+            ..
+        } = command;
+
+        let tikz_export = tikz.export.clone();
+        let tikz_depfile = tikz.depfile.clone();
+        let tikz_latex_warnings = tikz.latex_warnings;
+        let tikz_lock = tikz.lock;
+        let dir = input.parent().unwrap_or(Path::new(""));
+        let mut tikz = tikz_config(tikz, dir, args.verbosity)?;
+        tikz.force = force;
+        let mut settings = Self::new(
             input,
             output,
             args.root,
@@ -164,7 +235,158 @@ impl CompileSettings {
             open,
             ppi,
             diagnostic_format,
-        )
+            tikz,
+            tikz_export,
+            tikz_depfile,
+        );
+
+        settings.preview = preview;
+        settings.show_log = show_log;
+        settings.tikz_latex_warnings = tikz_latex_warnings;
+        settings.tikz_lock = tikz_lock;
+        Ok(settings)
+    }
+}
+
+/// Translate the TikZ command line arguments into a compiler configuration.
+///
+/// The configuration file of the project that contains the given directory
+/// provides the defaults. The `TYPST_TIKZ_*` environment variables override
+/// them, and the arguments override both. Unless the verbosity is configured
+/// anywhere, it follows the global `-v` flag.
+fn tikz_config(args: TikzArgs, dir: &Path, verbosity: u8) -> StrResult<TikzConfig> {
+    let mut defaults = TikzConfig::default();
+    let layers = ProjectConfig::layers(dir)?;
+    for layer in &layers {
+        layer.apply(&mut defaults);
+    }
+
+    // The preamble files replace the bundled preamble, but not the additions
+    // of the project and the environment.
+    let preamble = if args.preamble.is_empty() {
+        defaults.preamble
+    } else {
+        let mut preamble = String::new();
+        for path in &args.preamble {
+            let text = fs::read_to_string(path).map_err(|err| {
+                eco_format!("failed to read TikZ preamble {}: {err}", path.display())
+            })?;
+            preamble.push_str(&text);
+            preamble.push('\n');
+        }
+        for addition in layers.iter().filter_map(|layer| layer.preamble.as_ref()) {
+            preamble.push_str(addition);
+            preamble.push('\n');
+        }
+        preamble
+    };
+
+    let mut packages = defaults.packages;
+    packages.extend(args.packages);
+    let mut libraries = defaults.libraries;
+    libraries.extend(args.libraries);
+
+    let timeout = match args.timeout {
+        Some(seconds) if !seconds.is_finite() || seconds <= 0.0 => {
+            bail!("the TikZ timeout must be positive")
+        }
+        Some(seconds) => {
+            Some(std::time::Duration::try_from_secs_f64(seconds).map_err(|err| {
+                eco_format!("the TikZ timeout {seconds} is invalid: {err}")
+            })?)
+        }
+        None => defaults.timeout,
+    };
+
+    if args.raster_dpi == Some(0) {
+        bail!("the resolution of rasterized TikZ figures must be positive");
+    }
+
+    // The arguments are checked when they are parsed, but the configuration
+    // must not wrap around either way.
+    let bytes = |megabytes: Option<u64>, limit: &str| match megabytes {
+        Some(megabytes) => match megabytes.checked_mul(1_000_000) {
+            Some(bytes) => Ok(Some(bytes)),
+            None => {
+                Err(eco_format!("the TikZ {limit} limit of {megabytes} MB is too large"))
+            }
+        },
+        None => Ok(None),
+    };
+    let memory = bytes(args.max_memory, "memory")?;
+    let output_size = bytes(args.max_output_size, "output size")?;
+
+    Ok(TikzConfig {
+        outline_text: args.outline_text,
+        main_font: args.main_font.map(TikzFont::new),
+        math_font: args.math_font.map(TikzFont::new),
+        language: args.language.or(defaults.language),
+        language_package: args.language_package.unwrap_or(defaults.language_package),
+        tikzcd_preset: args.tikzcd_preset.or(defaults.tikzcd_preset),
+        scale: args.scale,
+        calibration: args.calibration.unwrap_or(defaults.calibration),
+        jobs: args.jobs.unwrap_or(defaults.jobs),
+        cache_dir: if args.no_cache {
+            None
+        } else {
+            args.cache_dir.or(defaults.cache_dir)
+        },
+        filename: args.filename.unwrap_or(defaults.filename),
+        template: args.template.or(defaults.template),
+        search_paths: args.input_paths,
+        preamble,
+        packages,
+        libraries,
+        class_options: args.class_options,
+        engines: if args.engines.is_empty() { defaults.engines } else { args.engines },
+        converter: args.converter.or(defaults.converter),
+        timeout,
+        remote: args.remote.or(defaults.remote),
+        keep_intermediates: args.keep_intermediates,
+        reproducible: args.reproducible || defaults.reproducible,
+        pipeline: args.pipeline.unwrap_or(defaults.pipeline),
+        export_formats: if args.export_formats.is_empty() {
+            defaults.export_formats
+        } else {
+            args.export_formats
+        },
+        placeholders: args.placeholders,
+        force: defaults.force,
+        normalize_whitespace: args.normalize_whitespace,
+        verbosity: args
+            .verbosity
+            .or_else(|| layers.iter().rev().find_map(|layer| layer.verbosity))
+            .unwrap_or(match verbosity {
+                0 => Verbosity::Quiet,
+                1 => Verbosity::Normal,
+                2 => Verbosity::Verbose,
+                _ => Verbosity::Debug,
+            }),
+        limits: ResourceLimits {
+            memory,
+            cpu_time: args.max_cpu_time.map(std::time::Duration::from_secs),
+            output_size,
+        },
+        raster: RasterFallback {
+            max_size: args.raster_max_size.map(|kilobytes| kilobytes * 1_000),
+            max_elements: args.raster_max_elements,
+            dpi: args.raster_dpi.unwrap_or(defaults.raster.dpi),
+        },
+        helper: args.helper,
+        cetz: args.cetz,
+        reveal: args.reveal.unwrap_or(defaults.reveal),
+        metadata: false,
+        failure_policy: args.failure_policy.unwrap_or(defaults.failure_policy),
+    })
+}
+
+/// The directory from which the configuration file of the project is
+/// discovered for commands with several inputs: That of the first input.
+fn project_dir(inputs: &[PathBuf]) -> &Path {
+    match inputs.first() {
+        Some(input) if input.is_dir() => input,
+        Some(input) => input.parent().unwrap_or(Path::new("")),
+        None => Path::new(""),
     }
 }
 
@@ -193,13 +415,423 @@ impl FontsSettings {
     }
 }
 
+struct PruneSettings {
+    /// The font paths, which determine the fonts of the TikZ figures.
+    font_paths: Vec<PathBuf>,
+    /// The files and directories whose TikZ figures are kept.
+    inputs: Vec<PathBuf>,
+    /// The limits for the remaining figures.
+    policy: CachePolicy,
+    /// Whether to prune the default cache, which all projects share.
+    force: bool,
+    /// How the TikZ figures are compiled.
+    tikz: TikzConfig,
+}
+
+impl PruneSettings {
+    /// Create a new prune settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a prune command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        let PruneCommand { inputs, max_age, max_size, force, tikz } = match args.command {
+            Command::Prune(command) => command,
+            _ => unreachable!(),
+        };
+
+        let policy = CachePolicy {
+            max_age: max_age
+                .map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60)),
+            max_size: max_size.map(|megabytes| megabytes * 1_000_000),
+        };
+
+        Ok(Self {
+            font_paths: args.font_paths,
+            policy,
+            force,
+            tikz: tikz_config(tikz, project_dir(&inputs), args.verbosity)?,
+            inputs,
+        })
+    }
+}
+
+struct CacheSettings {
+    /// The archive to write or read.
+    archive: PathBuf,
+    /// Whether to import the archive instead of exporting the cache.
+    import: bool,
+    /// Which cache to use.
+    tikz: TikzConfig,
+}
+
+impl CacheSettings {
+    /// Create a new cache settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a cache command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        let (command, import) = match args.command {
+            Command::Cache(CacheCommand::Export(command)) => (command, false),
+            Command::Cache(CacheCommand::Import(command)) => (command, true),
+            _ => unreachable!(),
+        };
+
+        Ok(Self {
+            archive: command.archive,
+            import,
+            tikz: tikz_config(command.tikz, Path::new(""), args.verbosity)?,
+        })
+    }
+}
+
+struct ListSettings {
+    /// The font paths, which determine the fonts of the TikZ figures.
+    font_paths: Vec<PathBuf>,
+    /// The files and directories whose TikZ figures are listed.
+    inputs: Vec<PathBuf>,
+    /// Whether to print JSON.
+    json: bool,
+    /// How the TikZ figures are compiled.
+    tikz: TikzConfig,
+}
+
+impl ListSettings {
+    /// Create a new list settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a list command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        match args.command {
+            Command::List(ListCommand { inputs, json, tikz }) => Ok(Self {
+                font_paths: args.font_paths,
+                json,
+                tikz: tikz_config(tikz, project_dir(&inputs), args.verbosity)?,
+                inputs,
+            }),
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct ServeSettings {
+    /// The font paths, which determine the fonts of the TikZ figures.
+    font_paths: Vec<PathBuf>,
+    /// The address to listen on.
+    address: String,
+    /// How the TikZ figures are compiled.
+    tikz: TikzConfig,
+}
+
+impl ServeSettings {
+    /// Create a new serve settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a serve command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        match args.command {
+            Command::Serve(ServeCommand { address, tikz }) => Ok(Self {
+                font_paths: args.font_paths,
+                address,
+                tikz: tikz_config(tikz, Path::new(""), args.verbosity)?,
+            }),
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct RenderSettings {
+    /// The font paths, which determine the fonts of the TikZ figure.
+    font_paths: Vec<PathBuf>,
+    /// The LaTeX environment of the figure.
+    environment: String,
+    /// The options of the figure.
+    options: String,
+    /// How the TikZ figure is compiled.
+    tikz: TikzConfig,
+}
+
+impl RenderSettings {
+    /// Create a new render settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a render command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        let RenderCommand { environment, preamble, options, tikz } = match args.command {
+            Command::Render(command) => command,
+            _ => unreachable!(),
+        };
+
+        let mut tikz = tikz_config(tikz, Path::new(""), args.verbosity)?;
+        if let Some(preamble) = preamble {
+            tikz.preamble.push('\n');
+            tikz.preamble.push_str(&preamble);
+        }
+
+        Ok(Self {
+            font_paths: args.font_paths,
+            environment,
+            options,
+            tikz,
+        })
+    }
+}
+
+/// Execute a render command.
+fn render(settings: RenderSettings) -> StrResult<()> {
+    let mut code = String::new();
+    io::stdin()
+        .read_to_string(&mut code)
+        .map_err(|err| eco_format!("failed to read TikZ code from stdin: {err}"))?;
+
+    let tikz = open_tikz(&settings.font_paths, settings.tikz)?;
+    tikz.verify().map_err(|err| err.to_string())?;
+    let svg = tikz
+        .render_block(&settings.environment, &code, &settings.options)
+        .map_err(|err| err.to_string())?;
+
+    io::stdout()
+        .write_all(&svg.data)
+        .map_err(|err| eco_format!("failed to write SVG to stdout: {err}"))?;
+    Ok(())
+}
+
+struct PreprocessSettings {
+    /// The font paths, which determine the fonts of the TikZ figures.
+    font_paths: Vec<PathBuf>,
+    /// The directory into which the images are written.
+    images: PathBuf,
+    /// The directory against which referenced files are resolved.
+    dir: PathBuf,
+    /// How the TikZ figures are compiled.
+    tikz: TikzConfig,
+}
+
+impl PreprocessSettings {
+    /// Create a new preprocess settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a preprocess command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        match args.command {
+            Command::Preprocess(PreprocessCommand { images, dir, metadata, tikz }) => {
+                // Only the rewritten file is meant for a newer Typst, which
+                // knows `metadata`, so the flag is limited to this command.
+                let mut tikz = tikz_config(tikz, &dir, args.verbosity)?;
+                tikz.metadata = metadata;
+                Ok(Self { font_paths: args.font_paths, tikz, images, dir })
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Execute a preprocess command.
+fn preprocess(settings: PreprocessSettings) -> StrResult<()> {
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .map_err(|err| eco_format!("failed to read Typst source from stdin: {err}"))?;
+
+    let placeholders = settings.tikz.placeholders;
+    let tikz = open_tikz(&settings.font_paths, settings.tikz)?;
+    if let Err(err) = tikz.verify() {
+        if !placeholders {
+            return Err(err.to_string().into());
+        }
+        eprintln!("warning: {err}\nTikZ figures are shown as placeholders instead");
+    }
+
+    // The rewritten source is written while the figures are replaced.
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let replaced = tikz
+        .replace_into(&text, &settings.dir, &mut stdout)
+        .and_then(|replaced| stdout.flush().map(|_| replaced))
+        .map_err(|err| eco_format!("failed to write Typst source to stdout: {err}"))?;
+
+    let exported = tikz
+        .export(&replaced.report, &settings.images)
+        .map_err(|err| eco_format!("failed to write TikZ figures: {err}"))?;
+    for figure in &exported {
+        if let Some(error) = &figure.error {
+            eprintln!("warning: TikZ figure {}: {error}", figure.hash);
+        }
+    }
+
+    for diag in &replaced.diagnostics {
+        let line = text[..diag.range.start].matches('\n').count() + 1;
+        eprintln!("warning: TikZ figure {} at line {line}: {}", diag.hash, diag.message);
+    }
+
+    let failures = replaced.report.failures();
+    if failures > 0 {
+        set_failed();
+        eprintln!("error: {failures} TikZ figures could not be replaced");
+    }
+
+    Ok(())
+}
+
+struct BenchSettings {
+    /// The font paths, which determine the fonts of the TikZ figures.
+    font_paths: Vec<PathBuf>,
+    /// The file whose figures are compiled.
+    input: PathBuf,
+    /// How many of the slowest figures are highlighted.
+    slowest: usize,
+    /// How the TikZ figures are compiled.
+    tikz: TikzConfig,
+}
+
+impl BenchSettings {
+    /// Create a new bench settings from the CLI arguments.
+    ///
+    /// # Panics
+    /// Panics if the command is not a bench command.
+    fn with_arguments(args: CliArguments) -> StrResult<Self> {
+        match args.command {
+            Command::Bench(BenchCommand { input, slowest, tikz }) => Ok(Self {
+                font_paths: args.font_paths,
+                tikz: tikz_config(
+                    tikz,
+                    project_dir(std::slice::from_ref(&input)),
+                    args.verbosity,
+                )?,
+                input,
+                slowest,
+            }),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Execute a bench command.
+fn bench(settings: BenchSettings) -> StrResult<()> {
+    let path = &settings.input;
+    let text = fs::read_to_string(path)
+        .map_err(|_| eco_format!("failed to read {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    // The cold run compiles every figure once more, bypassing the caches.
+    let config = TikzConfig { force: true, ..settings.tikz.clone() };
+    let tikz = open_tikz(&settings.font_paths, config)?;
+    tikz.verify().map_err(|err| err.to_string())?;
+    let start = std::time::Instant::now();
+    let cold = tikz.replace_in(&text, dir).report;
+    let cold_duration = start.elapsed();
+
+    // The warm run loads the figures from the shared cache, or from memory
+    // if there is none.
+    let tikz = match settings.tikz.cache_dir {
+        Some(_) => open_tikz(&settings.font_paths, settings.tikz)?,
+        None => tikz,
+    };
+    let start = std::time::Instant::now();
+    let warm = tikz.replace_in(&text, dir).report;
+    let warm_duration = start.elapsed();
+
+    let line = |block: &BlockReport| text[..block.range.start].matches('\n').count() + 1;
+    let mut stages = StageTimings::default();
+    for (block, again) in cold.blocks.iter().zip(&warm.blocks) {
+        let location = format!("{}:{}", path.display(), line(block));
+        let timings = match (block.status, block.timings) {
+            (BlockStatus::Compiled, Some(timings)) => timings,
+            (status, _) => {
+                let outcome = match status {
+                    BlockStatus::Compiled => "not compiled",
+                    BlockStatus::Translated => "translated into CeTZ",
+                    BlockStatus::Failed | BlockStatus::Unembeddable => "failed",
+                };
+                println!("{location}: {} {} {outcome}", block.environment, block.hash);
+                continue;
+            }
+        };
+
+        stages.latex += timings.latex;
+        stages.conversion += timings.conversion;
+        stages.io += timings.io;
+        println!(
+            "{location}: {} {} cold {:.2?} (LaTeX {:.2?}, conversion {:.2?}, IO {:.2?}), warm {:.2?}",
+            block.environment,
+            block.hash,
+            timings.total(),
+            timings.latex,
+            timings.conversion,
+            timings.io,
+            again.duration,
+        );
+    }
+
+    println!(
+        "\n{} TikZ figures: cold {cold_duration:.2?}, warm {warm_duration:.2?}",
+        cold.blocks.len()
+    );
+    println!(
+        "stages: LaTeX {:.2?}, conversion {:.2?}, IO {:.2?}",
+        stages.latex, stages.conversion, stages.io
+    );
+
+    let mut slowest: Vec<_> = cold
+        .blocks
+        .iter()
+        .filter_map(|block| Some((block, block.timings?.total())))
+        .collect();
+    slowest.sort_by_key(|&(_, duration)| std::cmp::Reverse(duration));
+    if settings.slowest > 0 && !slowest.is_empty() {
+        println!("slowest:");
+        for (block, duration) in slowest.iter().take(settings.slowest) {
+            println!(
+                "  {}:{}: {} {} {duration:.2?}",
+                path.display(),
+                line(block),
+                block.environment,
+                block.hash,
+            );
+        }
+    }
+
+    let failures = cold.failures();
+    if failures > 0 {
+        set_failed();
+        eprintln!("error: {failures} TikZ figures failed to compile");
+    }
+
+    Ok(())
+}
+
+/// Print the LaTeX log of a TikZ figure, if one was requested.
+fn show_log(world: &SystemWorld, settings: &CompileSettings) {
+    let Some(hash) = settings.show_log else { return };
+    match world.tikz.log(hash) {
+        Some(log) => print!("{log}"),
+        None => {
+            set_failed();
+            print_error(&format!(
+                "there is no LaTeX log for TikZ figure {hash}, which was either not \
+                 compiled or taken from the cache (try --tikz-no-cache)"
+            ))
+            .expect("failed to print error");
+        }
+    }
+}
+
 /// Execute a compilation command.
 fn compile(mut settings: CompileSettings) -> StrResult<()> {
     // Create the world that serves sources, files, and fonts.
     let mut world = SystemWorld::new(&settings)?;
 
+    // Serve the preview before the first compilation, so that the browser
+    // can already connect.
+    let preview = match &settings.preview {
+        Some(address) => Some(preview::Preview::start(address, &settings.output)?),
+        None => None,
+    };
+
     // Perform initial compilation.
     let ok = compile_once(&mut world, &settings)?;
+    show_log(&world, &settings);
+    if let (true, Some(preview)) = (ok, &preview) {
+        preview.reload();
+    }
 
     // Open the file if requested, this must be done on the first **successful**
     // compilation.
@@ -246,10 +878,21 @@ fn compile(mut settings: CompileSettings) -> StrResult<()> {
             // Retrieve the dependencies of the last compilation.
             let dependencies = world.dependencies();
 
-            // Recompile.
+            // Compile the TikZ figures that the editor shows first.
+            if let Some(visible) = preview.as_ref().and_then(|preview| preview.visible())
+            {
+                prioritize(&world, &visible);
+            }
+
+            // Recompile. Unchanged TikZ figures are taken from the cache.
             let ok = compile_once(&mut world, &settings)?;
+            show_log(&world, &settings);
             comemo::evict(10);
 
+            if let (true, Some(preview)) = (ok, &preview) {
+                preview.reload();
+            }
+
             // Adjust the watching.
             world.watch(&mut watcher, dependencies)?;
 
@@ -263,6 +906,24 @@ fn compile(mut settings: CompileSettings) -> StrResult<()> {
         }
     }
 }
+
+/// Compile the TikZ figures on the visible lines of a file before all others.
+fn prioritize(world: &SystemWorld, visible: &preview::Visible) {
+    let Ok(text) = fs::read_to_string(&visible.path) else { return };
+    let dir = visible.path.parent().unwrap_or(Path::new("."));
+    let line = |offset: usize| text[..offset].matches('\n').count() + 1;
+    let hashes = world
+        .tikz
+        .list(&text, dir)
+        .into_iter()
+        .filter(|block| {
+            line(block.range.start) <= visible.end
+                && line(block.range.end) >= visible.start
+        })
+        .map(|block| block.hash);
+    world.tikz.prioritize(hashes);
+}
+
 /// Compile a single time.
 ///
 /// Returns whether it compiled without errors.
@@ -275,16 +936,61 @@ fn compile_once(world: &mut SystemWorld, settings: &CompileSettings) -> StrResul
 
     // Reset everything and ensure that the main file is still present.
     world.reset();
-    world.source(world.main).map_err(|err| err.to_string())?;
+    let loaded = world.source(world.main);
 
-    let result = typst::compile(world);
+    // Failing fast on a figure of the main file leaves nothing to compile.
+    let result = match loaded {
+        Ok(_) => typst::compile(world),
+        Err(_) if !world.tikz_errors.borrow().is_empty() => Err(Box::new(vec![])),
+        Err(err) => return Err(err.to_string().into()),
+    };
     let duration = start.elapsed();
 
+    // Figures that failed to compile are reported instead of the errors that
+    // Typst raised about their images.
+    if !world.tikz_errors.borrow().is_empty() {
+        set_failed();
+        status(settings, Status::Error).unwrap();
+        print_tikz_warnings(world, settings.diagnostic_format)
+            .map_err(|_| "failed to print diagnostics")?;
+        print_tikz_errors(world, settings.diagnostic_format)
+            .map_err(|_| "failed to print diagnostics")?;
+        if let Err(errors) = result {
+            let errors = (*errors)
+                .into_iter()
+                .filter(|error| world.tikz.is_error(world, error).is_none())
+                .collect();
+            print_diagnostics(world, errors, settings.diagnostic_format)
+                .map_err(|_| "failed to print diagnostics")?;
+        }
+        tracing::info!("Compilation failed after {duration:?}");
+        return Ok(false);
+    }
+
     match result {
         // Export the PDF / PNG.
         Ok(document) => {
+            if let Some(mode) = settings.tikz_lock {
+                if !check_lock(world, &settings.input, mode)? {
+                    set_failed();
+                    status(settings, Status::Error).unwrap();
+                    tracing::info!("Compilation failed after {duration:?}");
+                    return Ok(false);
+                }
+            }
+
             export(&document, settings)?;
+            if let Some(dir) = &settings.tikz_export {
+                export_tikz(world, dir)
+                    .map_err(|err| eco_format!("failed to export TikZ figures: {err}"))?;
+            }
+            if let Some(path) = &settings.tikz_depfile {
+                write_depfile(world, &settings.output, path)
+                    .map_err(|_| "failed to write depfile")?;
+            }
             status(settings, Status::Success(duration)).unwrap();
+            print_tikz_warnings(world, settings.diagnostic_format)
+                .map_err(|_| "failed to print diagnostics")?;
             tracing::info!("Compilation succeeded in {duration:?}");
             Ok(true)
         }
@@ -293,6 +999,8 @@ fn compile_once(world: &mut SystemWorld, settings: &CompileSettings) -> StrResul
         Err(errors) => {
             set_failed();
             status(settings, Status::Error).unwrap();
+            print_tikz_warnings(world, settings.diagnostic_format)
+                .map_err(|_| "failed to print diagnostics")?;
             print_diagnostics(world, *errors, settings.diagnostic_format)
                 .map_err(|_| "failed to print diagnostics")?;
             tracing::info!("Compilation failed after {duration:?}");
@@ -301,6 +1009,43 @@ fn compile_once(world: &mut SystemWorld, settings: &CompileSettings) -> StrResul
     }
 }
 
+/// Check the SVGs of the TikZ figures against the lockfile next to the input
+/// and record those of new figures.
+///
+/// Returns whether the build may go on, which it may not if figures drifted
+/// and the mode denies that.
+fn check_lock(world: &SystemWorld, input: &Path, mode: LockMode) -> StrResult<bool> {
+    let path = input.parent().unwrap_or(Path::new("")).join(LOCK_FILE);
+    let previous = Lockfile::read(&path)?;
+    let mut lock = match mode {
+        LockMode::Update => Lockfile::default(),
+        LockMode::Warn | LockMode::Deny => previous.clone(),
+    };
+
+    let reports = world.tikz_reports.borrow();
+    let drifts = lock.verify(
+        &world.tikz,
+        reports.iter().map(|(_, report)| report),
+        mode == LockMode::Update,
+    );
+
+    let level = if mode == LockMode::Deny { "error" } else { "warning" };
+    for drift in &drifts {
+        eprintln!("{level}: {drift}");
+    }
+
+    if mode == LockMode::Deny && !drifts.is_empty() {
+        return Ok(false);
+    }
+
+    if lock != previous {
+        lock.write(&path)
+            .map_err(|err| eco_format!("failed to write {}: {err}", path.display()))?;
+    }
+
+    Ok(true)
+}
+
 /// Export into the target format.
 fn export(document: &Document, settings: &CompileSettings) -> StrResult<()> {
     match settings.output.extension() {
@@ -339,6 +1084,110 @@ fn export(document: &Document, settings: &CompileSettings) -> StrResult<()> {
     Ok(())
 }
 
+/// Export the rewritten sources of the project together with their TikZ
+/// figures, so that plain Typst can compile them.
+///
+/// Other files of the project are not copied.
+fn export_tikz(world: &SystemWorld, dir: &Path) -> io::Result<()> {
+    let mut manifest = Manifest::default();
+
+    for (id, report) in world.tikz_reports.borrow().iter() {
+        if id.package().is_some() {
+            continue;
+        }
+
+        let relative: PathBuf = id
+            .path()
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+
+        let target = dir.join(&relative);
+        let parent = target.parent().unwrap_or(dir);
+        fs::create_dir_all(parent)?;
+        fs::write(&target, world.lookup(*id).text())?;
+
+        for mut figure in world.tikz.export(report, parent)? {
+            if let Some(error) = &figure.error {
+                eprintln!("warning: TikZ figure {}: {error}", figure.hash);
+            }
+            figure.source = Some(relative.clone());
+            if let Ok(file) = figure.file.strip_prefix(dir) {
+                figure.file = file.to_path_buf();
+            }
+            let files = [&mut figure.named, &mut figure.pdf, &mut figure.png];
+            for path in files.into_iter().flatten() {
+                if let Ok(file) = path.strip_prefix(dir) {
+                    *path = file.to_path_buf();
+                }
+            }
+            manifest.figures.push(figure);
+        }
+    }
+
+    manifest.write(&dir.join("tikz-manifest.json"))
+}
+
+/// Write a Makefile-style depfile for the output.
+///
+/// Lists all files that the compilation loaded, the files that TikZ figures
+/// reference, and the cached images of the TikZ figures, so that build systems
+/// recompile whenever any of them changes. The paths are canonical, so that
+/// build systems find them from any working directory.
+fn write_depfile(world: &SystemWorld, output: &Path, path: &Path) -> io::Result<()> {
+    let mut dependencies: Vec<PathBuf> = world.dependencies().into_iter().collect();
+
+    for (id, report) in world.tikz_reports.borrow().iter() {
+        // LaTeX resolves relative paths against the directory of the source.
+        let dir = world
+            .slot(*id)
+            .ok()
+            .and_then(|slot| slot.system_path.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+
+        for block in &report.blocks {
+            dependencies.extend(block.assets.iter().map(|asset| dir.join(asset)));
+            dependencies.extend(
+                block.pages.iter().filter_map(|&hash| world.tikz.cache_entry(hash)),
+            );
+        }
+    }
+
+    // Files that do not exist (anymore) cannot be canonicalized, but are
+    // still made absolute.
+    let cwd = env::current_dir()?;
+    let mut dependencies: Vec<PathBuf> = dependencies
+        .into_iter()
+        .map(|dependency| {
+            dependency.canonicalize().unwrap_or_else(|_| cwd.join(dependency))
+        })
+        .collect();
+    dependencies.sort();
+    dependencies.dedup();
+
+    let mut w = io::BufWriter::new(File::create(path)?);
+    write!(w, "{}:", escape_make(output))?;
+    for dependency in &dependencies {
+        write!(w, " \\\n  {}", escape_make(dependency))?;
+    }
+    writeln!(w)?;
+    w.flush()
+}
+
+/// Escape a path for use in a Makefile rule.
+fn escape_make(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 /// Clear the terminal and render the status message.
 #[tracing::instrument(skip_all)]
 fn status(settings: &CompileSettings, status: Status) -> io::Result<()> {
@@ -411,15 +1260,27 @@ impl Status {
     }
 }
 
-/// Print diagnostic messages to the terminal.
-fn print_diagnostics(
-    world: &SystemWorld,
-    errors: Vec<SourceError>,
+/// Print the progress of compiling TikZ figures on a single terminal line.
+fn print_tikz_progress(progress: &Progress) {
+    let esc = 27 as char;
+    match progress {
+        Progress::BlockStarted { index, total, cached: false, .. } => {
+            eprint!("\r{esc}[2Kcompiling TikZ figure {}/{total} ...", index + 1);
+        }
+        Progress::Finished { .. } => eprint!("\r{esc}[2K"),
+        _ => {}
+    }
+}
+
+/// Get the stream and configuration for printing diagnostics.
+fn diagnostic_output(
     diagnostic_format: DiagnosticFormat,
-) -> Result<(), codespan_reporting::files::Error> {
-    let mut w = match diagnostic_format {
+) -> (StandardStream, term::Config) {
+    let w = match diagnostic_format {
         DiagnosticFormat::Human => color_stream(),
-        DiagnosticFormat::Short => StandardStream::stderr(ColorChoice::Never),
+        DiagnosticFormat::Short | DiagnosticFormat::Json => {
+            StandardStream::stderr(ColorChoice::Never)
+        }
     };
 
     let mut config = term::Config { tab_width: 2, ..Default::default() };
@@ -427,11 +1288,171 @@ fn print_diagnostics(
         config.display_style = term::DisplayStyle::Short;
     }
 
+    (w, config)
+}
+
+/// Print the problems with TikZ figures as warnings to the terminal.
+fn print_tikz_warnings(
+    world: &SystemWorld,
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), codespan_reporting::files::Error> {
+    let (mut w, config) = diagnostic_output(diagnostic_format);
+
+    for (id, line, block) in world.tikz_warnings.borrow().iter() {
+        if diagnostic_format == DiagnosticFormat::Json {
+            emit_json(&JsonDiagnostic::tikz("warning", *id, *line, block));
+            continue;
+        }
+
+        let range = world.lookup(*id).line_to_range(*line).unwrap_or(0..0);
+        let diag = Diagnostic::warning()
+            .with_message(&block.message)
+            .with_notes(vec![format!("the TikZ figure has the hash {}", block.hash)])
+            .with_labels(vec![Label::primary(*id, range)]);
+
+        term::emit(&mut w, &config, world, &diag)?;
+    }
+
+    Ok(())
+}
+
+/// Print the TikZ figures that failed to compile as errors to the terminal,
+/// with their LaTeX logs.
+fn print_tikz_errors(
+    world: &SystemWorld,
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), codespan_reporting::files::Error> {
+    let (mut w, config) = diagnostic_output(diagnostic_format);
+
+    // The sources of figures that failed fast were never loaded, so the
+    // location is given as a note.
+    for (id, line, block) in world.tikz_errors.borrow().iter() {
+        if diagnostic_format == DiagnosticFormat::Json {
+            let mut diag = JsonDiagnostic::tikz("error", *id, *line, block);
+            diag.message = format!("failed to compile TikZ figure {}", block.hash);
+            diag.log = excerpt(&block.message).into_iter().map(String::from).collect();
+            emit_json(&diag);
+            continue;
+        }
+
+        let location = format!("in {}:{}", id.path().display(), line + 1);
+        let diag = Diagnostic::error()
+            .with_message(format!("failed to compile TikZ figure {}", block.hash))
+            .with_notes(
+                std::iter::once(location)
+                    .chain(block.message.split('\n').map(|line| line.to_string()))
+                    .collect(),
+            );
+
+        term::emit(&mut w, &config, world, &diag)?;
+    }
+
+    Ok(())
+}
+
+/// A diagnostic for `--diagnostic-format json`, which is printed as a single
+/// line of JSON.
+#[derive(Debug, Serialize)]
+struct JsonDiagnostic {
+    /// Either `error` or `warning`.
+    severity: &'static str,
+    /// What went wrong.
+    message: String,
+    /// The source file, if known.
+    file: Option<PathBuf>,
+    /// The line in the source file, counted from one.
+    line: Option<usize>,
+    /// The byte range in the source file.
+    range: Option<std::ops::Range<usize>>,
+    /// The hash of the TikZ figure, if the diagnostic is about one.
+    hash: Option<String>,
+    /// The stage of the TikZ figure in which it went wrong.
+    stage: Option<Stage>,
+    /// The lines of the LaTeX log that matter most.
+    log: Vec<String>,
+    /// Hints on how to fix it.
+    hints: Vec<String>,
+}
+
+impl JsonDiagnostic {
+    /// A problem with a TikZ figure on the given line, counted from zero.
+    fn tikz(
+        severity: &'static str,
+        id: FileId,
+        line: usize,
+        block: &BlockDiagnostic,
+    ) -> Self {
+        Self {
+            severity,
+            message: block.message.clone(),
+            file: Some(id.path().to_path_buf()),
+            line: Some(line + 1),
+            range: Some(block.range.clone()),
+            hash: Some(block.hash.to_string()),
+            stage: Some(block.stage),
+            log: vec![],
+            hints: vec![],
+        }
+    }
+
+    /// An error that Typst raised, which is about a TikZ figure if it refers
+    /// to the image of one.
+    fn typst(world: &SystemWorld, error: &SourceError) -> Self {
+        let id = error.span.id();
+        let range = error.span.range(world);
+        let mut diag = Self {
+            severity: "error",
+            message: error.message.to_string(),
+            file: Some(id.path().to_path_buf()),
+            line: world.lookup(id).byte_to_line(range.start).map(|line| line + 1),
+            range: Some(range),
+            hash: None,
+            stage: None,
+            log: vec![],
+            hints: error.hints.iter().map(|hint| hint.to_string()).collect(),
+        };
+
+        if let Some(hash) = world.tikz.is_error(world, error) {
+            if let Some(Err(logs)) = world.tikz.fetch(hash) {
+                diag.message = "failed to compile TikZ figure".into();
+                diag.hash = Some(hash.to_string());
+                diag.stage = Some(Stage::Compilation);
+                diag.log = excerpt(&logs).into_iter().map(String::from).collect();
+            }
+        } else if let Some(hash) = world.tikz.is_unknown(world, error) {
+            diag.message = TikzError::UnknownFigure(hash).to_string();
+            diag.hash = Some(hash.to_string());
+        }
+
+        diag
+    }
+}
+
+/// Print a diagnostic as a line of JSON to stderr.
+fn emit_json(diag: &JsonDiagnostic) {
+    if let Ok(json) = serde_json::to_string(diag) {
+        eprintln!("{json}");
+    }
+}
+
+/// Print diagnostic messages to the terminal.
+fn print_diagnostics(
+    world: &SystemWorld,
+    errors: Vec<SourceError>,
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), codespan_reporting::files::Error> {
+    let (mut w, config) = diagnostic_output(diagnostic_format);
+
     for error in errors {
-        // The main diagnostic.
-        if let Some(id) = Tikz::is_error(world, &error) {
-            let logs = world.tikz.fetch(id).as_ref().unwrap_err().clone();
+        if diagnostic_format == DiagnosticFormat::Json {
+            emit_json(&JsonDiagnostic::typst(world, &error));
+            continue;
+        }
 
+        // The main diagnostic.
+        let failed =
+            world.tikz.is_error(world, &error).and_then(|id| world.tikz.fetch(id));
+        if let Some(Err(logs)) = failed {
             let diag = Diagnostic::error()
                 .with_message("failed to compile TikZ figure")
                 .with_notes(logs.split('\n').map(|line| line.to_string()).collect());
@@ -445,6 +1466,19 @@ fn print_diagnostics(
                     error.span.range(world),
                 )]);
 
+            term::emit(&mut w, &config, world, &diag)?;
+        } else if let Some(hash) = world.tikz.is_unknown(world, &error) {
+            let diag = Diagnostic::error()
+                .with_message(TikzError::UnknownFigure(hash).to_string())
+                .with_notes(vec![
+                    "hint: no TikZ figure with this hash was compiled".into(),
+                    "hint: refer to figures through their code, not their images".into(),
+                ])
+                .with_labels(vec![Label::primary(
+                    error.span.id(),
+                    error.span.range(world),
+                )]);
+
             term::emit(&mut w, &config, world, &diag)?;
         } else {
             let diag = Diagnostic::error()
@@ -496,6 +1530,164 @@ fn fonts(command: FontsSettings) -> StrResult<()> {
     Ok(())
 }
 
+/// Execute a prune command.
+fn prune(settings: PruneSettings) -> StrResult<()> {
+    if settings.inputs.is_empty()
+        && settings.policy.max_age.is_none()
+        && settings.policy.max_size.is_none()
+    {
+        bail!("nothing to prune, pass input files or a maximum age or size");
+    }
+
+    let tikz = open_tikz(&settings.font_paths, settings.tikz)?;
+
+    let mut stats = PruneStats::default();
+    if !settings.inputs.is_empty() {
+        let mut keep = vec![];
+        for (path, text) in read_typst_files(&settings.inputs)? {
+            keep.extend(tikz.hashes(&text, path.parent().unwrap_or(Path::new("."))));
+        }
+
+        stats += tikz
+            .prune(&keep, settings.force)
+            .map_err(|err| eco_format!("failed to prune TikZ cache: {err}"))?;
+    }
+
+    stats += tikz
+        .collect_garbage(&settings.policy)
+        .map_err(|err| eco_format!("failed to prune TikZ cache: {err}"))?;
+
+    print_prune_stats(stats);
+    Ok(())
+}
+
+/// Execute a clean-cache command.
+fn clean_cache(config: TikzConfig) -> StrResult<()> {
+    let tikz = Tikz::new(config).map_err(|err| err.to_string())?;
+    let stats = tikz
+        .clear_cache()
+        .map_err(|err| eco_format!("failed to clean TikZ cache: {err}"))?;
+
+    print_prune_stats(stats);
+    Ok(())
+}
+
+/// Execute a cache command.
+fn cache(settings: CacheSettings) -> StrResult<()> {
+    let tikz = Tikz::new(settings.tikz).map_err(|err| err.to_string())?;
+    let path = &settings.archive;
+    if settings.import {
+        let file = File::open(path)
+            .map_err(|err| eco_format!("failed to open {}: {err}", path.display()))?;
+        let count = tikz
+            .import_cache(io::BufReader::new(file))
+            .map_err(|err| eco_format!("failed to import TikZ cache: {err}"))?;
+        println!("imported {count} TikZ files");
+    } else {
+        let file = File::create(path)
+            .map_err(|err| eco_format!("failed to create {}: {err}", path.display()))?;
+        let mut writer = io::BufWriter::new(file);
+        let count = tikz
+            .export_cache(&mut writer)
+            .and_then(|count| writer.flush().map(|_| count))
+            .map_err(|err| eco_format!("failed to export TikZ cache: {err}"))?;
+        println!("exported {count} TikZ files");
+    }
+
+    Ok(())
+}
+
+/// Print how much was removed from the TikZ cache.
+fn print_prune_stats(stats: PruneStats) {
+    println!(
+        "removed {} cached TikZ files, freeing {:.1} MB",
+        stats.removed,
+        stats.freed as f64 / 1e6
+    );
+}
+
+/// Execute a list command.
+fn list(settings: ListSettings) -> StrResult<()> {
+    /// The figures of a single file.
+    #[derive(Serialize)]
+    struct Listing {
+        source: PathBuf,
+        blocks: Vec<ScannedBlock>,
+    }
+
+    let tikz = open_tikz(&settings.font_paths, settings.tikz)?;
+    let listings: Vec<_> = read_typst_files(&settings.inputs)?
+        .into_iter()
+        .map(|(path, text)| {
+            let blocks = tikz.list(&text, path.parent().unwrap_or(Path::new(".")));
+            (Listing { source: path, blocks }, text)
+        })
+        .collect();
+
+    if settings.json {
+        let listings: Vec<_> = listings.iter().map(|(listing, _)| listing).collect();
+        let json = serde_json::to_string_pretty(&listings)
+            .map_err(|err| eco_format!("failed to serialize TikZ figures: {err}"))?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    for (listing, text) in &listings {
+        for block in &listing.blocks {
+            let line = text[..block.range.start].matches('\n').count() + 1;
+            let status = if block.cached { "cached" } else { "not cached" };
+            println!(
+                "{}:{line}: {} {} ({status})",
+                listing.source.display(),
+                block.environment,
+                block.hash,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the Typst files among the inputs and in the input directories.
+///
+/// Explicitly passed files are read regardless of their extension.
+fn read_typst_files(inputs: &[PathBuf]) -> StrResult<Vec<(PathBuf, String)>> {
+    let mut files = vec![];
+    for input in inputs {
+        for entry in WalkDir::new(input).sort_by_file_name() {
+            let entry = entry.map_err(|err| err.to_string())?;
+            let path = entry.path();
+
+            let typst = path.extension().map_or(false, |ext| ext == "typ");
+            if !entry.file_type().is_file() || (entry.depth() > 0 && !typst) {
+                continue;
+            }
+
+            let text = fs::read_to_string(path)
+                .map_err(|_| eco_format!("failed to read {}", path.display()))?;
+            files.push((path.to_path_buf(), text));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Create a TikZ compiler outside of a compilation, with the fonts resolved
+/// like a compilation would.
+fn open_tikz(font_paths: &[PathBuf], mut config: TikzConfig) -> StrResult<Tikz> {
+    let mut searcher = FontSearcher::new();
+    searcher.search(font_paths);
+    resolve_tikz_fonts(&searcher, &mut config);
+    Tikz::new(config).map_err(|err| err.to_string().into())
+}
+
+/// Let LaTeX load the very same font files as Typst, if possible.
+fn resolve_tikz_fonts(searcher: &FontSearcher, config: &mut TikzConfig) {
+    for font in [&mut config.main_font, &mut config.math_font].into_iter().flatten() {
+        font.path = searcher.find(&font.family);
+    }
+}
+
 /// A world that provides access to the operating system.
 struct SystemWorld {
     /// The root relative to which absolute paths are resolved.
@@ -519,6 +1711,24 @@ struct SystemWorld {
     today: OnceCell<Option<Datetime>>,
     /// Maps each LaTeX code to its compiled TikZ image.
     tikz: Tikz,
+    /// Problems with the TikZ figures in the sources of the current
+    /// compilation, with the line at which each figure starts. Reset between
+    /// compilations.
+    tikz_warnings: RefCell<Vec<(FileId, usize, BlockDiagnostic)>>,
+    /// Whether the warnings in the LaTeX logs of the figures are reported
+    /// along with their problems.
+    tikz_latex_warnings: bool,
+    /// The TikZ figures in the sources of the current compilation that
+    /// failed to compile, with the line at which each figure starts, unless
+    /// they are left to Typst. Reset between compilations.
+    tikz_errors: RefCell<Vec<(FileId, usize, BlockDiagnostic)>>,
+    /// What happened to the TikZ figures in each source file that was loaded
+    /// during the last compilation. Reset between compilations.
+    tikz_reports: RefCell<Vec<(FileId, ReplaceReport)>>,
+    /// The last text and replacement of each source file, so that only the
+    /// figures that changed are replaced when it is loaded again. Kept
+    /// between compilations.
+    tikz_previous: RefCell<HashMap<PathBuf, (String, Replacement)>>,
 }
 
 /// Holds details about the location of a font and lazily the font itself.
@@ -572,7 +1782,21 @@ impl SystemWorld {
             .map(|path| Path::new("/").join(path))
             .map_err(|_| "input file must be contained in project root")?;
 
-        let tikz = Tikz::new().map_err(|err| err.to_string())?;
+        let mut config = settings.tikz.clone();
+        resolve_tikz_fonts(&searcher, &mut config);
+
+        // Without TeX, the figures may be shown as placeholders instead.
+        let placeholders = config.placeholders;
+        let mut tikz = Tikz::new(config).map_err(|err| err.to_string())?;
+        if let Err(err) = tikz.verify() {
+            if !placeholders {
+                return Err(err.to_string().into());
+            }
+            eprintln!("warning: {err}\nTikZ figures are shown as placeholders instead");
+        }
+        if std::io::stderr().is_terminal() {
+            tikz.on_progress(print_tikz_progress);
+        }
 
         Ok(Self {
             root,
@@ -584,6 +1808,11 @@ impl SystemWorld {
             paths: RefCell::default(),
             today: OnceCell::new(),
             tikz,
+            tikz_warnings: RefCell::default(),
+            tikz_latex_warnings: settings.tikz_latex_warnings,
+            tikz_errors: RefCell::default(),
+            tikz_reports: RefCell::default(),
+            tikz_previous: RefCell::default(),
         })
     }
 }
@@ -602,26 +1831,103 @@ impl World for SystemWorld {
     }
 
     fn source(&self, id: FileId) -> FileResult<Source> {
-        let slot = self.slot(id)?;
+        // The helper module is generated unless the project has its own.
+        let slot = match self.slot(id) {
+            Err(FileError::NotFound(_)) if self.is_tikz_helper(id) => {
+                let helper = self.tikz.helper().unwrap_or_default();
+                return Ok(Source::new(id, helper.into()));
+            }
+            slot => slot?,
+        };
         slot.source
             .get_or_init(|| {
                 let buf = read(&slot.system_path)?;
                 let text = decode_utf8(buf)?;
-                Ok(Source::new(id, self.tikz.replace(&text)))
+                let dir = slot.system_path.parent().unwrap_or(Path::new("."));
+                let line = |offset: usize| text[..offset].matches('\n').count();
+                let record = |errors: Vec<BlockDiagnostic>| {
+                    let mut recorded = self.tikz_errors.borrow_mut();
+                    for diag in errors {
+                        recorded.push((id, line(diag.range.start), diag));
+                    }
+                };
+
+                // Failing fast stops before the remaining figures are compiled.
+                let policy = self.tikz.config().failure_policy;
+                let mut previous = self.tikz_previous.borrow_mut();
+                let replaced = match previous.get(&slot.system_path) {
+                    _ if policy == FailurePolicy::FailFast => {
+                        match self.tikz.try_replace_in(&text, dir) {
+                            Ok(replaced) => replaced,
+                            Err(errors) => {
+                                record(errors);
+                                return Err(FileError::Other);
+                            }
+                        }
+                    }
+                    Some((old, replaced)) => {
+                        self.tikz
+                            .replace_incremental(old, replaced, &text, dir)
+                            .replacement
+                    }
+                    None => self.tikz.replace_in(&text, dir),
+                };
+                previous
+                    .insert(slot.system_path.clone(), (text.clone(), replaced.clone()));
+                drop(previous);
+
+                if policy == FailurePolicy::CollectAll {
+                    record(self.tikz.errors(&replaced.report));
+                }
+
+                let report = &replaced.report;
+                if !report.blocks.is_empty() {
+                    tracing::info!(
+                        "Replaced {} TikZ figures in {} ({} cached, {} failed)",
+                        report.blocks.len(),
+                        id.path().display(),
+                        report.cache_hits(),
+                        report.failures(),
+                    );
+                }
+
+                // The rewritten source has the same lines as the original one.
+                let mut warnings = self.tikz_warnings.borrow_mut();
+                for diag in replaced.diagnostics {
+                    let line = text[..diag.range.start].matches('\n').count();
+                    warnings.push((id, line, diag));
+                }
+                if self.tikz_latex_warnings {
+                    for block in &report.blocks {
+                        let line = text[..block.range.start].matches('\n').count();
+                        for warning in &block.warnings {
+                            let diag = BlockDiagnostic {
+                                hash: block.hash,
+                                range: block.range.clone(),
+                                stage: Stage::Compilation,
+                                message: format!("LaTeX warning: {warning}"),
+                            };
+                            warnings.push((id, line, diag));
+                        }
+                    }
+                }
+
+                self.tikz_reports.borrow_mut().push((id, replaced.report));
+
+                Ok(Source::new(id, replaced.text))
             })
             .clone()
     }
 
     fn file(&self, id: FileId) -> FileResult<Bytes> {
-        let filename = id.path().file_name().unwrap().to_str().unwrap();
-
-        if let Some(id) = Tikz::is_filename(filename) {
-            return self
-                .tikz
-                .fetch(id)
-                .as_ref()
-                .map(|f| Bytes::from(f.as_slice()))
-                .or(Err(FileError::Other));
+        if let Some(hash) = self.tikz.is_filename(&id.path().to_string_lossy()) {
+            return match self.tikz.resolve(hash) {
+                Ok(image) => Ok(Bytes::from(image)),
+                Err(TikzError::UnknownFigure(_)) => {
+                    Err(FileError::NotFound(id.path().into()))
+                }
+                Err(_) => Err(FileError::Other),
+            };
         }
 
         let slot = self.slot(id)?;
@@ -738,6 +2044,14 @@ impl SystemWorld {
         self.hashes.borrow_mut().clear();
         self.paths.borrow_mut().clear();
         self.today.take();
+        self.tikz_warnings.borrow_mut().clear();
+        self.tikz_errors.borrow_mut().clear();
+        self.tikz_reports.borrow_mut().clear();
+    }
+
+    /// Whether a file is the helper module of the TikZ figures.
+    fn is_tikz_helper(&self, id: FileId) -> bool {
+        id.package().is_none() && self.tikz.is_helper(&id.path().to_string_lossy())
     }
 
     /// Lookup a source file by id.
@@ -959,6 +2273,17 @@ impl FontSearcher {
         Self { book: FontBook::new(), fonts: vec![] }
     }
 
+    /// Find the file that contains the regular style of a font family.
+    ///
+    /// Returns `None` if the family is unknown or the font is embedded.
+    fn find(&self, family: &str) -> Option<PathBuf> {
+        let variant =
+            FontVariant::new(FontStyle::Normal, FontWeight::REGULAR, FontStretch::NORMAL);
+        let id = self.book.select(&family.to_lowercase(), variant)?;
+        let path = &self.fonts[id].path;
+        (!path.as_os_str().is_empty()).then(|| path.clone())
+    }
+
     /// Search everything that is available.
     fn search(&mut self, font_paths: &[PathBuf]) {
         self.search_system();
@@ -0,0 +1,232 @@
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use typst::diag::StrResult;
+use typst::eval::eco_format;
+
+use crate::serve::{Head, Response};
+
+/// The largest report of the visible lines that is accepted, in bytes.
+const MAX_VISIBLE_SIZE: usize = 64 * 1024;
+
+/// How many connections are handled at the same time. Further connections
+/// wait until a worker is free.
+const WORKERS: usize = 4;
+
+/// How many accepted connections may wait for a worker before the preview
+/// stops accepting more.
+const BACKLOG: usize = 16;
+
+/// How long reading a request or writing a response may stall before the
+/// connection is dropped.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long telling a page to reload may stall before the page is dropped,
+/// so that a stalled page cannot hold up the compilation.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The GUID that the WebSocket handshake appends to the client's key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The page that shows the output and reloads it whenever it is told to.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>typst-tikz preview</title>
+  <style>html, body, iframe { margin: 0; width: 100%; height: 100%; border: 0; }</style>
+</head>
+<body>
+  <iframe id="output" src="/output"></iframe>
+  <script>
+    const output = document.getElementById("output");
+    const connect = () => {
+      const socket = new WebSocket(`ws://${location.host}/reload`);
+      socket.onmessage = () => { output.src = `/output?${Date.now()}`; };
+      socket.onclose = () => setTimeout(connect, 1000);
+    };
+    connect();
+  </script>
+</body>
+</html>
+"#;
+
+/// A live preview of the output in the browser.
+///
+/// The page connects to the preview with a WebSocket, over which it is told
+/// to reload the output after each successful compilation. An editor may
+/// report the lines that it shows to `/visible`, so that the TikZ figures on
+/// them are compiled first.
+pub struct Preview {
+    /// The connections of the open pages.
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    /// The lines that the editor reported as visible last.
+    visible: Arc<Mutex<Option<Visible>>>,
+}
+
+/// The lines of a source file that are visible in an editor, which it
+/// reports by POSTing e.g. `{"path": "notes.typ", "start": 10, "end": 60}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Visible {
+    /// The source file, relative to the working directory.
+    pub path: PathBuf,
+    /// The first visible line, counted from one.
+    pub start: usize,
+    /// The last visible line.
+    pub end: usize,
+}
+
+impl Preview {
+    /// Start serving the preview of an output file in the background.
+    pub fn start(address: &str, output: &Path) -> StrResult<Self> {
+        let listener = TcpListener::bind(address)
+            .map_err(|err| eco_format!("failed to listen on {address}: {err}"))?;
+        let address = listener.local_addr().map_err(|err| err.to_string())?;
+        eprintln!("previewing {} on http://{address}", output.display());
+
+        let clients = Arc::new(Mutex::new(vec![]));
+        let visible = Arc::new(Mutex::new(None));
+        let output = output.to_path_buf();
+
+        // A fixed set of workers handles the connections, so that the browser
+        // cannot start an unbounded number of threads.
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(BACKLOG);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..WORKERS {
+            let (clients, visible, output) =
+                (clients.clone(), visible.clone(), output.clone());
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let Ok(stream) = receiver.lock().unwrap().recv() else { break };
+                if let Err(err) = handle(stream, &output, &clients, &visible) {
+                    tracing::warn!("Failed to handle preview request: {}", err);
+                }
+            });
+        }
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let timeouts = stream
+                    .set_read_timeout(Some(SOCKET_TIMEOUT))
+                    .and_then(|()| stream.set_write_timeout(Some(SOCKET_TIMEOUT)));
+                if timeouts.is_ok() && sender.send(stream).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { clients, visible })
+    }
+
+    /// The lines that the editor reported as visible last, if any.
+    pub fn visible(&self) -> Option<Visible> {
+        self.visible.lock().unwrap().clone()
+    }
+
+    /// Tell all open pages to reload the output. Pages that were closed or
+    /// stalled are forgotten.
+    pub fn reload(&self) {
+        // An unmasked WebSocket text frame.
+        let message = b"reload";
+        let mut frame = vec![0x81, message.len() as u8];
+        frame.extend_from_slice(message);
+
+        // The pages are written to without the lock, so that pages can still
+        // connect in the meantime.
+        let mut clients = std::mem::take(&mut *self.clients.lock().unwrap());
+        clients.retain_mut(|client| client.write_all(&frame).is_ok());
+        self.clients.lock().unwrap().extend(clients);
+    }
+}
+
+/// Answer a request for the page or the output, accept a WebSocket, or
+/// remember the visible lines.
+fn handle(
+    stream: TcpStream,
+    output: &Path,
+    clients: &Mutex<Vec<TcpStream>>,
+    visible: &Mutex<Option<Visible>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let head = Head::read(&mut reader)?;
+    let path = head.path.split('?').next().unwrap_or_default();
+
+    let response = match (head.method.as_str(), path) {
+        ("GET", "/") => Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: PAGE.as_bytes().to_vec(),
+        },
+        ("GET", "/output") => match fs::read(output) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: content_type(output),
+                body,
+            },
+            Err(_) => Response::text(404, "the output was not compiled yet"),
+        },
+        ("POST", "/visible") => {
+            let length = head
+                .header("content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            if length > MAX_VISIBLE_SIZE {
+                return Response::text(413, "request body is too large").write(&stream);
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            match serde_json::from_slice(&body) {
+                Ok(lines) => {
+                    *visible.lock().unwrap() = Some(lines);
+                    Response::text(200, "ok")
+                }
+                Err(err) => Response::text(400, &format!("invalid request: {err}")),
+            }
+        }
+        ("GET", "/reload") => {
+            let Some(key) = head.header("sec-websocket-key") else {
+                return Response::text(400, "expected a WebSocket").write(&stream);
+            };
+
+            let mut stream = stream;
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key),
+            )?;
+
+            // The page never sends anything, and must not hold up reloads.
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(Some(RELOAD_TIMEOUT))?;
+            clients.lock().unwrap().push(stream);
+            return Ok(());
+        }
+        _ => Response::text(404, "not found"),
+    };
+
+    response.write(&stream)
+}
+
+/// The MIME type of an output file.
+fn content_type(output: &Path) -> &'static str {
+    match output.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        _ => "application/pdf",
+    }
+}
+
+/// The key with which the server accepts a WebSocket handshake.
+fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{key}{WEBSOCKET_GUID}").as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
@@ -0,0 +1,429 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use typst::diag::StrResult;
+use typst::eval::eco_format;
+use typst_tikz::tikz::{Pipeline, RasterFallback, RenderRequest, Tikz, TikzError};
+
+use crate::{open_tikz, ServeSettings};
+
+/// The largest request body that is accepted, in bytes.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// The environment of figures whose request does not name one.
+const DEFAULT_ENVIRONMENT: &str = "tikzpicture";
+
+/// How many compilers for different preambles and outputs are kept. The least recently
+/// used one is dropped to make room for another.
+const MAX_COMPILERS: usize = 8;
+
+/// How many connections are handled at the same time. Further connections
+/// wait until a worker is free.
+const WORKERS: usize = 16;
+
+/// How many accepted connections may wait for a worker before the server
+/// stops accepting more.
+const BACKLOG: usize = 64;
+
+/// How long reading a request or writing a response may stall before the
+/// connection is dropped.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Execute a serve command.
+pub fn serve(settings: ServeSettings) -> StrResult<()> {
+    let listener = TcpListener::bind(&settings.address)
+        .map_err(|err| eco_format!("failed to listen on {}: {err}", settings.address))?;
+
+    let server = Arc::new(Server { settings, compilers: Mutex::default() });
+
+    // Fail right away instead of on the first request if LaTeX is missing.
+    server
+        .compiler(Variant::default())?
+        .verify()
+        .map_err(|err| err.to_string())?;
+
+    let address = listener.local_addr().map_err(|err| err.to_string())?;
+    eprintln!("serving TikZ figures on http://{address}/render");
+
+    // A fixed set of workers handles the connections, so that clients cannot
+    // start an unbounded number of threads.
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(BACKLOG);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let (server, receiver) = (server.clone(), receiver.clone());
+        thread::spawn(move || loop {
+            let Ok(stream) = receiver.lock().unwrap().recv() else { break };
+            if let Err(err) = server.handle(&stream) {
+                tracing::warn!("Failed to handle TikZ request: {}", err);
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let timeouts = stream
+            .set_read_timeout(Some(SOCKET_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(SOCKET_TIMEOUT)));
+        if timeouts.is_ok() && sender.send(stream).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Compiles the figures of all requests.
+struct Server {
+    /// How the figures are compiled, unless a request overrides the preamble.
+    settings: ServeSettings,
+    /// The compilers for the most recently requested variants, the most
+    /// recently used one last. All of them share the cache on disk.
+    compilers: Mutex<Vec<(Variant, Arc<Tikz>)>>,
+}
+
+/// How the compiler of a request differs from the configured one.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Variant {
+    /// Replaces the configured preamble.
+    preamble: Option<String>,
+    /// Replaces the configured pipeline, converter and raster fallback.
+    output: Option<Output>,
+}
+
+/// How the client of a document request turns figures into SVGs.
+#[derive(Debug, Clone, PartialEq)]
+struct Output {
+    /// Whether LaTeX produces a DVI file instead of a PDF.
+    dvi: bool,
+    /// Whether the converter is dvisvgm instead of pdf2svg.
+    dvisvgm: bool,
+    /// When SVGs that are too large are replaced by raster images.
+    raster: RasterFallback,
+}
+
+/// The body of a request to render a figure.
+#[derive(Debug, Deserialize)]
+struct Request {
+    /// The TikZ code of the figure.
+    code: String,
+    /// The LaTeX environment of the figure, `tikzpicture` by default.
+    environment: Option<String>,
+    /// Replaces the configured preamble.
+    preamble: Option<String>,
+}
+
+/// The body of a request to compile a complete document, as a
+/// `RemoteRenderer` sends it.
+#[derive(Debug, Deserialize)]
+struct DocumentRequest {
+    /// The LaTeX document.
+    document: String,
+    /// The files that the document references.
+    #[serde(default)]
+    inputs: Vec<DocumentInput>,
+    /// Whether the glyphs are outlined, which must match the configuration.
+    #[serde(default)]
+    outline_text: bool,
+    /// The LaTeX engine that the document asks for.
+    engine: Option<String>,
+    /// Whether the document is written for a DVI file instead of a PDF.
+    #[serde(default)]
+    dvi: bool,
+    /// Whether the client converts with dvisvgm instead of pdf2svg.
+    #[serde(default)]
+    dvisvgm: bool,
+    /// The size in bytes above which SVGs are replaced by raster images.
+    #[serde(default)]
+    raster_max_size: Option<u64>,
+    /// The number of elements above which SVGs are replaced by raster images.
+    #[serde(default)]
+    raster_max_elements: Option<usize>,
+    /// The resolution of the raster images, the configured one by default.
+    #[serde(default)]
+    raster_dpi: Option<u32>,
+}
+
+/// A file that a document references.
+#[derive(Debug, Deserialize)]
+struct DocumentInput {
+    /// The path by which the document refers to the file.
+    path: PathBuf,
+    /// The content of the file in base64.
+    content: String,
+}
+
+/// The body of the response to a document that compiled.
+#[derive(Debug, Serialize)]
+struct DocumentResponse {
+    /// The SVGs, one per page.
+    pages: Vec<String>,
+}
+
+/// The request line and headers of an HTTP request.
+pub struct Head {
+    /// The method, e.g. `POST`.
+    pub method: String,
+    /// The requested path, including the query.
+    pub path: String,
+    /// The headers, with lowercase names.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// Read the request line and headers of a request.
+    pub fn read(reader: &mut impl BufRead) -> io::Result<Self> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().into();
+        let path = parts.next().unwrap_or_default().into();
+
+        let mut headers = vec![];
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().into()));
+            }
+        }
+
+        Ok(Self { method, path, headers })
+    }
+
+    /// The value of the header with the given lowercase name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The response to a request.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Server {
+    /// Read a request from a connection and answer it.
+    fn handle(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let head = Head::read(&mut reader)?;
+        let length = head
+            .header("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+
+        let response = match (head.method.as_str(), head.path.as_str()) {
+            ("POST", "/render" | "/document") if length > MAX_BODY_SIZE => {
+                Response::text(413, "request body is too large")
+            }
+            ("POST", "/render") => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                self.render(&body)
+            }
+            ("POST", "/document") => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                self.document(&body)
+            }
+            ("GET", "/toolchain") => self.toolchain(),
+            (_, "/render" | "/document") => Response::text(405, "only POST is supported"),
+            (_, "/toolchain") => Response::text(405, "only GET is supported"),
+            _ => Response::text(404, "not found"),
+        };
+
+        response.write(stream)
+    }
+
+    /// Compile the figure of a request.
+    fn render(&self, body: &[u8]) -> Response {
+        let request: Request = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return Response::text(400, &format!("invalid request: {err}")),
+        };
+
+        let variant = Variant { preamble: request.preamble, output: None };
+        let tikz = match self.compiler(variant) {
+            Ok(tikz) => tikz,
+            Err(message) => return Response::text(500, &message),
+        };
+
+        let environment = request.environment.as_deref().unwrap_or(DEFAULT_ENVIRONMENT);
+        match tikz.render_block(environment, &request.code, "") {
+            Ok(svg) => Response {
+                status: 200,
+                content_type: "image/svg+xml",
+                body: svg.data,
+            },
+            Err(
+                err @ (TikzError::UnknownEnvironment(_) | TikzError::UnknownFigure(_)),
+            ) => Response::text(400, &err.to_string()),
+            Err(TikzError::Compilation(logs)) => Response::text(422, &logs),
+            Err(TikzError::Unembeddable(message)) => Response::text(422, &message),
+        }
+    }
+
+    /// Compile the complete document of a request from another instance.
+    fn document(&self, body: &[u8]) -> Response {
+        let request: DocumentRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return Response::text(400, &format!("invalid request: {err}")),
+        };
+
+        let mut contents = vec![];
+        for input in &request.inputs {
+            match base64::engine::general_purpose::STANDARD.decode(&input.content) {
+                Ok(content) => contents.push(content),
+                Err(err) => {
+                    let message =
+                        format!("invalid content of {}: {err}", input.path.display());
+                    return Response::text(400, &message);
+                }
+            }
+        }
+
+        let output = Output {
+            dvi: request.dvi,
+            dvisvgm: request.dvisvgm,
+            raster: RasterFallback {
+                max_size: request.raster_max_size,
+                max_elements: request.raster_max_elements,
+                dpi: request.raster_dpi.unwrap_or(self.settings.tikz.raster.dpi),
+            },
+        };
+        let variant = Variant { preamble: None, output: Some(output.clone()) };
+        let tikz = match self.compiler(variant) {
+            Ok(tikz) => tikz,
+            Err(message) => return Response::text(500, &message),
+        };
+
+        let render = RenderRequest {
+            hash: 0,
+            document: &request.document,
+            inputs: request
+                .inputs
+                .iter()
+                .zip(&contents)
+                .map(|(input, content)| (input.path.as_path(), content.as_slice()))
+                .collect(),
+            outline_text: request.outline_text,
+            engine: request.engine.as_deref(),
+            dvi: output.dvi,
+            dvisvgm: output.dvisvgm,
+            raster: &output.raster,
+        };
+
+        let pages = match tikz.render_document(&render) {
+            Ok(pages) => pages,
+            Err(logs) => return Response::text(422, &logs),
+        };
+
+        let pages = pages
+            .iter()
+            .map(|page| String::from_utf8_lossy(page).into_owned())
+            .collect();
+        match serde_json::to_vec(&DocumentResponse { pages }) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(err) => Response::text(500, &err.to_string()),
+        }
+    }
+
+    /// The fingerprint of the toolchain, by which clients tell whether their
+    /// cached figures are outdated.
+    fn toolchain(&self) -> Response {
+        match self.compiler(Variant::default()) {
+            Ok(tikz) => Response::text(200, &format!("{:016x}", tikz.fingerprint())),
+            Err(message) => Response::text(500, &message),
+        }
+    }
+
+    /// The compiler for figures of the given variant of the configuration.
+    fn compiler(&self, variant: Variant) -> StrResult<Arc<Tikz>> {
+        let mut compilers = self.compilers.lock().unwrap();
+        if let Some(index) = compilers.iter().position(|(key, _)| *key == variant) {
+            let entry = compilers.remove(index);
+            let tikz = entry.1.clone();
+            compilers.push(entry);
+            return Ok(tikz);
+        }
+
+        let mut config = self.settings.tikz.clone();
+        if let Some(preamble) = &variant.preamble {
+            config.preamble = preamble.clone();
+        }
+        if let Some(output) = &variant.output {
+            config.pipeline = if output.dvi { Pipeline::Dvi } else { Pipeline::Pdf };
+            // Keep a configured converter of the same kind, which may be
+            // installed under a path of its own.
+            let converter = if output.dvisvgm { "dvisvgm" } else { "pdf2svg" };
+            let stem = |path: &String| {
+                Path::new(path)
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(String::from)
+            };
+            if config.converter.as_ref().and_then(stem).as_deref() != Some(converter) {
+                config.converter = Some(converter.into());
+            }
+            config.raster = output.raster.clone();
+        }
+
+        let tikz = Arc::new(open_tikz(&self.settings.font_paths, config)?);
+        if compilers.len() >= MAX_COMPILERS {
+            compilers.remove(0);
+        }
+        compilers.push((variant, tikz.clone()));
+        Ok(tikz)
+    }
+}
+
+impl Response {
+    /// A plain text response.
+    pub fn text(status: u16, text: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+
+    /// Write the response to a connection and close it.
+    pub fn write(&self, mut stream: &TcpStream) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            422 => "Unprocessable Entity",
+            _ => "Internal Server Error",
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len(),
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+#[cfg(feature = "archive")]
+use std::io::Read;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use tempfile::NamedTempFile;
+
+use super::job::{Compiled, Job, StageTimings, Tool};
+use super::pool::Pages;
+use super::{page_hash, CachePolicy, PruneStats};
+
+/// How long to wait before checking an entry's lock again.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The age after which a lock is assumed to belong to a crashed process.
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(300);
+
+/// The file in the cache and in archives that records the toolchain which
+/// compiled the entries of an archive.
+const TOOLCHAIN_FILE: &str = "toolchain.json";
+
+/// A directory of compiled SVGs that is shared between projects and
+/// processes.
+///
+/// Entries are keyed by the hash of their figure. Each entry is written to a
+/// temporary file first and then renamed, so readers never see a partial
+/// entry. While a process compiles an entry, it holds a lock file, so that
+/// other processes wait for the result instead of compiling it again.
+/// Failed compilations are never stored.
+///
+/// Each page of a figure is a separate entry. The first page is written last,
+/// so a figure whose first page is in the cache is complete. A figure with
+/// several pages records their number next to its first page, so that its
+/// pages are removed together and a missing page makes it a miss. Next to it,
+/// the
+/// LaTeX document of the figure and the engine it asked for are kept, so that
+/// a stale reference to the figure can still be compiled as long as the
+/// document is there, and the LaTeX warnings of the figure, if it had any, so
+/// that they are reported again whenever it is taken from the cache. These
+/// files belong to the entry of the first page and are removed with it.
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Open the cache in the given directory, creating it if necessary.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Read the SVG with the given hash, if it is in the cache.
+    pub fn load(&self, hash: u64) -> Option<Vec<u8>> {
+        fs::read(self.entry(hash)).ok()
+    }
+
+    /// Read all pages of the figure with the given hash, if they are all in
+    /// the cache.
+    pub fn load_pages(&self, hash: u64) -> Option<Vec<Vec<u8>>> {
+        let first = self.load(hash)?;
+        let count = match self.page_count(hash) {
+            Some(count) => count,
+            // A figure with a second page but without a count was stored
+            // before counts were recorded, and may have lost pages since.
+            None if self.entry(page_hash(hash, 1)).exists() => return None,
+            None => 1,
+        };
+
+        let mut pages = vec![first];
+        for page in 1..count {
+            pages.push(self.load(page_hash(hash, page))?);
+        }
+        Some(pages)
+    }
+
+    /// Read the number of pages of the figure with the given hash, if it has
+    /// several and the cache recorded them.
+    fn page_count(&self, hash: u64) -> Option<usize> {
+        fs::read_to_string(self.dir.join(pages_name(hash)))
+            .ok()
+            .and_then(|count| count.trim().parse().ok())
+    }
+
+    /// Read the LaTeX warnings of the figure with the given hash, which are
+    /// empty if it had none or is not in the cache.
+    pub fn warnings(&self, hash: u64) -> Vec<String> {
+        fs::read(self.dir.join(warnings_name(hash)))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Read the LaTeX document of the figure with the given hash, if the cache
+    /// kept it.
+    pub fn source(&self, hash: u64) -> Option<String> {
+        fs::read_to_string(self.dir.join(source_name(hash))).ok()
+    }
+
+    /// Read the LaTeX engine that the figure with the given hash asked for, if
+    /// it asked for one and the cache kept its document.
+    pub fn engine(&self, hash: u64) -> Option<String> {
+        fs::read_to_string(self.dir.join(engine_name(hash))).ok()
+    }
+
+    /// The toolchain that compiled the entries of the last imported archive,
+    /// if any.
+    pub fn toolchain(&self) -> Option<Vec<Tool>> {
+        let json = fs::read(self.dir.join(TOOLCHAIN_FILE)).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// Write all entries into a zstd-compressed tarball, together with the
+    /// toolchain that compiled them. Returns the number of entries.
+    #[cfg(feature = "archive")]
+    pub fn export(&self, writer: impl Write, toolchain: &[Tool]) -> io::Result<usize> {
+        let encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        let mut builder = tar::Builder::new(encoder);
+        append(&mut builder, TOOLCHAIN_FILE, &serde_json::to_vec_pretty(toolchain)?)?;
+
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|entry| entry.hash);
+        let mut count = 0;
+        for path in entries.iter().flat_map(|entry| &entry.files) {
+            // Only the pages and their counts are exported, not the documents
+            // and warnings.
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let svg = parse(name).is_some();
+            if !svg && parse_pages(name).is_none() {
+                continue;
+            }
+
+            // Entries may vanish while another process prunes the cache.
+            let Ok(data) = fs::read(path) else { continue };
+            append(&mut builder, name, &data)?;
+            count += usize::from(svg);
+        }
+
+        builder.into_inner()?.finish()?;
+        Ok(count)
+    }
+
+    /// Add the entries of a tarball that `export` wrote, and remember the
+    /// toolchain that compiled them. Entries that are already in the cache
+    /// are kept. Returns the number of added entries.
+    ///
+    /// Only files that are named like entries are taken, so that an archive
+    /// cannot write anywhere else.
+    #[cfg(feature = "archive")]
+    pub fn import(&self, reader: impl Read) -> io::Result<usize> {
+        let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+        let mut count = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if name == TOOLCHAIN_FILE {
+                let mut json = vec![];
+                entry.read_to_end(&mut json)?;
+                serde_json::from_slice::<Vec<Tool>>(&json)?;
+                self.write(TOOLCHAIN_FILE, &json)?;
+                continue;
+            }
+
+            if let Some(hash) = parse_pages(&name) {
+                let mut count = vec![];
+                entry.read_to_end(&mut count)?;
+                if !self.dir.join(pages_name(hash)).exists() {
+                    self.write(&pages_name(hash), &count)?;
+                }
+                continue;
+            }
+
+            let Some(hash) = parse(&name) else { continue };
+            if self.entry(hash).exists() {
+                continue;
+            }
+
+            let mut svg = vec![];
+            entry.read_to_end(&mut svg)?;
+            self.store(hash, &svg)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Returns the pages of a job, taking them from the cache if possible and
+    /// storing them otherwise. A `fresh` job is always run, and its pages
+    /// replace those in the cache.
+    pub fn compile(&self, job: &Job, fresh: bool) -> Pages {
+        let lock = self.lock(job.hash);
+
+        // Another process may have stored the entry while we were waiting.
+        let start = Instant::now();
+        if let Some(pages) = self.load_pages(job.hash).filter(|_| !fresh) {
+            trace_event!(info, "Found TikZ figure {} in the shared cache", job.hash);
+            let warnings = self.warnings(job.hash);
+            let timings = StageTimings { io: start.elapsed(), ..Default::default() };
+            return Ok(Compiled { pages, engine: None, warnings, timings });
+        }
+
+        let pages = job.run();
+
+        // Without the lock, another process may write the same entry.
+        match (&pages, &lock) {
+            (Ok(compiled), Some(_)) => {
+                let stored = self
+                    .store_source(job)
+                    .and_then(|()| self.store_pages(job.hash, compiled));
+                if let Err(_err) = stored {
+                    trace_event!(warn, "Failed to store TikZ figure in cache: {}", _err);
+                }
+            }
+            (Ok(_), None) => {
+                trace_event!(
+                    debug,
+                    "Not caching TikZ figure {} without its lock",
+                    job.hash
+                );
+            }
+            (Err(_), _) => {
+                trace_event!(debug, "Not caching failed TikZ figure {}", job.hash);
+            }
+        }
+
+        pages
+    }
+
+    /// Remove all entries whose hashes are not kept, together with their
+    /// documents, engines and warnings.
+    pub fn prune(&self, keep: &HashSet<u64>) -> io::Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for entry in self.entries()? {
+            if !keep.contains(&entry.hash) {
+                remove(&entry, &mut stats);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Remove entries that exceed the limits of a policy.
+    ///
+    /// First removes all entries that are older than the maximum age, then the
+    /// least recently used entries until the cache fits into the maximum size.
+    /// The documents, engines and warnings of the figures count towards the
+    /// size and are removed together with their entries.
+    pub fn collect_garbage(&self, policy: &CachePolicy) -> io::Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        let mut entries = self.entries()?;
+
+        if let Some(max_age) = policy.max_age {
+            let now = SystemTime::now();
+            entries.retain(|entry| {
+                let expired =
+                    now.duration_since(entry.used).map_or(false, |age| age > max_age);
+                if expired {
+                    remove(entry, &mut stats);
+                }
+                !expired
+            });
+        }
+
+        if let Some(max_size) = policy.max_size {
+            entries.sort_by_key(|entry| entry.used);
+            let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+            for entry in &entries {
+                if size <= max_size {
+                    break;
+                }
+                size -= entry.size;
+                remove(entry, &mut stats);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Remove all files from the cache, including temporary files that were
+    /// left behind by crashed processes. Only the locks of running
+    /// compilations are kept.
+    pub fn clear(&self) -> io::Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for item in fs::read_dir(&self.dir)? {
+            let item = item?;
+            let path = item.path();
+            if path.extension().map_or(false, |ext| ext == "lock") {
+                continue;
+            }
+
+            let size = item.metadata().map_or(0, |metadata| metadata.len());
+            if fs::remove_file(&path).is_ok() {
+                stats.removed += 1;
+                stats.freed += size;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// All entries in the cache, including those of which only the document
+    /// of the figure is left. The later pages of a figure with a recorded
+    /// count belong to the entry of its first page.
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        let mut entries = HashMap::<u64, Entry>::new();
+        let mut counted = vec![];
+        for item in fs::read_dir(&self.dir)? {
+            let item = item?;
+            let name = item.file_name();
+            let Some(name) = name.to_str() else { continue };
+            let Some(hash) = owner(name) else { continue };
+            if parse_pages(name).is_some() {
+                counted.push(hash);
+            }
+
+            // The access time is only updated coarsely on most systems, but
+            // that is good enough to find entries that were not used for days.
+            let Ok(metadata) = item.metadata() else { continue };
+            let used = metadata.accessed().or_else(|_| metadata.modified())?;
+            let entry = entries.entry(hash).or_insert_with(|| Entry {
+                hash,
+                files: vec![],
+                size: 0,
+                used,
+            });
+            entry.files.push(item.path());
+            entry.size += metadata.len();
+            entry.used = entry.used.max(used);
+        }
+
+        for hash in counted {
+            for page in 1..self.page_count(hash).unwrap_or(1) {
+                let Some(later) = entries.remove(&page_hash(hash, page)) else {
+                    continue;
+                };
+                let Some(entry) = entries.get_mut(&hash) else { continue };
+                entry.files.extend(later.files);
+                entry.size += later.size;
+                entry.used = entry.used.max(later.used);
+            }
+        }
+
+        Ok(entries.into_values().collect())
+    }
+
+    /// Write the LaTeX document of a job and the engine it asks for.
+    fn store_source(&self, job: &Job) -> io::Result<()> {
+        match &job.engine {
+            Some(engine) => self.write(&engine_name(job.hash), engine.as_bytes())?,
+            None => {
+                fs::remove_file(self.dir.join(engine_name(job.hash))).ok();
+            }
+        }
+        self.write(&source_name(job.hash), job.document.as_bytes())
+    }
+
+    /// Write the warnings and the entries of all pages of a figure, the first
+    /// page last.
+    pub fn store_pages(&self, hash: u64, compiled: &Compiled) -> io::Result<()> {
+        // Warnings of an earlier compilation must not outlive it.
+        let path = self.dir.join(warnings_name(hash));
+        if compiled.warnings.is_empty() {
+            fs::remove_file(path).ok();
+        } else {
+            self.write(&warnings_name(hash), &serde_json::to_vec(&compiled.warnings)?)?;
+        }
+
+        // The count is written before the first page, which completes the
+        // figure.
+        let path = self.dir.join(pages_name(hash));
+        if compiled.pages.len() > 1 {
+            self.write(&pages_name(hash), compiled.pages.len().to_string().as_bytes())?;
+        } else {
+            fs::remove_file(path).ok();
+        }
+
+        for (page, svg) in compiled.pages.iter().enumerate().rev() {
+            self.store(page_hash(hash, page), svg)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically write an entry.
+    fn store(&self, hash: u64, svg: &[u8]) -> io::Result<()> {
+        self.write(&name(hash), svg)
+    }
+
+    /// Atomically write a file into the cache.
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(data)?;
+        file.persist(self.dir.join(name)).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// Acquire the lock of an entry, waiting while another process holds it.
+    ///
+    /// Returns `None` if the lock cannot be created at all, e.g. because the
+    /// cache directory is read-only.
+    fn lock(&self, hash: u64) -> Option<Lock> {
+        let path = self.dir.join(format!("{:016x}.lock", hash));
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Some(Lock { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if !is_stale(&path) {
+                        thread::sleep(LOCK_POLL_INTERVAL);
+                    } else if fs::remove_file(&path).is_err() {
+                        return None;
+                    }
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// The path of the entry with the given hash.
+    pub fn entry(&self, hash: u64) -> PathBuf {
+        self.dir.join(name(hash))
+    }
+}
+
+/// The file name of the entry with the given hash.
+fn name(hash: u64) -> String {
+    format!("{:016x}.svg", hash)
+}
+
+/// The file name of the LaTeX document of the figure with the given hash.
+fn source_name(hash: u64) -> String {
+    format!("{:016x}.tex", hash)
+}
+
+/// The file name of the LaTeX engine of the figure with the given hash.
+fn engine_name(hash: u64) -> String {
+    format!("{:016x}.engine", hash)
+}
+
+/// The file name of the number of pages of the figure with the given hash.
+fn pages_name(hash: u64) -> String {
+    format!("{:016x}.pages", hash)
+}
+
+/// The file name of the LaTeX warnings of the figure with the given hash.
+fn warnings_name(hash: u64) -> String {
+    format!("{:016x}.warnings.json", hash)
+}
+
+/// The hash of the entry with the given file name, if it is one.
+fn parse(name: &str) -> Option<u64> {
+    name.strip_suffix(".svg").and_then(parse_hash)
+}
+
+/// The hash of the figure whose number of pages a file with the given name
+/// records, if it does.
+fn parse_pages(name: &str) -> Option<u64> {
+    name.strip_suffix(".pages").and_then(parse_hash)
+}
+
+/// The hash of the entry that a file with the given name belongs to, if it
+/// is an entry or a file that is kept next to one.
+fn owner(name: &str) -> Option<u64> {
+    [".svg", ".tex", ".engine", ".pages", ".warnings.json"]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .and_then(parse_hash)
+}
+
+/// Parse the hexadecimal hash at the start of a file name.
+fn parse_hash(hash: &str) -> Option<u64> {
+    Some(hash)
+        .filter(|hash| hash.len() == 16)
+        .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+}
+
+/// Append a file to a tarball.
+#[cfg(feature = "archive")]
+fn append(
+    builder: &mut tar::Builder<impl Write>,
+    name: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, name, data)
+}
+
+/// Whether a lock file is older than any compilation should take.
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map_or(false, |age| age > LOCK_STALE_AFTER)
+}
+
+/// Remove all files of an entry and count it. Entries that vanished in the
+/// meantime are skipped.
+fn remove(entry: &Entry, stats: &mut PruneStats) {
+    let mut removed = false;
+    for path in &entry.files {
+        removed |= fs::remove_file(path).is_ok();
+    }
+    if removed {
+        stats.removed += 1;
+        stats.freed += entry.size;
+    }
+}
+
+/// An SVG in the cache, together with the files that are kept next to it.
+struct Entry {
+    /// The hash of its figure.
+    hash: u64,
+    /// Where its SVG, if it has not been removed, and the other files are
+    /// stored.
+    files: Vec<PathBuf>,
+    /// The size of all its files in bytes.
+    size: u64,
+    /// When any of its files was last read or written.
+    used: SystemTime,
+}
+
+/// A held lock on a cache entry, released when dropped.
+struct Lock {
+    path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// The CeTZ package that translated figures import.
+const CETZ_PACKAGE: &str = "@preview/cetz:0.0.1";
+
+/// How far lines stop short of the nodes they connect, in centimeters.
+///
+/// TikZ ends lines at the border of a node, which depends on the size of its
+/// text. Since that size is unknown here, lines end at a fixed distance from
+/// the center instead, which suits nodes with a letter or two.
+const NODE_CLEARANCE: f64 = 0.3;
+
+lazy_static! {
+    static ref REG_NODE: Regex = Regex::new(
+        r"^\\node\s*(?:\[\s*\])?\s*(?:\((?P<name>[\w\s-]+)\))?\s*at\s*\((?P<at>[^()]*)\)\s*\{(?P<text>[^{}]*)\}$"
+    )
+    .unwrap();
+    static ref REG_DRAW: Regex =
+        Regex::new(r"^\\draw\s*(?:\[(?P<options>[^\]]*)\])?\s*(?P<path>.*)$").unwrap();
+    static ref REG_CIRCLE: Regex =
+        Regex::new(r"^\((?P<center>[^()]*)\)\s*circle\s*\((?P<radius>[^()]*)\)$").unwrap();
+    static ref REG_RECTANGLE: Regex =
+        Regex::new(r"^\((?P<from>[^()]*)\)\s*rectangle\s*\((?P<to>[^()]*)\)$").unwrap();
+}
+
+/// Translate the code of a `tikzpicture` into Typst code that draws it with
+/// CeTZ, so that it needs no LaTeX and stays editable.
+///
+/// Only a small subset of TikZ is understood: Nodes with plain text at
+/// explicit coordinates, straight lines between coordinates and nodes,
+/// circles and rectangles, with arrow tips, line widths, dashes and the basic
+/// colors. Returns `None` for anything else, so that the figure is compiled by
+/// LaTeX as usual.
+pub(crate) fn translate(tex_code: &str) -> Option<String> {
+    let mut nodes = HashMap::new();
+    let mut commands = vec![];
+
+    for statement in statements(tex_code)? {
+        if let Some(capture) = REG_NODE.captures(&statement) {
+            let at = point(&capture["at"])?;
+            let text = text(capture["text"].trim())?;
+            if let Some(name) = capture.name("name") {
+                nodes.insert(name.as_str().trim().to_string(), at);
+            }
+            commands.push(format!("content({}, [{}])", coordinates(at), text));
+        } else if let Some(capture) = REG_DRAW.captures(&statement) {
+            let options = capture.name("options").map_or("", |m| m.as_str());
+            commands.push(draw(options, capture["path"].trim(), &nodes)?);
+        } else {
+            return None;
+        }
+    }
+
+    if commands.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        r#"{{import "{}"; cetz.canvas({{import cetz.draw: *; {}}})}}"#,
+        CETZ_PACKAGE,
+        commands.join("; ")
+    ))
+}
+
+/// Split TikZ code into its statements without comments, or return `None` if
+/// it contains anything that a statement cannot end in.
+fn statements(tex_code: &str) -> Option<Vec<String>> {
+    let code: Vec<_> = tex_code
+        .lines()
+        .map(|line| match line.find('%') {
+            Some(index) if !line[..index].ends_with('\\') => &line[..index],
+            _ => line,
+        })
+        .collect();
+
+    let code = code.join(" ");
+    let (statements, rest) = code.rsplit_once(';').unwrap_or(("", &code));
+    if !rest.trim().is_empty() {
+        return None;
+    }
+
+    Some(
+        statements
+            .split(';')
+            .map(|statement| statement.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|statement| !statement.is_empty())
+            .collect(),
+    )
+}
+
+/// Translate a `\draw` command with the given options and path.
+fn draw(
+    options: &str,
+    path: &str,
+    nodes: &HashMap<String, (f64, f64)>,
+) -> Option<String> {
+    let style = style(options)?;
+
+    if let Some(capture) = REG_CIRCLE.captures(path) {
+        let center = point(&capture["center"])?;
+        let radius = number(&capture["radius"])?;
+        return Some(format!(
+            "circle({}, radius: {}{})",
+            coordinates(center),
+            radius,
+            style.shape
+        ));
+    }
+
+    if let Some(capture) = REG_RECTANGLE.captures(path) {
+        let (from, to) = (point(&capture["from"])?, point(&capture["to"])?);
+        return Some(format!(
+            "rect({}, {}{})",
+            coordinates(from),
+            coordinates(to),
+            style.shape
+        ));
+    }
+
+    // A line through points and nodes, which are resolved to their centers.
+    let ends: Vec<_> = path
+        .split("--")
+        .map(|end| {
+            let inner = end.trim().strip_prefix('(')?.strip_suffix(')')?;
+            match nodes.get(inner.trim()) {
+                Some(&at) => Some((at, true)),
+                None => Some((point(inner)?, false)),
+            }
+        })
+        .collect::<Option<_>>()?;
+
+    if ends.len() < 2 {
+        return None;
+    }
+
+    // Only the lines that touch a node stop short of it.
+    let mut points: Vec<_> = ends.iter().map(|&(at, _)| at).collect();
+    let last = ends.len() - 1;
+    if ends[0].1 {
+        points[0] = approach(ends[0].0, ends[1].0);
+    }
+    if ends[last].1 {
+        points[last] = approach(ends[last].0, ends[last - 1].0);
+    }
+
+    let points: Vec<_> = points.into_iter().map(coordinates).collect();
+    Some(format!("line({}{}{})", points.join(", "), style.shape, style.marks))
+}
+
+/// The style of a `\draw` command in CeTZ.
+struct Style {
+    /// The named arguments for the stroke, starting with a comma if any.
+    shape: String,
+    /// The named argument for the arrow tips, starting with a comma if any.
+    marks: String,
+}
+
+/// Translate the options of a `\draw` command, or return `None` if any of
+/// them is not supported.
+fn style(options: &str) -> Option<Style> {
+    let mut stroke = vec![];
+    let mut marks = vec![];
+
+    for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        match option {
+            "-" => {}
+            "->" => marks.push(r#"end: ">""#),
+            "<-" => marks.push(r#"start: ">""#),
+            "<->" => marks.extend([r#"start: ">""#, r#"end: ">""#]),
+            "thin" => stroke.push("thickness: 0.4pt".to_string()),
+            "thick" => stroke.push("thickness: 0.8pt".to_string()),
+            "very thick" => stroke.push("thickness: 1.2pt".to_string()),
+            "dashed" => stroke.push(r#"dash: "dashed""#.to_string()),
+            "dotted" => stroke.push(r#"dash: "dotted""#.to_string()),
+            "red" | "green" | "blue" | "black" | "gray" | "white" | "yellow"
+            | "orange" | "purple" => stroke.push(format!("paint: {}", option)),
+            _ => return None,
+        }
+    }
+
+    let shape = if stroke.is_empty() {
+        String::new()
+    } else {
+        format!(", stroke: ({})", stroke.join(", "))
+    };
+
+    let marks = if marks.is_empty() {
+        String::new()
+    } else {
+        format!(", mark: ({})", marks.join(", "))
+    };
+
+    Some(Style { shape, marks })
+}
+
+/// Parse explicit coordinates like `1,2.5`.
+fn point(text: &str) -> Option<(f64, f64)> {
+    let (x, y) = text.split_once(',')?;
+    Some((number(x)?, number(y)?))
+}
+
+/// Parse a finite number. Rust also reads `inf` and `NaN`, which are no
+/// valid Typst.
+fn number(text: &str) -> Option<f64> {
+    text.trim().parse::<f64>().ok().filter(|number| number.is_finite())
+}
+
+/// Typst code for coordinates.
+fn coordinates((x, y): (f64, f64)) -> String {
+    format!("({}, {})", x, y)
+}
+
+/// The point on the way from a node to another point at which a line stops.
+fn approach(node: (f64, f64), towards: (f64, f64)) -> (f64, f64) {
+    let (dx, dy) = (towards.0 - node.0, towards.1 - node.1);
+    let distance = dx.hypot(dy);
+    if distance <= 2.0 * NODE_CLEARANCE {
+        return node;
+    }
+
+    let factor = NODE_CLEARANCE / distance;
+    (node.0 + dx * factor, node.1 + dy * factor)
+}
+
+/// Translate the text of a node into Typst markup, or return `None` if it
+/// contains LaTeX commands or math that Typst would read differently.
+///
+/// Math is only kept if it consists of single letters, digits and simple
+/// operators, which look the same in both.
+fn text(text: &str) -> Option<String> {
+    let plain = |c: char| c.is_alphanumeric() || " .,:!?'()-".contains(c);
+    let mut math = false;
+    let mut previous = ' ';
+    for c in text.chars() {
+        match c {
+            '$' => math = !math,
+            c if math && c.is_alphabetic() && previous.is_alphabetic() => return None,
+            c if math && (c.is_alphanumeric() || " +-=<>".contains(c)) => {}
+            c if !math && plain(c) => {}
+            _ => return None,
+        }
+        previous = c;
+    }
+
+    (!math).then(|| text.to_string())
+}
@@ -0,0 +1,88 @@
+use typst::diag::SourceError;
+use typst::syntax::{ast, LinkedNode, Source, Span};
+use typst::World;
+
+/// The string literals that an error points at. With `trace`, those of its
+/// trace follow, e.g. of the call to a helper function that loads an image
+/// on behalf of the rewritten buffer.
+///
+/// This does not depend on the wording of the error or on whether its span
+/// includes the quotes around a path. Everything that relies on the shape of
+/// Typst's diagnostics lives in this module, so that new releases of Typst
+/// only need to be followed here.
+pub(crate) fn strings(
+    world: &dyn World,
+    error: &SourceError,
+    trace: bool,
+) -> Vec<String> {
+    let mut found = vec![];
+    spanned(world, error.span, &mut found);
+    if trace {
+        for point in &error.trace {
+            spanned(world, point.span, &mut found);
+        }
+    }
+    found
+}
+
+/// The path of the file in which an error was raised.
+pub(crate) fn path(error: &SourceError) -> String {
+    error.span.id().path().to_string_lossy().into_owned()
+}
+
+/// Collect the string literals within a span.
+fn spanned(world: &dyn World, span: Span, found: &mut Vec<String>) {
+    let Ok(source) = world.source(span.id()) else { return };
+    match source.find(span) {
+        Some(node) => literals(&node, found),
+        // Fall back to the text, which is the quoted path for load errors.
+        None => {
+            let range = span.range(world);
+            if let Some(text) = source.text().get(range) {
+                found.push(text.trim().trim_matches('"').into());
+            }
+        }
+    }
+}
+
+/// The paths that a Typst file includes or imports with string literals, in
+/// the order in which they appear. Paths that are computed, like
+/// `"ch" + str(n) + ".typ"`, cannot be followed and are left out.
+pub(crate) fn includes(text: &str) -> Vec<String> {
+    let source = Source::detached(text);
+    let mut found = vec![];
+    modules(&LinkedNode::new(source.root()), &mut found);
+    found
+}
+
+/// Collect the literal paths of includes and imports in a syntax tree.
+fn modules(node: &LinkedNode, found: &mut Vec<String>) {
+    let path = match (node.cast::<ast::ModuleInclude>(), node.cast::<ast::ModuleImport>())
+    {
+        (Some(include), _) => Some(include.source()),
+        (_, Some(import)) => Some(import.source()),
+        _ => None,
+    };
+
+    match path {
+        Some(ast::Expr::Str(literal)) => found.push(literal.get().into()),
+        Some(_) => {}
+        None => {
+            for child in node.children() {
+                modules(&child, found);
+            }
+        }
+    }
+}
+
+/// Collect the string literals in a syntax tree.
+fn literals(node: &LinkedNode, found: &mut Vec<String>) {
+    match node.cast::<ast::Str>() {
+        Some(literal) => found.push(literal.get().into()),
+        None => {
+            for child in node.children() {
+                literals(&child, found);
+            }
+        }
+    }
+}
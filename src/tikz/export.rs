@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use super::{BlockStatus, ReplaceReport, Tikz, REG_GNUPLOT};
+
+/// A format in which figures are exported.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// SVGs, one per page, which the rewritten buffers refer to.
+    #[default]
+    Svg,
+    /// The PDF with all pages of a figure, e.g. for LaTeX documents.
+    Pdf,
+    /// PNGs, one per page, at the resolution of the raster fallback, e.g.
+    /// for websites.
+    Png,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "svg" => Ok(Self::Svg),
+            "pdf" => Ok(Self::Pdf),
+            "png" => Ok(Self::Png),
+            _ => Err(format!("invalid export format {}, expected svg, pdf or png", s)),
+        }
+    }
+}
+
+/// Whether any of the formats is made from the PDF that LaTeX produces.
+pub(crate) fn needs_pdf(formats: &[ExportFormat]) -> bool {
+    formats.iter().any(|&format| format != ExportFormat::Svg)
+}
+
+/// The figures that were written to disk, e.g. to compile a rewritten buffer
+/// with plain Typst.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Manifest {
+    /// The exported figures.
+    pub figures: Vec<ExportedFigure>,
+}
+
+impl Manifest {
+    /// Write the manifest as JSON.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+    }
+}
+
+/// A figure that was written to disk.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedFigure {
+    /// The hash of the figure. It is serialized as a string, since JSON
+    /// numbers cannot represent all hashes exactly.
+    #[serde(serialize_with = "super::serialize_hash")]
+    pub hash: u64,
+    /// The source file that contains the figure, if known.
+    pub source: Option<PathBuf>,
+    /// The byte range of the figure in the original source.
+    pub range: Range<usize>,
+    /// The LaTeX environment of the figure, e.g. `tikzcd`.
+    pub environment: String,
+    /// The page of the figure, counted from zero. Figures with several pages
+    /// are exported once per page.
+    pub page: usize,
+    /// The written SVG.
+    pub file: PathBuf,
+    /// The copy of the SVG under the name of the figure, if it has one, so
+    /// that other documents and tools can refer to it without its hash.
+    /// Copies of the PDF and the PNG have the same name with their own
+    /// extension.
+    pub named: Option<PathBuf>,
+    /// The written PDF with all pages of the figure, if PDFs are exported.
+    pub pdf: Option<PathBuf>,
+    /// The written PNG of the page, if PNGs are exported.
+    pub png: Option<PathBuf>,
+    /// Why the PDF or the PNG of the page could not be written, in which
+    /// case the page is exported without them.
+    pub error: Option<String>,
+}
+
+impl Tikz {
+    /// Write the images of the figures of a replacement into a directory,
+    /// under the filenames that the rewritten buffer refers to.
+    ///
+    /// Figures that failed to compile or to embed are skipped. The source of
+    /// the returned figures is unknown, since the report does not contain it.
+    ///
+    /// A figure with a `name` is also written as `<name>.svg`, and its later
+    /// pages as `<name>-2.svg` and so on. Names must be plain filenames, and
+    /// different figures must not share one.
+    ///
+    /// PDFs and PNGs are written next to the SVGs if `export_formats` asks
+    /// for them, with the background and the margin of the figure. They are
+    /// taken from the files of the compilation of the figure. A figure that
+    /// left none, e.g. since it was taken from the shared cache, is compiled
+    /// again. If that fails, the error is recorded in the exported figure and
+    /// the other figures are exported nonetheless.
+    ///
+    /// The helper module, if one is configured, is written as well, unless
+    /// the directory already has one.
+    pub fn export(
+        &self,
+        report: &ReplaceReport,
+        dir: &Path,
+    ) -> io::Result<Vec<ExportedFigure>> {
+        fs::create_dir_all(dir)?;
+        self.write_helper(dir)?;
+
+        let mut written = HashSet::new();
+        let mut names = HashMap::new();
+        let mut figures = vec![];
+        for block in &report.blocks {
+            if block.status != BlockStatus::Compiled {
+                continue;
+            }
+
+            if let Some(name) = &block.name {
+                if !is_plain_name(name) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "the name {:?} of a TikZ figure is not a plain filename",
+                            name
+                        ),
+                    ));
+                }
+
+                if *names.entry(name.as_str()).or_insert(block.hash) != block.hash {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("several TikZ figures are named {:?}", name),
+                    ));
+                }
+            }
+
+            let formats = &self.config.export_formats;
+            let artifacts = match needs_pdf(formats) {
+                true => Some(self.artifacts(block.hash)),
+                false => None,
+            };
+
+            for (page, &hash) in block.pages.iter().enumerate() {
+                let Some(Ok(svg)) = self.fetch(hash) else { continue };
+                let file = dir.join(self.filename(hash));
+                if written.insert(hash) {
+                    if let Some(parent) = file.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(&file, &svg)?;
+                }
+
+                let named = match &block.name {
+                    Some(name) => {
+                        let named = dir.join(match page {
+                            0 => format!("{}.svg", name),
+                            page => format!("{}-{}.svg", name, page + 1),
+                        });
+                        fs::write(&named, &svg)?;
+                        Some(named)
+                    }
+                    None => None,
+                };
+
+                let (mut pdf, mut png) = (None, None);
+                let written = match &artifacts {
+                    Some(Ok((from, stem))) => {
+                        // The PDF holds all pages, so it is written with the
+                        // first.
+                        let mut written = Ok(());
+                        if formats.contains(&ExportFormat::Pdf) {
+                            let to =
+                                dir.join(self.filename(block.hash)).with_extension("pdf");
+                            if page == 0 {
+                                let rendered = from.join(format!("{}.pdf", stem));
+                                written = copy(&rendered, &to, named.as_deref());
+                            }
+                            pdf = written.is_ok().then_some(to);
+                        }
+                        if written.is_ok() && formats.contains(&ExportFormat::Png) {
+                            let to = file.with_extension("png");
+                            let rendered =
+                                from.join(format!("{}-{}.png", stem, page + 1));
+                            written = copy(&rendered, &to, named.as_deref());
+                            png = written.is_ok().then_some(to);
+                        }
+                        written
+                    }
+                    Some(Err(message)) => Err(message.clone()),
+                    None => Ok(()),
+                };
+
+                let error = written.err();
+                if let Some(_message) = &error {
+                    trace_event!(
+                        warn,
+                        "Failed to export page {} of TikZ figure {}: {}",
+                        page + 1,
+                        block.hash,
+                        _message
+                    );
+                }
+
+                figures.push(ExportedFigure {
+                    hash: block.hash,
+                    source: None,
+                    range: block.range.clone(),
+                    environment: block.environment.clone(),
+                    page,
+                    file,
+                    named,
+                    pdf,
+                    png,
+                    error,
+                });
+            }
+        }
+
+        Ok(figures)
+    }
+
+    /// The directory with the PDF and the PNGs of a figure and the name of
+    /// the PDF without its extension, which compiles the figure again if they
+    /// are missing.
+    fn artifacts(&self, hash: u64) -> Result<(PathBuf, &'static str), String> {
+        let known = self.exported.lock().unwrap().get(&hash).cloned();
+        let job = match known {
+            Some(job) => job,
+            // Figures that this compiler did not replace are only known by the
+            // document and the engine that the cache keeps for them.
+            None => {
+                let cache = self.pool.cache();
+                let Some(document) = cache.and_then(|cache| cache.source(hash)) else {
+                    return Err(format!(
+                        "the LaTeX document of TikZ figure {} is not cached",
+                        hash
+                    ));
+                };
+                let engine = cache.and_then(|cache| cache.engine(hash));
+                let gnuplot = REG_GNUPLOT.is_match(&document);
+                self.document_job(hash, document, vec![], engine, gnuplot)
+            }
+        };
+
+        let stem = job.exported_stem();
+        let pdf = job.dir.join(format!("{}.pdf", stem));
+        let png = job.dir.join(format!("{}-1.png", stem));
+        if pdf.exists() && (!job.pngs || png.exists()) {
+            return Ok((job.dir, stem));
+        }
+
+        if self.renderer.is_some() {
+            return Err("TikZ figures that are compiled remotely cannot be exported as \
+                        PDFs or PNGs"
+                .into());
+        }
+
+        job.run().map_err(|err| {
+            format!("failed to compile TikZ figure {} for its export: {}", hash, err)
+        })?;
+
+        Ok((job.dir, stem))
+    }
+}
+
+/// Copy a file of a compilation into the export directory, and also under the
+/// name of the figure, with the extension of the copy.
+fn copy(from: &Path, to: &Path, named: Option<&Path>) -> Result<(), String> {
+    let copied = fs::copy(from, to).and_then(|_| match (named, to.extension()) {
+        (Some(named), Some(extension)) => {
+            fs::copy(from, named.with_extension(extension)).map(|_| ())
+        }
+        _ => Ok(()),
+    });
+    copied.map_err(|err| format!("failed to write {}: {}", to.display(), err))
+}
+
+/// Whether the name of a figure can be used as a filename on every system,
+/// without leaving the export directory.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
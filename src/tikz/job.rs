@@ -0,0 +1,1604 @@
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::render::{RenderRequest, Renderer};
+use super::Stopwatch;
+
+pub(crate) const LATEX_ENGINE: &str = "lualatex";
+const PDF_CONVERTER: &str = "pdf2svg";
+const OUTLINE_CONVERTER: &str = "dvisvgm";
+const GNUPLOT: &str = "gnuplot";
+const RASTERIZER: &str = "pdftoppm";
+
+lazy_static! {
+    /// Gnuplot commands that could run other programs or touch files outside
+    /// of the tables that pgfplots asks for.
+    static ref REG_GNUPLOT_UNSAFE: Regex = Regex::new(
+        r#"(?m)\b(?:system|shell|load|call|cd|save|print|output|pipe)\b|`|^\s*!|["'][<|]"#
+    )
+    .unwrap();
+
+    /// Comments, in which converters record their versions and the like.
+    static ref REG_SVG_COMMENT: Regex = Regex::new(r"(?s)<!--.*?-->\r?\n?").unwrap();
+
+    /// The start tag of the root element of an SVG.
+    static ref REG_SVG_ROOT: Regex = Regex::new(r"<svg\b[^>]*>").unwrap();
+
+    /// An attribute of the root element of an SVG that determines its size.
+    static ref REG_SVG_SIZE: Regex =
+        Regex::new(r#"\s(?P<name>width|height|viewBox)\s*=\s*["'](?P<value>[^"']*)["']"#)
+            .unwrap();
+
+    /// The first line of a warning in a LaTeX log, with the name of the
+    /// package for package warnings.
+    static ref REG_LATEX_WARNING: Regex = Regex::new(
+        r"^(?:(?:Package|Class) (?P<package>\S+) Warning:|LaTeX(?: Font)? Warning:|pdfTeX warning|Overfull \\[hv]box|Underfull \\[hv]box|Missing character:)"
+    )
+    .unwrap();
+
+    /// The last, unfinished line of the output of a TeX engine that waits for
+    /// input on the terminal: `Enter file name: ` for a missing file, `? ` for
+    /// an error, `*` or `**` for more input, or `\macro=` for a `\read`.
+    static ref REG_PROMPT: Regex =
+        Regex::new(r"^(?:Enter file name: |\? |\*\*?|\\[A-Za-z@]+=)$").unwrap();
+}
+
+/// How many points an absolute unit of SVG has. User units without a unit
+/// are pixels.
+const SVG_UNITS: [(&str, f64); 7] = [
+    ("pt", 1.0),
+    ("px", 0.75),
+    ("", 0.75),
+    ("pc", 12.0),
+    ("in", 72.0),
+    ("cm", 72.0 / 2.54),
+    ("mm", 7.2 / 2.54),
+];
+
+/// The width at which TeX wraps the lines of its log.
+const LOG_LINE_WIDTH: usize = 79;
+
+/// The compilation of a single TikZ figure into an SVG.
+///
+/// A job owns all of its data, so that it can run on another thread or task.
+#[derive(Debug, Clone)]
+pub(crate) struct Job {
+    /// The hash of the figure.
+    pub hash: u64,
+    /// The directory in which all files of the job are created.
+    pub dir: PathBuf,
+    /// The complete LaTeX document that contains the figure.
+    pub document: String,
+    /// The files that the figure references.
+    pub inputs: Vec<InputFile>,
+    /// Extra directories in which LaTeX looks for files.
+    pub search_paths: Vec<PathBuf>,
+    /// The LaTeX engines to try, in order.
+    pub engines: Vec<Tool>,
+    /// The engine that the figure asks for, which a renderer should use.
+    pub engine: Option<String>,
+    /// The program that converts the PDF into SVGs.
+    pub converter: PathBuf,
+    /// The Lua file that configures LuaTeX-based engines.
+    pub lua_config: PathBuf,
+    /// Whether to outline all glyphs in the generated SVG.
+    pub outline_text: bool,
+    /// Whether the figure plots with gnuplot, so that the scripts that
+    /// pgfplots writes have to be run between two LaTeX runs.
+    pub gnuplot: bool,
+    /// How long each program may run before it is killed.
+    pub timeout: Option<Duration>,
+    /// The resources that each program may use.
+    pub limits: ResourceLimits,
+    /// When pages are embedded as raster images instead of SVGs.
+    pub raster: RasterFallback,
+    /// The background and margin that are added to the SVGs.
+    pub frame: Frame,
+    /// Whether the PDFs and SVGs must not depend on when and where they were
+    /// generated.
+    pub reproducible: bool,
+    /// Whether LaTeX produces a DVI file instead of a PDF.
+    pub dvi: bool,
+    /// Whether every page of the PDF is also rendered into a PNG in the
+    /// directory of the job, for exporting the figure.
+    pub pngs: bool,
+    /// Whether the PDF is exported, so that a copy with the frame of the
+    /// figure is needed, if it has one.
+    pub pdf: bool,
+    /// The environment of the figure if a placeholder is generated in place
+    /// of compiling it, since no TeX toolchain is installed.
+    pub placeholder: Option<String>,
+    /// Compiles the figure in place of the local programs.
+    pub renderer: Option<Arc<dyn Renderer>>,
+}
+
+/// Caps on the resources that LaTeX, the converter and gnuplot may use for a
+/// figure, so that a pathological figure cannot exhaust a shared machine.
+///
+/// They are enforced with `setrlimit` and thus only on Unix. A program that
+/// exceeds them is killed by the operating system.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The maximum size of the address space of a program, in bytes.
+    pub memory: Option<u64>,
+    /// The maximum processor time of a program, rounded up to whole seconds.
+    pub cpu_time: Option<Duration>,
+    /// The maximum size of each file that a program writes, in bytes.
+    pub output_size: Option<u64>,
+}
+
+/// When the SVG of a page is replaced by a raster image, because it is so
+/// large or has so many elements that Typst and PDF viewers slow down, e.g.
+/// for plots with thousands of marks.
+///
+/// The raster image is rendered from the PDF by pdftoppm and wrapped into an
+/// SVG of the same size. Figures that are compiled through DVI files or by a
+/// renderer have no PDF and always stay vector graphics. The fallback is
+/// disabled unless one of the thresholds is set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RasterFallback {
+    /// The size in bytes above which an SVG is rasterized.
+    pub max_size: Option<u64>,
+    /// The number of elements above which an SVG is rasterized.
+    pub max_elements: Option<usize>,
+    /// The resolution of the raster images, in dots per inch.
+    pub dpi: u32,
+}
+
+impl RasterFallback {
+    /// Whether any of the thresholds is set.
+    pub fn enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_elements.is_some()
+    }
+
+    /// Whether an SVG exceeds one of the thresholds.
+    fn exceeds(&self, svg: &[u8]) -> bool {
+        self.max_size.map_or(false, |max| svg.len() as u64 > max)
+            || self.max_elements.map_or(false, |max| elements(svg) > max)
+    }
+}
+
+impl Default for RasterFallback {
+    fn default() -> Self {
+        Self { max_size: None, max_elements: None, dpi: 300 }
+    }
+}
+
+/// A background and a margin that are added to the SVGs of a figure after it
+/// was compiled, as with `tikzcd(fill: white, pad: 4pt)[...]`, so that
+/// figures need neither a background layer nor a `border` of their own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Frame {
+    /// The color of the background, like `white` or `#fafafa`.
+    pub fill: Option<String>,
+    /// The margin around the figure, in points.
+    pub pad: f64,
+}
+
+impl Frame {
+    /// Whether the SVGs are left as they are.
+    pub fn is_empty(&self) -> bool {
+        self.fill.is_none() && self.pad == 0.0
+    }
+}
+
+/// A file that a figure references, e.g. via `\input`.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct InputFile {
+    /// The path exactly as the figure references it.
+    pub path: PathBuf,
+    /// The content of the file, or `None` if it could not be read.
+    pub content: Option<Vec<u8>>,
+}
+
+/// The images of a compiled figure.
+#[derive(Debug, Clone)]
+pub(crate) struct Compiled {
+    /// The SVGs, one per page.
+    pub pages: Vec<Vec<u8>>,
+    /// The LaTeX engine that compiled the figure, or `None` if the figure was
+    /// taken from the shared cache.
+    pub engine: Option<String>,
+    /// The warnings in the LaTeX log, like overfull boxes.
+    pub warnings: Vec<String>,
+    /// How long the stages of the compilation took.
+    pub timings: StageTimings,
+}
+
+/// How long the stages of compiling a figure took.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StageTimings {
+    /// Running LaTeX, including gnuplot between two runs, or the renderer.
+    #[serde(serialize_with = "super::serialize_seconds")]
+    pub latex: Duration,
+    /// Converting the PDF or DVI file into SVGs, and rasterizing them.
+    #[serde(serialize_with = "super::serialize_seconds")]
+    pub conversion: Duration,
+    /// Writing the document and its inputs and reading the SVGs, or loading
+    /// the figure from the shared cache.
+    #[serde(serialize_with = "super::serialize_seconds")]
+    pub io: Duration,
+}
+
+impl StageTimings {
+    /// The time of all stages together.
+    pub fn total(&self) -> Duration {
+        self.latex + self.conversion + self.io
+    }
+}
+
+/// Why a command failed.
+enum Failure {
+    /// The program could not be started, e.g. because it is not installed.
+    Missing(String),
+    /// The program ran for too long and was killed.
+    TimedOut(String),
+    /// The program stopped at a prompt for input and was killed.
+    Waiting(String),
+    /// The program reported an error, with its output.
+    Error(String),
+    /// The program crashed or was killed, with its output.
+    Crashed(String),
+}
+
+impl Failure {
+    /// The message or output of the failure.
+    fn into_message(self) -> String {
+        match self {
+            Self::Missing(message)
+            | Self::TimedOut(message)
+            | Self::Waiting(message)
+            | Self::Error(message)
+            | Self::Crashed(message) => message,
+        }
+    }
+}
+
+impl Job {
+    /// Run the job and return the generated SVGs.
+    #[cfg_attr(
+        feature = "tikz-tracing",
+        tracing::instrument(skip_all, fields(hash = self.hash))
+    )]
+    pub fn run(&self) -> Result<Compiled, String> {
+        if let Some(renderer) = &self.renderer {
+            return self.delegate(renderer.as_ref());
+        } else if let Some(environment) = &self.placeholder {
+            return Ok(placeholder(environment, self.hash));
+        }
+
+        let start = Instant::now();
+        self.prepare()?;
+        let mut timings = StageTimings { io: start.elapsed(), ..Default::default() };
+
+        let start = Instant::now();
+        let mut engine = self.typeset();
+        if self.gnuplot {
+            let plots = self.plots()?;
+            if !plots.is_empty() {
+                for mut plot in plots {
+                    execute(&mut plot, self.timeout)?;
+                }
+                engine = self.typeset();
+            }
+        }
+
+        let engine = engine?;
+        timings.latex = start.elapsed();
+        trace_event!(info, "Typeset TikZ figure {} with {}", self.hash, engine);
+
+        let start = Instant::now();
+        self.convert()?;
+        timings.conversion = start.elapsed();
+
+        let start = Instant::now();
+        let pages = self.output()?;
+        timings.io += start.elapsed();
+
+        let start = Instant::now();
+        let pages = self.frame(self.rasterize(pages));
+        if self.frames_pdf() {
+            let framed = self.framer(&engine, pages.len()).and_then(|mut framer| {
+                attempt(&mut framer, self.timeout, true).map_err(Failure::into_message)
+            });
+            if let Err(_err) = framed {
+                trace_event!(
+                    warn,
+                    "Failed to frame the PDF of TikZ figure {}: {}",
+                    self.hash,
+                    _err
+                );
+            }
+        }
+        for page in self.unrendered(pages.len()) {
+            let stem = self.exported_stem();
+            if let Err(_err) = execute(&mut self.rasterizer(stem, page), self.timeout) {
+                trace_event!(
+                    warn,
+                    "Failed to render page {} of TikZ figure {} into a PNG: {}",
+                    page,
+                    self.hash,
+                    _err
+                );
+            }
+        }
+        timings.conversion += start.elapsed();
+
+        Ok(Compiled {
+            pages,
+            engine: Some(engine),
+            warnings: self.warnings(),
+            timings,
+        })
+    }
+
+    /// Run the job without blocking the executor and return the generated
+    /// SVGs.
+    #[cfg(feature = "async")]
+    pub async fn run_async(&self) -> Result<Compiled, String> {
+        if let Some(renderer) = &self.renderer {
+            return self.delegate(renderer.as_ref());
+        } else if let Some(environment) = &self.placeholder {
+            return Ok(placeholder(environment, self.hash));
+        }
+
+        let start = Instant::now();
+        self.prepare()?;
+        let mut timings = StageTimings { io: start.elapsed(), ..Default::default() };
+
+        let start = Instant::now();
+        let mut engine = self.typeset_async().await;
+        if self.gnuplot {
+            let plots = self.plots()?;
+            if !plots.is_empty() {
+                for plot in plots {
+                    execute_async(plot, self.timeout).await?;
+                }
+                engine = self.typeset_async().await;
+            }
+        }
+
+        let engine = engine?;
+        timings.latex = start.elapsed();
+        trace_event!(info, "Typeset TikZ figure {} with {}", self.hash, engine);
+
+        let start = Instant::now();
+        execute_async(self.converter(), self.timeout).await?;
+        timings.conversion = start.elapsed();
+
+        let start = Instant::now();
+        let mut pages = self.output()?;
+        timings.io += start.elapsed();
+
+        let start = Instant::now();
+        for index in self.oversized(&pages) {
+            let rastered =
+                execute_async(self.rasterizer("tikz", index + 1), self.timeout)
+                    .await
+                    .and_then(|()| self.raster(&pages[index], index + 1));
+            match rastered {
+                Ok(svg) => pages[index] = svg,
+                Err(_err) => {
+                    trace_event!(
+                        warn,
+                        "Failed to rasterize page {} of TikZ figure {}: {}",
+                        index + 1,
+                        self.hash,
+                        _err
+                    );
+                }
+            }
+        }
+        let pages = self.frame(pages);
+        if self.frames_pdf() {
+            let framed = match self.framer(&engine, pages.len()) {
+                Ok(framer) => attempt_async(framer, self.timeout, true)
+                    .await
+                    .map_err(Failure::into_message),
+                Err(err) => Err(err),
+            };
+            if let Err(_err) = framed {
+                trace_event!(
+                    warn,
+                    "Failed to frame the PDF of TikZ figure {}: {}",
+                    self.hash,
+                    _err
+                );
+            }
+        }
+        for page in self.unrendered(pages.len()) {
+            let rasterizer = self.rasterizer(self.exported_stem(), page);
+            if let Err(_err) = execute_async(rasterizer, self.timeout).await {
+                trace_event!(
+                    warn,
+                    "Failed to render page {} of TikZ figure {} into a PNG: {}",
+                    page,
+                    self.hash,
+                    _err
+                );
+            }
+        }
+        timings.conversion += start.elapsed();
+
+        Ok(Compiled {
+            pages,
+            engine: Some(engine),
+            warnings: self.warnings(),
+            timings,
+        })
+    }
+
+    /// Compile the LaTeX document into a PDF without blocking the executor,
+    /// like `typeset`.
+    #[cfg(feature = "async")]
+    async fn typeset_async(&self) -> Result<String, String> {
+        let mut failure = Failure::Missing("no LaTeX engine configured".into());
+        for candidate in &self.engines {
+            for _ in 0..ATTEMPTS {
+                let latex = self.latex(candidate, "tikz.tex");
+                let attempted = attempt_async(latex, self.timeout, true).await;
+                match attempted.map_err(|failure| self.limited(failure)) {
+                    Ok(()) => return Ok(candidate.program.clone()),
+                    Err(
+                        Failure::Error(logs)
+                        | Failure::TimedOut(logs)
+                        | Failure::Waiting(logs),
+                    ) => return Err(logs),
+                    Err(Failure::Missing(message)) => {
+                        failure = Failure::Missing(message);
+                        break;
+                    }
+                    Err(crashed) => failure = crashed,
+                }
+            }
+        }
+
+        Err(failure.into_message())
+    }
+
+    /// Treat a crash as an error in the document if LaTeX ran with a memory
+    /// limit. Programs that exceed it crash in all sorts of ways, and would
+    /// only exceed it again when they are run once more.
+    fn limited(&self, failure: Failure) -> Failure {
+        match (failure, self.limits.memory) {
+            (Failure::Crashed(logs), Some(memory)) if cfg!(unix) => {
+                let megabytes = memory / 1_000_000;
+                Failure::Error(format!(
+                    "LaTeX crashed, most likely because it exceeded the memory \
+                     limit of {megabytes} MB\n{logs}"
+                ))
+            }
+            (failure, _) => failure,
+        }
+    }
+
+    /// Let a renderer compile the figure.
+    fn delegate(&self, renderer: &dyn Renderer) -> Result<Compiled, String> {
+        let request = RenderRequest {
+            hash: self.hash,
+            document: &self.document,
+            inputs: self
+                .inputs
+                .iter()
+                .filter_map(|input| {
+                    Some((input.path.as_path(), input.content.as_deref()?))
+                })
+                .collect(),
+            outline_text: self.outline_text,
+            engine: self.engine.as_deref(),
+            dvi: self.dvi,
+            dvisvgm: is_dvisvgm(&self.converter),
+            raster: &self.raster,
+        };
+
+        let start = Stopwatch::start();
+        let mut pages = renderer.render(&request)?;
+        let timings = StageTimings { latex: start.elapsed(), ..Default::default() };
+        if pages.is_empty() {
+            return Err("renderer returned no SVG".into());
+        }
+
+        if self.reproducible {
+            pages = pages.iter().map(|svg| reproducible(svg)).collect();
+        }
+
+        Ok(Compiled {
+            pages: self.frame(pages),
+            engine: Some(renderer.name()),
+            warnings: vec![],
+            timings,
+        })
+    }
+
+    /// Compile the LaTeX document into a PDF with the first engine that
+    /// works, and return the name of that engine.
+    ///
+    /// Engines that are missing are skipped, and engines that crash are tried
+    /// once more before moving on. Errors in the document and timeouts are
+    /// returned right away, since another engine would most likely run into
+    /// them as well.
+    #[cfg_attr(feature = "tikz-tracing", tracing::instrument(skip_all))]
+    fn typeset(&self) -> Result<String, String> {
+        let mut failure = Failure::Missing("no LaTeX engine configured".into());
+        for engine in &self.engines {
+            for _ in 0..ATTEMPTS {
+                let attempted =
+                    attempt(&mut self.latex(engine, "tikz.tex"), self.timeout, true);
+                match attempted.map_err(|failure| self.limited(failure)) {
+                    Ok(()) => return Ok(engine.program.clone()),
+                    Err(
+                        Failure::Error(logs)
+                        | Failure::TimedOut(logs)
+                        | Failure::Waiting(logs),
+                    ) => return Err(logs),
+                    Err(Failure::Missing(message)) => {
+                        trace_event!(warn, "{}", message);
+                        failure = Failure::Missing(message);
+                        break;
+                    }
+                    Err(crashed) => {
+                        trace_event!(warn, "LaTeX engine {} crashed", engine.program);
+                        failure = crashed;
+                    }
+                }
+            }
+        }
+
+        Err(failure.into_message())
+    }
+
+    /// Convert the PDF produced by LaTeX into an SVG.
+    #[cfg_attr(feature = "tikz-tracing", tracing::instrument(skip_all))]
+    fn convert(&self) -> Result<(), String> {
+        execute(&mut self.converter(), self.timeout)
+    }
+
+    /// The commands that run the gnuplot scripts that pgfplots wrote into the
+    /// job directory, which it cannot run itself without shell escape.
+    ///
+    /// Scripts that could run other programs or write other files are
+    /// refused, since only pgfplots' own `set table` output is needed.
+    fn plots(&self) -> Result<Vec<Command>, String> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|err| format!("failed to read job directory: {}", err))?;
+
+        let mut scripts: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == GNUPLOT))
+            .collect();
+        scripts.sort();
+
+        let mut plots = vec![];
+        for script in scripts {
+            let code = fs::read_to_string(&script)
+                .map_err(|err| format!("failed to read gnuplot script: {}", err))?;
+            let name = script.file_name().unwrap_or_default().to_string_lossy();
+            if REG_GNUPLOT_UNSAFE.is_match(&code) {
+                return Err(format!("refused to run the gnuplot script {}", name));
+            }
+
+            let mut cmd = self.command(&locate(GNUPLOT));
+            cmd.arg(&*name);
+            plots.push(cmd);
+        }
+
+        Ok(plots)
+    }
+
+    /// Create the job directory and write the LaTeX document and the files
+    /// that it references into it.
+    fn prepare(&self) -> Result<(), String> {
+        let dir = long_path(&self.dir);
+        fs::create_dir_all(&dir)
+            .map_err(|err| format!("failed to create job directory: {}", err))?;
+
+        for input in &self.inputs {
+            // Files that cannot be read are reported by LaTeX. Absolute paths
+            // and paths that leave the directory are not copied.
+            let Some(content) = &input.content else { continue };
+            if !input.path.components().all(|component| {
+                matches!(component, Component::Normal(_) | Component::CurDir)
+            }) {
+                continue;
+            }
+
+            // Rebuilding the path from its components also turns forward
+            // slashes into native separators, which long paths require.
+            let path = dir.join(input.path.components().collect::<PathBuf>());
+            path.parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, content))
+                .map_err(|err| {
+                    format!("failed to copy {}: {}", input.path.display(), err)
+                })?;
+        }
+
+        fs::write(dir.join("tikz.tex"), &self.document)
+            .map_err(|err| format!("failed to create LaTeX buffer: {}", err))
+    }
+
+    /// Read the generated SVGs, which are numbered from one.
+    fn output(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut pages = vec![];
+        loop {
+            let path = self.dir.join(format!("tikz-{}.svg", pages.len() + 1));
+            match fs::read(path) {
+                Ok(svg) if self.reproducible => pages.push(reproducible(&svg)),
+                Ok(svg) => pages.push(svg),
+                Err(err) if pages.is_empty() => {
+                    return Err(format!("failed to read generated SVG: {}", err))
+                }
+                Err(_) => return Ok(pages),
+            }
+        }
+    }
+
+    /// Add the background and the margin to all pages. Pages that cannot be
+    /// framed are left as they are.
+    fn frame(&self, pages: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        if self.frame.is_empty() {
+            return pages;
+        }
+
+        pages
+            .into_iter()
+            .enumerate()
+            .map(|(index, svg)| match framed(&svg, &self.frame) {
+                Ok(framed) => framed,
+                Err(_err) => {
+                    trace_event!(
+                        warn,
+                        "Failed to frame page {} of TikZ figure {}: {}",
+                        index + 1,
+                        self.hash,
+                        _err
+                    );
+                    svg
+                }
+            })
+            .collect()
+    }
+
+    /// Replace the SVGs that exceed the thresholds of the raster fallback by
+    /// raster images. Pages that cannot be rasterized keep their SVGs.
+    fn rasterize(&self, mut pages: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        for index in self.oversized(&pages) {
+            let rastered = execute(&mut self.rasterizer("tikz", index + 1), self.timeout)
+                .and_then(|()| self.raster(&pages[index], index + 1));
+            match rastered {
+                Ok(svg) => pages[index] = svg,
+                Err(_err) => {
+                    trace_event!(
+                        warn,
+                        "Failed to rasterize page {} of TikZ figure {}: {}",
+                        index + 1,
+                        self.hash,
+                        _err
+                    );
+                }
+            }
+        }
+
+        pages
+    }
+
+    /// The numbers of the pages whose PNGs are exported but were not rendered
+    /// by the raster fallback, counted from one.
+    fn unrendered(&self, count: usize) -> Vec<usize> {
+        if !self.pngs || self.dvi {
+            return vec![];
+        }
+
+        let stem = self.exported_stem();
+        (1..=count)
+            .filter(|page| !self.dir.join(format!("{}-{}.png", stem, page)).exists())
+            .collect()
+    }
+
+    /// The name of the exported PDF in the directory of the job, without its
+    /// extension. The PNGs are named after it, followed by their page. Framed
+    /// figures export a copy of the PDF with the frame.
+    pub fn exported_stem(&self) -> &'static str {
+        if self.frame.is_empty() {
+            "tikz"
+        } else {
+            "framed"
+        }
+    }
+
+    /// Whether the PDF is exported with a frame, as a PDF or as PNGs.
+    fn frames_pdf(&self) -> bool {
+        !self.frame.is_empty() && (self.pdf || self.pngs) && !self.dvi
+    }
+
+    /// Write a LaTeX document that adds the background and the margin of the
+    /// frame to every page of the PDF, and return the command that compiles
+    /// it with the engine that typeset the figure.
+    fn framer(&self, engine: &str, pages: usize) -> Result<Command, String> {
+        let tool = self
+            .engines
+            .iter()
+            .find(|tool| tool.program == engine)
+            .ok_or_else(|| format!("the LaTeX engine {} is unknown", engine))?;
+
+        let mut document = format!(
+            concat!(
+                "\\documentclass[multi=true,border={}bp]{{standalone}}\n",
+                "\\usepackage{{graphicx}}\n",
+                "\\usepackage{{xcolor}}\n",
+                "\\newenvironment{{framedpage}}{{}}{{}}\n",
+                "\\standaloneenv{{framedpage}}\n",
+            ),
+            self.frame.pad
+        );
+        if let Some(fill) = &self.frame.fill {
+            // Colors are given as in SVG, i.e. hexadecimal with a hash, which
+            // xcolor calls HTML and only knows in its long form, or by a name
+            // that xcolor knows as well.
+            match fill.strip_prefix('#') {
+                Some(hex) => {
+                    let hex: String = match hex.len() {
+                        3 => hex.chars().flat_map(|c| [c, c]).collect(),
+                        _ => hex.into(),
+                    };
+                    document += &format!(
+                        "\\definecolor{{framefill}}{{HTML}}{{{}}}\n",
+                        hex.to_ascii_uppercase()
+                    );
+                }
+                None => document += &format!("\\colorlet{{framefill}}{{{}}}\n", fill),
+            }
+            document += "\\pagecolor{framefill}\n";
+        }
+
+        document += "\\begin{document}\n";
+        for page in 1..=pages {
+            document += &format!(
+                "\\begin{{framedpage}}\\includegraphics[page={}]{{tikz.pdf}}\\end{{framedpage}}\n",
+                page
+            );
+        }
+        document += "\\end{document}\n";
+
+        fs::write(self.dir.join("framed.tex"), document)
+            .map_err(|err| format!("failed to write framed document: {}", err))?;
+        Ok(self.latex(tool, "framed.tex"))
+    }
+
+    /// The indices of the pages that exceed the thresholds of the raster
+    /// fallback, if there is a PDF to rasterize them from.
+    fn oversized(&self, pages: &[Vec<u8>]) -> Vec<usize> {
+        if !self.raster.enabled() || self.dvi {
+            return vec![];
+        }
+
+        let oversized: Vec<_> = (0..pages.len())
+            .filter(|&index| self.raster.exceeds(&pages[index]))
+            .collect();
+        if !oversized.is_empty() {
+            trace_event!(
+                info,
+                "Rasterizing {} pages of TikZ figure {} at {} dpi",
+                oversized.len(),
+                self.hash,
+                self.raster.dpi
+            );
+        }
+        oversized
+    }
+
+    /// The command that renders a page of the PDF with the given name, without
+    /// its extension, into a PNG.
+    fn rasterizer(&self, stem: &str, page: usize) -> Command {
+        let mut cmd = self.command(&locate(RASTERIZER));
+        cmd.arg("-png")
+            .arg("-r")
+            .arg(self.raster.dpi.to_string())
+            .arg("-f")
+            .arg(page.to_string())
+            .arg("-l")
+            .arg(page.to_string())
+            .arg("-singlefile")
+            .arg(format!("{}.pdf", stem))
+            .arg(format!("{}-{}", stem, page));
+        cmd
+    }
+
+    /// Wrap the PNG of a page into an SVG with the size and view box of the
+    /// page's original SVG, so that it is embedded like any other page.
+    fn raster(&self, svg: &[u8], page: usize) -> Result<Vec<u8>, String> {
+        let png = fs::read(self.dir.join(format!("tikz-{}.png", page)))
+            .map_err(|err| format!("failed to read generated PNG: {}", err))?;
+
+        let text = String::from_utf8_lossy(svg);
+        let root = REG_SVG_ROOT
+            .find(&text)
+            .ok_or("the generated SVG has no root element")?
+            .as_str();
+        let attribute = |name: &str| {
+            REG_SVG_SIZE
+                .captures_iter(root)
+                .find(|capture| &capture["name"] == name)
+                .map(|capture| capture["value"].to_string())
+        };
+
+        let view_box = attribute("viewBox").ok_or("the generated SVG has no view box")?;
+        let [x, y, width, height] = match view_box
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()[..]
+        {
+            [x, y, width, height] => [x, y, width, height],
+            _ => return Err(format!("the view box {} is invalid", view_box)),
+        };
+
+        let size: String = ["width", "height"]
+            .into_iter()
+            .filter_map(|name| Some(format!(r#" {}="{}""#, name, attribute(name)?)))
+            .collect();
+        let data = base64::engine::general_purpose::STANDARD.encode(png);
+        Ok(format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"{} viewBox="{}">"#,
+                r#"<image x="{}" y="{}" width="{}" height="{}" preserveAspectRatio="none" xlink:href="data:image/png;base64,{}"/>"#,
+                "</svg>\n"
+            ),
+            size, view_box, x, y, width, height, data
+        )
+        .into_bytes())
+    }
+
+    /// The warnings in the log of the last LaTeX run.
+    fn warnings(&self) -> Vec<String> {
+        let warnings = match fs::read(self.dir.join("tikz.log")) {
+            Ok(log) => warnings(&String::from_utf8_lossy(&log)),
+            Err(_) => vec![],
+        };
+
+        if !warnings.is_empty() {
+            trace_event!(
+                info,
+                "TikZ figure {} has {} LaTeX warnings",
+                self.hash,
+                warnings.len()
+            );
+        }
+        warnings
+    }
+
+    /// A command that runs a program in the job directory, within the
+    /// resource limits.
+    fn command(&self, program: &Path) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(&self.dir);
+        limit(&mut cmd, &self.limits);
+
+        // TeX Live takes the creation date of PDFs, and the IDs derived from
+        // it, from the environment.
+        if self.reproducible {
+            let epoch = env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| "0".into());
+            cmd.env("SOURCE_DATE_EPOCH", epoch).env("FORCE_SOURCE_DATE", "1");
+        }
+
+        cmd
+    }
+
+    /// The command that compiles the LaTeX document with the given name into
+    /// a PDF with the given engine.
+    fn latex(&self, engine: &Tool, document: &str) -> Command {
+        // Relative paths in the figure are resolved against the job directory.
+        // The document is referred to relatively as well, since TeX cannot
+        // handle spaces in paths, which are common in temporary directories on
+        // Windows.
+        let mut cmd = self.command(&engine.path);
+
+        // Other engines cannot be configured with Lua, but can be told to
+        // stop at the first error as well.
+        if is_luatex(&engine.program) {
+            cmd.arg("-lua").arg(&self.lua_config);
+        } else {
+            cmd.arg("-interaction=nonstopmode").arg("-halt-on-error");
+        }
+
+        // XeTeX calls its DVI files XDV, and needs no option to produce them
+        // other than not producing a PDF.
+        if self.dvi {
+            if is_xetex(&engine.program) {
+                cmd.arg("-no-pdf");
+            } else {
+                cmd.arg("-output-format=dvi");
+            }
+        }
+
+        // MiKTeX spells the option to disable shell commands differently.
+        if engine.miktex {
+            cmd.arg("-disable-write18");
+        } else {
+            cmd.arg("-no-shell-escape");
+        }
+
+        cmd.arg(document);
+
+        if !self.search_paths.is_empty() {
+            // An empty entry stands for the default directories, unless the
+            // user configured them differently.
+            let mut paths = self.search_paths.clone();
+            match env::var_os("TEXINPUTS") {
+                Some(existing) => paths.extend(env::split_paths(&existing)),
+                None => paths.push(PathBuf::new()),
+            }
+
+            if let Ok(joined) = env::join_paths(paths) {
+                cmd.env("TEXINPUTS", joined);
+            }
+        }
+
+        cmd
+    }
+
+    /// The command that converts each page of the PDF into an SVG.
+    fn converter(&self) -> Command {
+        let mut cmd = self.command(&self.converter);
+
+        // Only dvisvgm can replace the embedded fonts with glyph outlines.
+        if is_dvisvgm(&self.converter) {
+            let input = match self.dvi {
+                true if self.dir.join("tikz.xdv").exists() => "tikz.xdv",
+                true => "tikz.dvi",
+                false => {
+                    cmd.arg("--pdf");
+                    "tikz.pdf"
+                }
+            };
+
+            cmd.arg("--page=1-");
+            if self.outline_text {
+                cmd.arg("--no-fonts");
+            }
+            cmd.arg("--output=tikz-%p.svg").arg(input);
+        } else {
+            cmd.arg("tikz.pdf").arg("tikz-%d.svg").arg("all");
+        }
+
+        cmd
+    }
+}
+
+/// Apply resource limits to a command, which takes effect in the child
+/// process just before the program starts.
+#[cfg(unix)]
+fn limit(cmd: &mut Command, limits: &ResourceLimits) {
+    use std::os::unix::process::CommandExt;
+
+    if *limits == ResourceLimits::default() {
+        return;
+    }
+
+    let cpu_time = limits.cpu_time.map(|time| time.as_secs_f64().ceil().max(1.0) as u64);
+    let resources = [
+        (libc::RLIMIT_AS, limits.memory),
+        (libc::RLIMIT_CPU, cpu_time),
+        (libc::RLIMIT_FSIZE, limits.output_size),
+    ];
+
+    // SAFETY: The closure only calls `setrlimit`, which is async-signal-safe,
+    // and allocates nothing.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, value) in resources {
+                let Some(value) = value else { continue };
+                let value = value as libc::rlim_t;
+                let rlimit = libc::rlimit { rlim_cur: value, rlim_max: value };
+                if libc::setrlimit(resource, &rlimit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Resource limits are not supported on this platform.
+#[cfg(not(unix))]
+fn limit(_: &mut Command, _: &ResourceLimits) {}
+
+/// A gray box with the environment and the hash of a figure, which stands in
+/// for its image.
+fn placeholder(environment: &str, hash: u64) -> Compiled {
+    let svg = format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="120pt" height="60pt" viewBox="0 0 160 80">"##,
+            r##"<rect x="0.5" y="0.5" width="159" height="79" fill="#eeeeee" stroke="#999999"/>"##,
+            r##"<text x="80" y="36" text-anchor="middle" font-family="sans-serif" font-size="14" fill="#555555">{}</text>"##,
+            r##"<text x="80" y="56" text-anchor="middle" font-family="monospace" font-size="9" fill="#777777">{}</text>"##,
+            "</svg>\n"
+        ),
+        environment, hash
+    );
+
+    Compiled {
+        pages: vec![svg.into_bytes()],
+        engine: Some("placeholder".into()),
+        warnings: vec![],
+        timings: StageTimings::default(),
+    }
+}
+
+/// Find the warnings in a LaTeX log, each on a single line and without
+/// duplicates.
+///
+/// Lines that TeX wrapped are joined, as are the continuation lines of
+/// package warnings, which start with the name of the package in
+/// parentheses.
+fn warnings(log: &str) -> Vec<String> {
+    let mut warnings: Vec<String> = vec![];
+    let mut lines = log.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some(capture) = REG_LATEX_WARNING.captures(line) else { continue };
+        let continuation = capture.name("package").map(|m| format!("({})", m.as_str()));
+
+        let mut warning = line.trim_end().to_string();
+        let mut previous = line;
+        while let Some(&next) = lines.peek() {
+            let wrapped = previous.chars().count() == LOG_LINE_WIDTH;
+            match &continuation {
+                Some(prefix) if next.starts_with(prefix.as_str()) => {
+                    warning.push(' ');
+                    warning.push_str(next[prefix.len()..].trim());
+                }
+                _ if wrapped && !next.is_empty() => warning.push_str(next.trim_end()),
+                _ => break,
+            }
+            previous = next;
+            lines.next();
+        }
+
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    warnings
+}
+
+/// Add the margin and the background of a frame to an SVG.
+///
+/// The view box grows by the margin on all sides, and the width and height
+/// grow by the same factor, so that the figure keeps its scale. The
+/// background covers the whole view box, behind everything else.
+fn framed(svg: &[u8], frame: &Frame) -> Result<Vec<u8>, String> {
+    let text =
+        std::str::from_utf8(svg).map_err(|_| "the generated SVG is not valid UTF-8")?;
+    let root = REG_SVG_ROOT
+        .find(text)
+        .ok_or("the generated SVG has no root element")?;
+    let attribute = |name: &str| {
+        REG_SVG_SIZE
+            .captures_iter(root.as_str())
+            .find(|capture| &capture["name"] == name)
+            .map(|capture| capture["value"].to_string())
+    };
+
+    let view_box = attribute("viewBox").ok_or("the generated SVG has no view box")?;
+    let [x, y, width, height] = match view_box
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(str::parse::<f64>)
+        .collect::<Result<Vec<_>, _>>()
+        .as_deref()
+    {
+        Ok(&[x, y, width, height]) if width > 0.0 && height > 0.0 => {
+            [x, y, width, height]
+        }
+        _ => return Err(format!("the view box {} is invalid", view_box)),
+    };
+
+    // The margin is converted from points into user units by the ratio of
+    // the view box to the width.
+    let points = match attribute("width") {
+        Some(length) => points(&length)
+            .ok_or_else(|| format!("cannot pad an SVG that is {} wide", length))?,
+        None => width * 0.75,
+    };
+    let margin = frame.pad * width / points;
+    let (x, y) = (x - margin, y - margin);
+    let grown = [width + 2.0 * margin, height + 2.0 * margin];
+
+    let tag = REG_SVG_SIZE.replace_all(root.as_str(), |capture: &regex::Captures| {
+        let name = &capture["name"];
+        let value = match name {
+            "viewBox" => format!("{} {} {} {}", x, y, grown[0], grown[1]),
+            _ => {
+                let (index, original) =
+                    if name == "width" { (0, width) } else { (1, height) };
+                let (number, unit) = split_length(&capture["value"]);
+                match number.parse::<f64>() {
+                    Ok(number) => format!("{}{}", number * grown[index] / original, unit),
+                    Err(_) => capture["value"].to_string(),
+                }
+            }
+        };
+        format!(r#" {}="{}""#, name, value)
+    });
+
+    let background = match &frame.fill {
+        Some(fill) => format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#,
+            x, y, grown[0], grown[1], fill
+        ),
+        None => String::new(),
+    };
+
+    Ok([&text[..root.start()], &tag, &background, &text[root.end()..]]
+        .concat()
+        .into_bytes())
+}
+
+/// Convert an absolute SVG length, like `12.5pt` or `3mm`, into points.
+pub(crate) fn points(length: &str) -> Option<f64> {
+    let (number, unit) = split_length(length.trim());
+    let (_, factor) = SVG_UNITS.iter().find(|(name, _)| *name == unit)?;
+    Some(number.parse::<f64>().ok()? * factor)
+}
+
+/// Split a length into its number and its unit.
+fn split_length(length: &str) -> (&str, &str) {
+    let unit = length
+        .trim_start_matches(|c: char| c.is_ascii_digit() || matches!(c, '.' | '-' | '+'));
+    length.split_at(length.len() - unit.len())
+}
+
+/// The number of elements in an SVG, which measures how hard it is to render.
+fn elements(svg: &[u8]) -> usize {
+    svg.windows(2)
+        .filter(|pair| pair[0] == b'<' && pair[1].is_ascii_alphabetic())
+        .count()
+}
+
+/// Remove everything from an SVG that depends on the machine that generated
+/// it rather than on the figure: Comments and Windows line endings.
+fn reproducible(svg: &[u8]) -> Vec<u8> {
+    let Ok(text) = std::str::from_utf8(svg) else { return svg.to_vec() };
+    REG_SVG_COMMENT
+        .replace_all(text, "")
+        .replace("\r\n", "\n")
+        .into_bytes()
+}
+
+/// How often an engine is run before the next one is tried, if it crashes.
+const ATTEMPTS: usize = 2;
+
+/// Whether an engine is based on LuaTeX and can thus be configured with Lua.
+fn is_luatex(engine: &str) -> bool {
+    Path::new(engine)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map_or(false, |stem| stem.starts_with("lua"))
+}
+
+/// Whether an engine is based on XeTeX.
+fn is_xetex(engine: &str) -> bool {
+    Path::new(engine)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map_or(false, |stem| stem.starts_with("xe"))
+}
+
+/// Whether a converter is dvisvgm, whose options differ from those of
+/// pdf2svg.
+pub(crate) fn is_dvisvgm(converter: &Path) -> bool {
+    converter
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map_or(false, |stem| stem == OUTLINE_CONVERTER)
+}
+
+/// The converter that is used unless another one is configured: dvisvgm if
+/// glyphs are outlined, and pdf2svg otherwise.
+pub(crate) fn default_converter(outline_text: bool) -> &'static str {
+    if outline_text {
+        OUTLINE_CONVERTER
+    } else {
+        PDF_CONVERTER
+    }
+}
+
+/// A program that jobs run.
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub(crate) struct Tool {
+    /// The name of the program, as it is configured.
+    pub program: String,
+    /// Where the program was found, or its name if it was not found in any
+    /// of the usual places.
+    pub path: PathBuf,
+    /// Whether the program is a LaTeX engine, of which only one is needed.
+    pub engine: bool,
+    /// Whether the program belongs to MiKTeX, whose options differ from
+    /// those of TeX Live.
+    pub miktex: bool,
+    /// The first line of its version information, or `None` if it is not
+    /// installed.
+    pub version: Option<String>,
+}
+
+/// Identify the programs that jobs with the given engines and converter run,
+/// so that the images of different toolchains can be told apart.
+pub(crate) fn toolchain(engines: &[String], converter: &str) -> Vec<Tool> {
+    engines
+        .iter()
+        .map(|engine| tool(engine, true))
+        .chain([tool(converter, false)])
+        .collect()
+}
+
+/// Identify a program that jobs run.
+pub(crate) fn tool(program: &str, engine: bool) -> Tool {
+    let path = locate(program);
+    let version = version(&path);
+    Tool {
+        program: program.into(),
+        miktex: version.as_ref().map_or(false, |version| version.contains("MiKTeX")),
+        path,
+        engine,
+        version,
+    }
+}
+
+/// Whether a figure may ask for an engine by name: Only LaTeX engines on the
+/// `PATH` qualify, like `xelatex` or `pdflatex-dev`, so that a document
+/// cannot run arbitrary programs.
+pub(crate) fn is_engine_name(name: &str) -> bool {
+    let base = name.strip_suffix("-dev").unwrap_or(name);
+    base.ends_with("latex") && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Find a program.
+///
+/// On Windows, TeX distributions are often installed without being added to
+/// the `PATH` of running shells, so their default locations are searched as
+/// well, after the `PATH`, like `where` would. Elsewhere, the program is left
+/// to the operating system to find.
+#[cfg(windows)]
+fn locate(program: &str) -> PathBuf {
+    let name = Path::new(program);
+    if name.components().count() > 1 {
+        return name.into();
+    }
+
+    // A name that already has an extension is also tried as it is.
+    let extensions = env::var_os("PATHEXT").unwrap_or_else(|| ".EXE;.BAT;.CMD".into());
+    let mut extensions: Vec<_> = env::split_paths(&extensions).collect();
+    if name.extension().is_some() {
+        extensions.insert(0, PathBuf::new());
+    }
+    let mut dirs: Vec<PathBuf> = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_default();
+
+    for (var, suffix) in [
+        ("LOCALAPPDATA", r"Programs\MiKTeX\miktex\bin\x64"),
+        ("ProgramFiles", r"MiKTeX\miktex\bin\x64"),
+        ("ProgramFiles(x86)", r"MiKTeX\miktex\bin"),
+    ] {
+        if let Some(base) = env::var_os(var) {
+            dirs.push(PathBuf::from(base).join(suffix));
+        }
+    }
+
+    // TeX Live installs each year's release into its own directory.
+    let mut releases: Vec<_> = fs::read_dir(r"C:\texlive")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    releases.sort();
+    for release in releases.iter().rev() {
+        dirs.extend(
+            ["windows", "win64", "win32"].map(|arch| release.join("bin").join(arch)),
+        );
+    }
+
+    dirs.iter()
+        .flat_map(|dir| {
+            let plain = dir.join(name);
+            extensions.iter().map(move |extension| {
+                let mut file = plain.clone().into_os_string();
+                file.push(extension);
+                PathBuf::from(file)
+            })
+        })
+        .find(|file| file.is_file())
+        .unwrap_or_else(|| name.into())
+}
+
+/// Find a program.
+///
+/// On Windows, TeX distributions are often installed without being added to
+/// the `PATH` of running shells, so their default locations are searched as
+/// well. Elsewhere, the program is left to the operating system to find.
+#[cfg(not(windows))]
+fn locate(program: &str) -> PathBuf {
+    program.into()
+}
+
+/// A path that Windows accepts even if it is longer than 260 characters.
+///
+/// Job directories can become deep once the files that figures reference are
+/// copied into them. Only file operations in this process use such paths,
+/// since TeX does not understand them.
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::Prefix;
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut long = OsString::from(r"\\?\");
+                long.push(path);
+                long.into()
+            }
+            Prefix::UNC(..) => {
+                let mut long = OsString::from(r"\\?\UNC");
+                long.push(&path.as_os_str().to_string_lossy()[1..]);
+                long.into()
+            }
+            _ => path.into(),
+        },
+        _ => path.into(),
+    }
+}
+
+/// A path that Windows accepts even if it is longer than 260 characters.
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.into()
+}
+
+/// How to install a program that jobs run.
+pub(crate) fn install_hint(program: &str) -> &'static str {
+    let name = Path::new(program).file_stem().and_then(|stem| stem.to_str());
+    match name.unwrap_or(program) {
+        PDF_CONVERTER => "install it with your package manager, e.g. `apt install pdf2svg` or `brew install pdf2svg`",
+        OUTLINE_CONVERTER => "it ships with TeX Live and MiKTeX, or install it with your package manager, e.g. `apt install dvisvgm`",
+        _ => "install a TeX distribution such as TeX Live (e.g. `apt install texlive-luatex texlive-pictures`) or MiKTeX, and make sure its programs are on the PATH",
+    }
+}
+
+/// The first line that a program prints when asked for its version, or
+/// `None` if the program cannot be run.
+///
+/// pdf2svg has no such flag and prints its usage instead, which at least tells
+/// whether it is installed.
+fn version(program: &Path) -> Option<String> {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+
+    let text = if output.stdout.is_empty() { output.stderr } else { output.stdout };
+    let text = String::from_utf8_lossy(&text);
+    Some(text.lines().next().unwrap_or_default().trim().into())
+}
+
+/// Run a command to completion.
+fn execute(cmd: &mut Command, timeout: Option<Duration>) -> Result<(), String> {
+    attempt(cmd, timeout, false).map_err(Failure::into_message)
+}
+
+/// How often a running command is checked for completion.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a program must be silent after a prompt before it is taken to
+/// wait for input, since TeX also pauses in the middle of lines.
+const PROMPT_GRACE: Duration = Duration::from_secs(1);
+
+/// Run a command to completion, killing it if it runs for longer than the
+/// timeout or, if it is a TeX engine, waits for input, and tell how it
+/// failed.
+fn attempt(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    tex: bool,
+) -> Result<(), Failure> {
+    trace_event!(debug, "Running {:?}", cmd);
+
+    let mut child = cmd.stdout(Stdio::piped()).spawn().map_err(|err| {
+        let program = cmd.get_program().to_string_lossy();
+        Failure::Missing(format!("failed to invoke {}: {}", program, err))
+    })?;
+
+    let fetch_failed =
+        |err| Failure::Crashed(format!("failed to fetch LaTeX process: {}", err));
+
+    // The output is read on another thread, so that a chatty program cannot
+    // block on a full pipe while it is waited for, together with the time it
+    // last grew, so that a prompt is noticed while the program runs.
+    let output = Arc::new(Mutex::new((vec![], Instant::now())));
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn({
+        let output = output.clone();
+        move || {
+            let Some(stdout) = &mut stdout else { return };
+            let mut chunk = [0; 4096];
+            while let Ok(read @ 1..) = stdout.read(&mut chunk) {
+                let mut output = output.lock().unwrap();
+                output.0.extend_from_slice(&chunk[..read]);
+                output.1 = Instant::now();
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(fetch_failed)? {
+            break status;
+        }
+
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() >= timeout) {
+            child.kill().ok();
+            child.wait().ok();
+            let program = cmd.get_program().to_string_lossy();
+            return Err(Failure::TimedOut(format!(
+                "{} did not finish within {:?} and was killed",
+                program, timeout
+            )));
+        }
+
+        let prompted = {
+            let (stdout, grown) = &*output.lock().unwrap();
+            (tex && grown.elapsed() >= PROMPT_GRACE)
+                .then(|| waiting(stdout))
+                .flatten()
+        };
+        if let Some(failure) = prompted {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(failure);
+        }
+
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    };
+
+    reader.join().ok();
+    let stdout = std::mem::take(&mut output.lock().unwrap().0);
+    check(Output { status, stdout, stderr: vec![] })
+}
+
+/// The failure of a program whose output ends in a prompt for input, with the
+/// prompt and the lines that led to it, or `None` if it does not.
+///
+/// TeX engines run in nonstop mode, but some packages read from the terminal
+/// regardless, e.g. to ask for a file that is missing, and would wait for an
+/// answer forever.
+fn waiting(stdout: &[u8]) -> Option<Failure> {
+    let line = stdout
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |end| end + 1);
+    let prompt = String::from_utf8_lossy(&stdout[line..]);
+    if !REG_PROMPT.is_match(&prompt) {
+        return None;
+    }
+
+    let excerpt = super::excerpt(&String::from_utf8_lossy(stdout)).join("\n");
+    Some(Failure::Waiting(format!(
+        "LaTeX waited for input at the prompt {:?} and was killed\n{}",
+        prompt.trim(),
+        excerpt
+    )))
+}
+
+/// Run a command to completion without blocking the executor.
+#[cfg(feature = "async")]
+async fn execute_async(cmd: Command, timeout: Option<Duration>) -> Result<(), String> {
+    attempt_async(cmd, timeout, false)
+        .await
+        .map_err(Failure::into_message)
+}
+
+/// Run a command to completion without blocking the executor, killing it if
+/// it runs for longer than the timeout or, if it is a TeX engine, waits for
+/// input, and tell how it failed.
+#[cfg(feature = "async")]
+async fn attempt_async(
+    cmd: Command,
+    timeout: Option<Duration>,
+    tex: bool,
+) -> Result<(), Failure> {
+    trace_event!(debug, "Running {:?}", cmd);
+
+    use tokio::io::AsyncReadExt;
+
+    let mut cmd = tokio::process::Command::from(cmd);
+    let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+    let mut child =
+        cmd.stdout(Stdio::piped()).kill_on_drop(true).spawn().map_err(|err| {
+            Failure::Missing(format!("failed to invoke {}: {}", program, err))
+        })?;
+
+    // A program that is silent for a while after a prompt waits for input.
+    // Returning early drops the child, which kills the process.
+    let mut stdout = child.stdout.take();
+    let output = async {
+        let mut buffer = vec![];
+        if let Some(stdout) = &mut stdout {
+            let mut chunk = [0; 4096];
+            loop {
+                match tokio::time::timeout(PROMPT_GRACE, stdout.read(&mut chunk)).await {
+                    Ok(Ok(0) | Err(_)) => break,
+                    Ok(Ok(read)) => buffer.extend_from_slice(&chunk[..read]),
+                    Err(_) if tex => {
+                        if let Some(failure) = waiting(&buffer) {
+                            return Err(failure);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+
+        let status = child.wait().await.map_err(|err| {
+            Failure::Crashed(format!("failed to fetch LaTeX process: {}", err))
+        })?;
+        Ok(Output { status, stdout: buffer, stderr: vec![] })
+    };
+
+    // Dropping the future on a timeout kills the process.
+    let output = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, output).await.map_err(|_| {
+            Failure::TimedOut(format!(
+                "{} did not finish within {:?} and was killed",
+                program, timeout
+            ))
+        })?,
+        None => output.await,
+    };
+
+    check(output?)
+}
+
+/// Turn the output of a failed process into an error with its logs.
+///
+/// TeX engines exit with status 1 when the document has an error. Any other
+/// status, or none at all because the process was killed, is a crash, except
+/// for the signals that tell that a resource limit was exceeded.
+fn check(output: Output) -> Result<(), Failure> {
+    let Output { status, stdout, .. } = output;
+    let logs = String::from_utf8_lossy(&stdout).into_owned();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        let limit = match status.signal() {
+            Some(libc::SIGXCPU) => Some("processor time"),
+            Some(libc::SIGXFSZ) => Some("output size"),
+            _ => None,
+        };
+        if let Some(limit) = limit {
+            return Err(Failure::Error(format!(
+                "the program exceeded the {} limit and was killed\n{}",
+                limit, logs
+            )));
+        }
+    }
+
+    match status.code() {
+        _ if status.success() => Ok(()),
+        Some(1) => Err(Failure::Error(logs)),
+        _ => Err(Failure::Crashed(logs)),
+    }
+}
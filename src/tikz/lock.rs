@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip128::{Hasher128, SipHasher13};
+
+use super::{BlockStatus, ReplaceReport, Tikz};
+
+/// The name of the file that pins the images of a project's figures.
+pub const LOCK_FILE: &str = "typst-tikz.lock";
+
+/// The version of the format of lockfiles. Version 1 keyed figures by their
+/// hashes, which change with the toolchain.
+const LOCK_VERSION: u32 = 2;
+
+/// The comment at the top of lockfiles.
+const LOCK_HEADER: &str = concat!(
+    "# Generated by typst-tikz. Pins the SVGs of all TikZ figures, so that\n",
+    "# changes to them are noticed.\n\n",
+);
+
+/// Pins the SVGs of TikZ figures to checksums, so that a new TeX
+/// distribution or a changed package cannot silently change the images of
+/// published material.
+///
+/// Figures are keyed by their identities, which only depend on their code,
+/// their options and the LaTeX document around them, but not on the programs
+/// that compile them, while the checksums cover the SVGs of all their pages.
+/// A new TeX distribution thus keeps the keys but may change the checksums.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    /// The version of the format.
+    pub version: u32,
+    /// The checksum of the SVGs of each figure, by the identity of the
+    /// figure.
+    pub figures: BTreeMap<String, String>,
+}
+
+/// A figure whose SVGs no longer match their checksum in the lockfile.
+#[derive(Debug, Clone)]
+pub struct Drift {
+    /// The hash of the figure.
+    pub hash: u64,
+    /// The checksum in the lockfile.
+    pub locked: String,
+    /// The checksum of the current SVGs.
+    pub actual: String,
+}
+
+/// What to do about figures whose SVGs drifted from the lockfile.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LockMode {
+    /// Warn about them, but keep the lockfile as it is.
+    Warn,
+    /// Fail the build.
+    Deny,
+    /// Accept their new SVGs and write the lockfile anew.
+    Update,
+}
+
+impl FromStr for LockMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "deny" => Ok(Self::Deny),
+            "update" => Ok(Self::Update),
+            _ => Err(format!(
+                "unknown lock mode `{}`, expected `warn`, `deny` or `update`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the SVG of TikZ figure {} no longer matches {} (locked {}, now {})",
+            self.hash, LOCK_FILE, self.locked, self.actual
+        )
+    }
+}
+
+impl Default for Lockfile {
+    fn default() -> Self {
+        Self { version: LOCK_VERSION, figures: BTreeMap::new() }
+    }
+}
+
+impl Lockfile {
+    /// Read a lockfile, or start an empty one if it does not exist.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(format!("failed to read {}: {}", path.display(), err))
+            }
+        };
+
+        let lock: Self = toml::from_str(&text)
+            .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+        if lock.version != LOCK_VERSION {
+            return Err(format!(
+                "{} has version {}, but only version {} is supported, so it must \
+                 be recorded anew",
+                path.display(),
+                lock.version,
+                LOCK_VERSION
+            ));
+        }
+
+        Ok(lock)
+    }
+
+    /// Write the lockfile.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let text = toml::to_string(self)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        fs::write(path, format!("{}{}", LOCK_HEADER, text))
+    }
+
+    /// Check the SVGs of the compiled figures in the reports against their
+    /// checksums, and record the checksums of figures that are not locked
+    /// yet.
+    ///
+    /// Returns the figures whose SVGs drifted. If `update` is set, their new
+    /// checksums are recorded as well. Figures that failed to compile are
+    /// skipped, and figures that none of the reports contains any more are
+    /// forgotten.
+    pub fn verify<'a>(
+        &mut self,
+        tikz: &Tikz,
+        reports: impl IntoIterator<Item = &'a ReplaceReport>,
+        update: bool,
+    ) -> Vec<Drift> {
+        let mut drifts = vec![];
+        let mut seen = HashSet::new();
+        for report in reports {
+            for block in &report.blocks {
+                let key = block.identity.to_string();
+                seen.insert(key.clone());
+                if block.status != BlockStatus::Compiled {
+                    continue;
+                }
+
+                let Some(actual) = checksum(tikz, &block.pages) else { continue };
+                match self.figures.get(&key) {
+                    Some(locked) if *locked == actual => {}
+                    Some(locked) if !update => {
+                        drifts.push(Drift {
+                            hash: block.hash,
+                            locked: locked.clone(),
+                            actual,
+                        });
+                    }
+                    _ => {
+                        self.figures.insert(key, actual);
+                    }
+                }
+            }
+        }
+
+        self.figures.retain(|key, _| seen.contains(key));
+        drifts
+    }
+}
+
+/// The checksum of the SVGs of a figure's pages, or `None` if any of them is
+/// not compiled.
+fn checksum(tikz: &Tikz, pages: &[u64]) -> Option<String> {
+    let mut hasher = SipHasher13::new();
+    for &page in pages {
+        let svg = tikz.fetch(page)?.ok()?;
+        hasher.write_usize(svg.len());
+        hasher.write(&svg);
+    }
+
+    Some(format!("{:032x}", hasher.finish128().as_u128()))
+}
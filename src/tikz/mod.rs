@@ -0,0 +1,3472 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize, Serializer};
+use siphasher::sip::SipHasher13;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::ops::{AddAssign, Range};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use svg_metadata::{Height, Metadata, Unit, Width};
+use tempfile::TempDir;
+use typst::diag::SourceError;
+use typst::World;
+use walkdir::WalkDir;
+
+/// Emits a tracing event if the `tikz-tracing` feature is enabled and the
+/// configured verbosity includes its level.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tikz-tracing")]
+        if crate::tikz::logs(stringify!($level)) {
+            tracing::$level!($($arg)+);
+        }
+    };
+}
+
+mod cache;
+mod cetz;
+mod compat;
+mod export;
+mod job;
+mod lock;
+mod pool;
+mod project;
+mod quiver;
+#[cfg(feature = "remote")]
+mod remote;
+mod render;
+mod session;
+mod source_map;
+mod workspace;
+
+pub use self::export::{ExportFormat, ExportedFigure, Manifest};
+pub use self::job::{RasterFallback, ResourceLimits, StageTimings};
+pub use self::lock::{Drift, LockMode, Lockfile, LOCK_FILE};
+pub use self::project::{Engines, ProjectConfig, PROJECT_FILE};
+#[cfg(feature = "remote")]
+pub use self::remote::RemoteRenderer;
+pub use self::render::{RenderRequest, Renderer};
+pub use self::session::Session;
+pub use self::source_map::{Region, SourceMap};
+pub use self::workspace::{Workspace, WorkspaceFile, WorkspaceReport};
+
+use self::cache::Cache;
+use self::job::{Frame, InputFile, Job, Tool};
+use self::pool::Pool;
+
+const REGEX_PATTERN_TIKZ: &str = r"(?P<environment>tikzpicture|tikzcd|displaymath)(?:\((?P<options>[^)]*)\))?\[(?P<block>\s*(?:```(?P<tex_code>(?s).*?)```)?\s*)\]";
+
+const REGEX_PATTERN_ASSET: &str = r"\\(?:input|include|includegraphics|pgfimage|pgfplotstableread)\s*(?:\[[^\]]*\])?\s*\{(?P<path>[^}]+)\}|\btable\s*(?:\[[^\]]*\])?\s*\{(?P<table>[^\s{}]+)\}";
+
+const REGEX_PATTERN_PREAMBLE: &str = r"^\s*\\(?:usepackage|RequirePackage|usetikzlibrary|usepgfplotslibrary)\s*(?:\[[^\]]*\])?\s*\{[^}]*\}\s*(?:%.*)?$";
+
+/// Plots that pgfplots computes with gnuplot, like `\addplot gnuplot {x^2}`
+/// or `\addplot gnuplot [raw gnuplot] {...}`.
+const REGEX_PATTERN_GNUPLOT: &str = r"\\addplot3?\+?\s*(?:\[[^\]]*\]\s*)?gnuplot\b";
+
+/// The placeholders of `TikzConfig::template`.
+const REGEX_PATTERN_TEMPLATE_PLACEHOLDER: &str =
+    r"\{(filename|hash|width|height|attributes)\}";
+
+lazy_static! {
+    static ref REG_TIKZ: Regex = Regex::new(REGEX_PATTERN_TIKZ).unwrap();
+    static ref REG_ASSET: Regex = Regex::new(REGEX_PATTERN_ASSET).unwrap();
+    static ref REG_PREAMBLE: Regex = Regex::new(REGEX_PATTERN_PREAMBLE).unwrap();
+    static ref REG_GNUPLOT: Regex = Regex::new(REGEX_PATTERN_GNUPLOT).unwrap();
+    static ref REG_TEMPLATE_PLACEHOLDER: Regex =
+        Regex::new(REGEX_PATTERN_TEMPLATE_PLACEHOLDER).unwrap();
+}
+
+const LATEX_DOCUMENT_CLASS: &str = "standalone";
+const LATEX_CLASS_OPTION_TIKZ: &str = "tikz";
+const LATEX_CLASS_OPTION_MATH: &str = "varwidth";
+const LATEX_CLASS_OPTION_DVI: &str = "dvisvgm";
+const LATEX_MATH_PACKAGE: &str = r#"\usepackage{amsmath}"#;
+const LATEX_PREAMBLE: &str = include_str!("../../assets/latex/quiver.sty");
+const LATEX_DOCUMENT_BEGIN: &str = r#"\begin{document}"#;
+
+const LATEX_DOCUMENT_END: &str = r#"\end{document}"#;
+
+const LUA_CONFIG: &str = r#"
+    texconfig.halt_on_error = true
+    texconfig.interaction = 0
+
+    callback.register('show_error_message', function(...)
+        texio.write_nl('term and log', status.lasterrorstring)
+        texio.write('term', '.\n')
+    end)
+
+    callback.register('show_lua_error_hook', function(...)
+        texio.write_nl('term and log', status.lastluaerrorstring)
+        texio.write('term', '.\n')
+    end)
+"#;
+
+const PLACEHOLDER: &str = "box(stroke: red, inset: 4pt)[failed to embed TikZ figure]";
+
+/// The most lines of LaTeX's output that an excerpt has.
+const EXCERPT_LINES: usize = 8;
+
+const PT_PER_PX: f64 = 0.75;
+const PT_PER_PC: f64 = 12.0;
+const PT_PER_CM: f64 = 72.0 / 2.54;
+const EM_PER_EX: f64 = 0.5;
+
+/// A figure that is exactly 100 TeX points wide, whose image should thus be
+/// 100 Typst points wide.
+const CALIBRATION_FIGURE: &str = r"\path (0,0) rectangle (100pt,10pt);";
+const CALIBRATION_WIDTH: f64 = 100.0;
+
+/// Changes whenever the pipeline changes in a way that affects the images.
+const CACHE_VERSION: u32 = 1;
+
+const DEFAULT_FILENAME: &str = "generated_tikz_{hash}.svg";
+const HASH_PLACEHOLDER: &str = "{hash}";
+
+/// The label of the metadata that describes each figure.
+const METADATA_LABEL: &str = "tikz-diagram";
+
+/// The macro that holds the number of the frame in animated figures.
+const FRAME_MACRO: &str = "tikzframe";
+const DEFAULT_REVEAL: &str = "alternatives";
+
+/// The helper module that rewritten buffers call into, unless the project
+/// has its own file under the configured path.
+const HELPER_SOURCE: &str = r#"// Generated by typst-tikz. All TikZ figures are passed through `tikz-figure`,
+// so that they can be restyled in one place, e.g. to center them or to add
+// padding. `body` is the image of the figure, or a stack of its pages.
+#let tikz-figure(body, hash: none, environment: none) = body
+"#;
+
+/// Options that control how TikZ figures are compiled.
+#[derive(Debug, Clone)]
+pub struct TikzConfig {
+    /// Whether to outline all glyphs, so that the generated SVGs do not depend
+    /// on any fonts.
+    pub outline_text: bool,
+    /// The font for the text in TikZ figures. LaTeX's default font is used if
+    /// this is `None`.
+    pub main_font: Option<TikzFont>,
+    /// The font for the math in TikZ figures. LaTeX's default font is used if
+    /// this is `None`.
+    pub math_font: Option<TikzFont>,
+    /// The language of the text in TikZ figures, e.g. `german` or `arabic`,
+    /// optionally preceded by its options in brackets, like
+    /// `[bidi=basic]arabic`. It selects the hyphenation patterns, the
+    /// localized captions and the direction of the script. A figure can ask
+    /// for another language with its own option, as in
+    /// `tikzcd(language: "french")[...]`. LaTeX's default of English is used
+    /// if this is `None`.
+    pub language: Option<String>,
+    /// The package that sets up the language of figures.
+    pub language_package: LanguagePackage,
+    /// The look of the arrows in all `tikzcd` figures, so that the diagrams
+    /// of a whole course match without a `\tikzcdset` in every file. A
+    /// figure can ask for another preset with its own option, as in
+    /// `tikzcd(preset: "thick-arrows")[...]`. tikz-cd's defaults are used if
+    /// this is `None`.
+    pub tikzcd_preset: Option<ArrowPreset>,
+    /// The factor by which the widths of the generated images are scaled, to
+    /// size figures against the rest of the document.
+    ///
+    /// It applies on top of the `calibration`: absolute widths are first
+    /// corrected so that TeX points are Typst points, and then scaled, so
+    /// that a scale of 1 keeps the size that the figure has in LaTeX.
+    pub scale: f64,
+    /// Corrects the absolute widths of the generated images, like in points
+    /// or pixels, for the way that the LaTeX engine and the converter define
+    /// those units, so that a length in TeX points becomes the same length in
+    /// Typst points and text in figures has the size of text in the document.
+    /// Widths in `em` are not affected.
+    ///
+    /// Unlike the `scale`, it is a property of the toolchain rather than a
+    /// choice, and the two factors are multiplied.
+    pub calibration: Calibration,
+    /// The maximum number of figures that are compiled at the same time.
+    pub jobs: usize,
+    /// The directory in which compiled figures are cached across projects and
+    /// processes. Figures are only cached in memory if this is `None`.
+    pub cache_dir: Option<PathBuf>,
+    /// The path under which the rewritten buffer refers to the image of a
+    /// figure, relative to the buffer. It must contain `{hash}` exactly once,
+    /// which is replaced by the hash of the figure. The path may contain
+    /// directories, e.g. `.tikz-cache/{hash}.svg`.
+    pub filename: String,
+    /// The Typst code that embeds the image of a figure, e.g.
+    /// `align(center, box(stroke: 0.5pt, image("{filename}", {attributes})))`.
+    /// `{filename}` is replaced by the path of the image, `{hash}` by the
+    /// hash of the image, `{width}` and `{height}` by its size or `auto`, and
+    /// `{attributes}` by the arguments that size it by default, like
+    /// `width: 3.5cm`. It must contain `{filename}`. The images are embedded
+    /// with `image("{filename}", {attributes})` if this is `None`.
+    pub template: Option<String>,
+    /// Extra directories in which LaTeX looks for files, e.g. a course's own
+    /// style files. They are searched before the default directories, and a
+    /// trailing `//` also searches their subdirectories.
+    pub search_paths: Vec<PathBuf>,
+    /// The preamble that all figures share, which defaults to the bundled
+    /// `quiver.sty`. Replace it to use your own macros and styles, or append
+    /// to the default to extend it.
+    pub preamble: String,
+    /// Extra packages that are loaded after the preamble. Each entry is the
+    /// argument of a `\usepackage`, optionally preceded by its options in
+    /// brackets, e.g. `[compat=1.18]pgfplots`.
+    pub packages: Vec<String>,
+    /// TikZ libraries that are loaded after the packages for every figure,
+    /// e.g. `arrows.meta` or `decorations.pathmorphing`, so that figures need
+    /// no `\usetikzlibrary` of their own. An entry may also list several
+    /// libraries separated by commas. Duplicates are loaded once.
+    pub libraries: Vec<String>,
+    /// Extra options of the `standalone` document class, e.g. `border=4pt`
+    /// for padding around figures or `varwidth` for figures with a lot of
+    /// text. A figure can override them with its own options, as in
+    /// `tikzcd(border: 2pt)[...]`.
+    pub class_options: Vec<String>,
+    /// How figures are turned into SVGs: Through PDFs, which pdf2svg or
+    /// dvisvgm convert, or through DVI files, which dvisvgm converts. The DVI
+    /// pipeline is faster and produces tighter SVGs, but it does not support
+    /// fonts that are loaded with fontspec. It falls back to the PDF pipeline
+    /// if dvisvgm is missing.
+    pub pipeline: Pipeline,
+    /// The formats in which `Tikz::export` writes the figures. The SVGs are
+    /// always written, since the rewritten buffers refer to them. PDFs and
+    /// PNGs come from the PDF that LaTeX produces when it compiles a figure,
+    /// so they need the PDF pipeline, which is used in their presence.
+    pub export_formats: Vec<ExportFormat>,
+    /// Whether to replace figures with placeholders, gray boxes with their
+    /// environment and hash, if the TeX toolchain is not installed, so that
+    /// documents can still be built without TeX. Otherwise, every figure
+    /// fails to compile.
+    pub placeholders: bool,
+    /// Whether to compile every figure again once, instead of taking it from
+    /// the shared cache or from memory, e.g. after a package was updated.
+    pub force: bool,
+    /// Whether whitespace that TeX ignores is left out of the hashes of
+    /// figures, so that re-indenting a figure or removing trailing
+    /// whitespace does not compile it again. Each line of the code is
+    /// stripped of its leading and trailing whitespace before it is hashed,
+    /// which TeX does as well, except in verbatim text. It is disabled by
+    /// default, so that figures are hashed byte for byte and keep the hashes
+    /// they had before.
+    pub normalize_whitespace: bool,
+    /// The LaTeX engines that compile the figures, e.g. `lualatex` or
+    /// `xelatex`. If an engine is missing or crashes, the next one is tried.
+    /// There must be at least one.
+    pub engines: Vec<String>,
+    /// The program that converts the PDFs into SVGs, either `pdf2svg` or
+    /// `dvisvgm`, optionally with a path. If this is `None`, `dvisvgm` is
+    /// used to outline text and `pdf2svg` otherwise.
+    pub converter: Option<String>,
+    /// How long LaTeX and the converter may run for a figure before they are
+    /// killed, e.g. to stop a figure that loops forever. They may run for as
+    /// long as they like if this is `None`.
+    pub timeout: Option<Duration>,
+    /// The address of a `typst-tikz serve` instance that compiles the
+    /// figures in place of a local LaTeX installation, e.g.
+    /// `http://tex.example.org:8471`, so that documents build on machines
+    /// without TeX. The SVGs it returns are cached like any others, and it is
+    /// only contacted when a figure is not cached. This needs the `remote`
+    /// feature and is ignored by `Tikz::with_renderer`.
+    pub remote: Option<String>,
+    /// A directory in which the files of each compiled figure are kept, in a
+    /// subdirectory named by its hash: the LaTeX document `tikz.tex`, the
+    /// `tikz.pdf` and `tikz.log` that LaTeX produced, and the SVGs. This helps
+    /// to reproduce failures by hand. Figures that are taken from the shared
+    /// cache are not compiled and thus leave no files. If this is `None`, the
+    /// files are kept in a temporary directory that is removed on drop.
+    pub keep_intermediates: Option<PathBuf>,
+    /// Whether the generated PDFs and SVGs must be byte-identical for the same
+    /// figure across machines and runs, so that they can be verified and
+    /// diffed in version control. PDFs are dated by `SOURCE_DATE_EPOCH`, or
+    /// the Unix epoch if it is unset, and SVGs are stripped of comments with
+    /// the versions of the converters. This is enabled by default if
+    /// `SOURCE_DATE_EPOCH` is set.
+    pub reproducible: bool,
+    /// How much is logged through `tracing` about finding, compiling,
+    /// converting and caching figures.
+    pub verbosity: Verbosity,
+    /// Caps on the memory, processor time and file sizes of LaTeX and the
+    /// converter for a figure, which are only enforced on Unix.
+    pub limits: ResourceLimits,
+    /// When the SVGs of figures that are too large or complex are replaced by
+    /// high-resolution raster images. It is disabled by default.
+    pub raster: RasterFallback,
+    /// The path of a Typst helper module, relative to the buffer, e.g.
+    /// `tikz.typ`. If set, the images of figures are passed through the
+    /// module's `tikz-figure` function, which receives the image together
+    /// with the `hash` and `environment` of the figure, so that all figures
+    /// can be restyled in one place. The module is generated unless the
+    /// project provides its own.
+    pub helper: Option<String>,
+    /// Whether to translate simple `tikzpicture` figures into CeTZ code
+    /// instead of compiling them, so that they stay editable and need no
+    /// LaTeX. This is experimental and only understands nodes with plain
+    /// text at explicit coordinates, and straight lines, circles and
+    /// rectangles. Other figures are compiled as usual.
+    pub cetz: bool,
+    /// The Typst function that shows the frames of an animated figure one
+    /// after another, e.g. `alternatives` from polylux or touying. A figure
+    /// is animated with an option like `frames: 4`, which compiles it once
+    /// per frame, with the number of the frame in `\tikzframe`.
+    pub reveal: String,
+    /// Whether to follow each figure with a `metadata` element that holds
+    /// its `hash`, `env`ironment and `line`, labelled `<tikz-diagram>`, so
+    /// that `typst query` can list all figures of a document. The Typst of
+    /// this crate has no `metadata`, so only enable this for rewritten buffers
+    /// that are exported for Typst 0.8 or later, like by `typst-tikz
+    /// preprocess --tikz-metadata`.
+    pub metadata: bool,
+    /// What `try_replace_in` does about figures that fail to compile.
+    pub failure_policy: FailurePolicy,
+}
+
+impl Default for TikzConfig {
+    fn default() -> Self {
+        Self {
+            outline_text: false,
+            main_font: None,
+            math_font: None,
+            language: None,
+            language_package: LanguagePackage::default(),
+            tikzcd_preset: None,
+            scale: 1.0,
+            calibration: Calibration::default(),
+            jobs: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            cache_dir: default_cache_dir(),
+            filename: DEFAULT_FILENAME.into(),
+            template: None,
+            search_paths: vec![],
+            preamble: LATEX_PREAMBLE.into(),
+            packages: vec![],
+            libraries: vec![],
+            class_options: vec![],
+            pipeline: Pipeline::default(),
+            export_formats: vec![ExportFormat::Svg],
+            placeholders: false,
+            force: false,
+            normalize_whitespace: false,
+            engines: vec![job::LATEX_ENGINE.into()],
+            converter: None,
+            timeout: None,
+            remote: None,
+            keep_intermediates: None,
+            reproducible: std::env::var_os("SOURCE_DATE_EPOCH").is_some(),
+            verbosity: Verbosity::default(),
+            limits: ResourceLimits::default(),
+            raster: RasterFallback::default(),
+            helper: None,
+            cetz: false,
+            reveal: DEFAULT_REVEAL.into(),
+            metadata: false,
+            failure_policy: FailurePolicy::default(),
+        }
+    }
+}
+
+/// The cache directory that all projects share by default.
+fn default_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("typst-tikz"))
+}
+
+/// The programs that are needed to compile TikZ figures but are not
+/// installed, as returned by `Tikz::verify`.
+#[derive(Debug, Clone)]
+pub struct MissingPrograms {
+    /// The missing programs, each with a hint on how to install it.
+    pub programs: Vec<(String, String)>,
+}
+
+impl fmt::Display for MissingPrograms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> =
+            self.programs.iter().map(|(program, _)| program.as_str()).collect();
+        write!(f, "could not find {} to compile TikZ figures", names.join(", "))?;
+        for (program, hint) in &self.programs {
+            write!(f, "\n  {}: {}", program, hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MissingPrograms {}
+
+/// A single rendered TikZ figure, as returned by `Tikz::render_block`.
+#[derive(Debug, Clone)]
+pub struct Svg {
+    /// The hash of the figure, under which `fetch` returns it as well.
+    pub hash: u64,
+    /// The SVG of the figure's first page.
+    pub data: Vec<u8>,
+    /// The width of the figure as a Typst length, scaled like in rewritten
+    /// buffers, or `None` if the SVG has no size information.
+    pub width: Option<String>,
+}
+
+/// Identifies the figure whose image is registered with
+/// `Tikz::register_image`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ImageKey {
+    /// The figure with this hash.
+    Hash(u64),
+    /// The figures with this `name` option, like `tikzcd(name: "square")`.
+    Name(String),
+}
+
+impl From<u64> for ImageKey {
+    fn from(hash: u64) -> Self {
+        Self::Hash(hash)
+    }
+}
+
+impl From<&str> for ImageKey {
+    fn from(name: &str) -> Self {
+        Self::Name(name.into())
+    }
+}
+
+impl From<String> for ImageKey {
+    fn from(name: String) -> Self {
+        Self::Name(name)
+    }
+}
+
+/// Why a single TikZ figure could not be rendered.
+#[derive(Debug, Clone)]
+pub enum TikzError {
+    /// The environment is not one that TikZ figures can use.
+    UnknownEnvironment(String),
+    /// The figure failed to compile, with the LaTeX logs.
+    Compilation(String),
+    /// The figure compiled, but its SVG cannot be embedded.
+    Unembeddable(String),
+    /// No figure with this hash was compiled, e.g. because the filename that
+    /// refers to it is stale or was written by hand.
+    UnknownFigure(u64),
+}
+
+impl fmt::Display for TikzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownEnvironment(environment) => {
+                write!(f, "unknown TikZ environment {}", environment)
+            }
+            Self::Compilation(logs) => {
+                write!(f, "failed to compile TikZ figure:\n{}", logs)
+            }
+            Self::Unembeddable(message) => f.write_str(message),
+            Self::UnknownFigure(hash) => {
+                write!(f, "unknown TikZ figure {}, which may be a stale reference", hash)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TikzError {}
+
+/// A TikZ figure that compiled and can be embedded, as reported by
+/// `Tikz::check_in`.
+#[derive(Debug, Clone)]
+pub struct DiagramInfo {
+    /// The hash of the figure.
+    pub hash: u64,
+    /// The LaTeX environment of the figure, e.g. `tikzcd`.
+    pub environment: String,
+    /// Whether the image was taken from the cache instead of being compiled.
+    pub cached: bool,
+    /// The width of the figure's first page as a Typst length, or `None` if
+    /// the SVG has no size information.
+    pub width: Option<String>,
+}
+
+/// What happens when figures fail to compile.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum FailurePolicy {
+    /// Replace them with images that fail to load, so that Typst reports
+    /// them when it compiles the rewritten buffer.
+    #[default]
+    Defer,
+    /// Stop at the first figure that fails to compile and report it, without
+    /// compiling the remaining figures.
+    FailFast,
+    /// Compile all figures and report every one that fails, so that they can
+    /// be fixed in one pass.
+    CollectAll,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "defer" => Ok(Self::Defer),
+            "fail-fast" => Ok(Self::FailFast),
+            "collect-all" => Ok(Self::CollectAll),
+            _ => Err(format!(
+                "invalid failure policy {}, expected defer, fail-fast or collect-all",
+                s
+            )),
+        }
+    }
+}
+
+/// The package that sets up the language of TikZ figures.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum LanguagePackage {
+    /// babel, which works with every engine. TikZ's `babel` library is
+    /// loaded as well, so that the shorthands of languages like French or
+    /// German do not break the syntax of TikZ.
+    #[default]
+    Babel,
+    /// polyglossia, which needs XeLaTeX or LuaLaTeX, but is the better fit
+    /// for right-to-left scripts with fontspec.
+    Polyglossia,
+}
+
+impl FromStr for LanguagePackage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "babel" => Ok(Self::Babel),
+            "polyglossia" => Ok(Self::Polyglossia),
+            _ => Err(format!(
+                "invalid language package {}, expected babel or polyglossia",
+                s
+            )),
+        }
+    }
+}
+
+/// A named look for the arrows of `tikzcd` figures.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ArrowPreset {
+    /// The rounded stealth arrowheads of quiver's editor, so that exported
+    /// diagrams look like they did while they were drawn.
+    Quiver,
+    /// Heavier lines with larger arrowheads, e.g. for slides.
+    ThickArrows,
+}
+
+impl ArrowPreset {
+    /// The `\tikzcdset` that sets up the preset.
+    fn definition(self) -> &'static str {
+        match self {
+            Self::Quiver => concat!(
+                r"\tikzcdset{arrow style=tikz,",
+                r" diagrams={>={Stealth[round,length=4pt,width=4.5pt,inset=2.75pt]}}}",
+            ),
+            Self::ThickArrows => concat!(
+                r"\tikzcdset{arrow style=tikz, arrows={line width=0.8pt},",
+                r" diagrams={>={Stealth[length=5pt,width=5pt]}}}",
+            ),
+        }
+    }
+}
+
+impl FromStr for ArrowPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "quiver" => Ok(Self::Quiver),
+            "thick-arrows" => Ok(Self::ThickArrows),
+            _ => Err(format!(
+                "invalid arrow preset {}, expected quiver or thick-arrows",
+                s
+            )),
+        }
+    }
+}
+
+/// How the absolute widths of the generated images are corrected.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Calibration {
+    /// Multiply them by a fixed factor, e.g. one that `Tikz::calibrate`
+    /// measured before.
+    Factor(f64),
+    /// Measure the factor with `Tikz::calibrate` when the compiler is
+    /// created. This compiles a small figure, unless it is cached.
+    Auto,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::Factor(1.0)
+    }
+}
+
+impl FromStr for Calibration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+
+        match s.trim().parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(Self::Factor(factor)),
+            _ => Err(format!(
+                "invalid calibration {}, expected a positive factor or auto",
+                s
+            )),
+        }
+    }
+}
+
+/// The programs through which figures are turned into SVGs.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum Pipeline {
+    /// LaTeX produces a PDF, which pdf2svg or dvisvgm convert.
+    #[default]
+    Pdf,
+    /// LaTeX produces a DVI file, which dvisvgm converts.
+    Dvi,
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pdf" => Ok(Self::Pdf),
+            "dvi" => Ok(Self::Dvi),
+            _ => Err(format!("invalid pipeline {}, expected pdf or dvi", s)),
+        }
+    }
+}
+
+/// How much the TikZ support logs.
+///
+/// Events are emitted through `tracing`, so a subscriber must be installed
+/// to see them, and it may filter them further, e.g. by the `typst_tikz`
+/// target. The verbosity applies to the whole process, so the compiler that
+/// was created last determines it.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// Log nothing.
+    Quiet,
+    /// Log warnings, e.g. figures that failed to compile.
+    #[default]
+    Normal,
+    /// Also log why each figure is compiled or taken from a cache.
+    Verbose,
+    /// Also log every command line that is executed.
+    Debug,
+}
+
+impl FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "quiet" => Ok(Self::Quiet),
+            "normal" => Ok(Self::Normal),
+            "verbose" => Ok(Self::Verbose),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!(
+                "invalid verbosity {}, expected quiet, normal, verbose or debug",
+                s
+            )),
+        }
+    }
+}
+
+/// The verbosity of the process, as set by the last compiler.
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Whether events of the given `tracing` level are logged.
+#[cfg_attr(not(feature = "tikz-tracing"), allow(dead_code))]
+fn logs(level: &str) -> bool {
+    let required = match level {
+        "error" | "warn" => Verbosity::Normal,
+        "info" => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    };
+    VERBOSITY.load(Ordering::Relaxed) >= required as u8
+}
+
+/// The outcome of a single figure, together with its byte range in the
+/// checked buffer.
+pub type Checked = (Range<usize>, Result<DiagramInfo, TikzError>);
+
+/// A font that LaTeX should use in TikZ figures.
+#[derive(Debug, Clone, Hash)]
+pub struct TikzFont {
+    /// The name of the font family.
+    pub family: String,
+    /// The file that contains the font. If this is `None`, LaTeX looks up the
+    /// font by its family name.
+    pub path: Option<PathBuf>,
+}
+
+impl TikzFont {
+    /// Create a font that is looked up by its family name.
+    pub fn new(family: String) -> Self {
+        Self { family, path: None }
+    }
+
+    /// The fontspec command that selects this font.
+    fn declaration(&self, command: &str) -> String {
+        match self
+            .path
+            .as_ref()
+            .and_then(|path| Some((path.parent()?, path.file_name()?)))
+        {
+            Some((dir, name)) => format!(
+                "\\{command}{{{}}}[Path={}/]",
+                name.to_string_lossy(),
+                // fontspec expects forward slashes even on Windows.
+                dir.to_string_lossy().replace('\\', "/")
+            ),
+            None => format!("\\{command}{{{}}}", self.family),
+        }
+    }
+}
+
+/// The result of replacing the TikZ figures in a buffer.
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    /// The rewritten buffer, or an empty string if it was written elsewhere,
+    /// like by `replace_into`.
+    pub text: String,
+    /// Problems with individual figures. Each of them was replaced by a
+    /// placeholder.
+    pub diagnostics: Vec<BlockDiagnostic>,
+    /// What happened to each of the figures.
+    pub report: ReplaceReport,
+    /// Maps offsets between the rewritten and the original buffer, e.g. to
+    /// translate the spans of diagnostics.
+    pub source_map: SourceMap,
+}
+
+/// The result of replacing the TikZ figures in an edited buffer, given the
+/// replacement of its previous version.
+#[derive(Debug, Clone)]
+pub struct IncrementalReplacement {
+    /// The replacement of the whole edited buffer.
+    pub replacement: Replacement,
+    /// The hashes of the figures that were replaced anew, and of those that
+    /// were changed or removed by the edit.
+    pub affected: HashSet<u64>,
+}
+
+/// What happened to the figures in a buffer during replacement.
+///
+/// It serializes into a report for tools like editors and CI annotators, in
+/// which hashes are strings and durations are seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplaceReport {
+    /// The figures, in the order in which they appear in the buffer.
+    pub blocks: Vec<BlockReport>,
+}
+
+impl ReplaceReport {
+    /// The number of figures whose images were already compiled before.
+    pub fn cache_hits(&self) -> usize {
+        self.blocks.iter().filter(|block| block.cached).count()
+    }
+
+    /// The number of figures that failed to compile or to embed.
+    pub fn failures(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| {
+                !matches!(block.status, BlockStatus::Compiled | BlockStatus::Translated)
+            })
+            .count()
+    }
+}
+
+/// What happened to a single figure during replacement.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockReport {
+    /// The hash of the figure.
+    #[serde(serialize_with = "serialize_hash")]
+    pub hash: u64,
+    /// The hash of the figure without the programs that compile it, which
+    /// only changes with its code, its options and the LaTeX document around
+    /// it, e.g. to pin its image across updates of the TeX distribution.
+    #[serde(serialize_with = "serialize_hash")]
+    pub identity: u64,
+    /// The LaTeX environment of the figure, e.g. `tikzcd`.
+    pub environment: String,
+    /// The name of the figure, as in `tikzcd(name: "pullback-square")[...]`,
+    /// under which `Tikz::export` also writes its image.
+    pub name: Option<String>,
+    /// Whether the figure was compiled and embedded, or translated,
+    /// successfully.
+    pub status: BlockStatus,
+    /// Whether the image was taken from the cache instead of being compiled.
+    pub cached: bool,
+    /// How long it took to process the figure.
+    #[serde(serialize_with = "serialize_seconds")]
+    pub duration: Duration,
+    /// The byte range of the figure in the original buffer.
+    pub range: Range<usize>,
+    /// The external files that the figure references, e.g. via `\input` or
+    /// `\includegraphics`, exactly as they are written in its code.
+    pub assets: Vec<PathBuf>,
+    /// The hashes of the images of all pages of the figure, starting with the
+    /// hash of the figure itself. Most figures have a single page.
+    #[serde(serialize_with = "serialize_hashes")]
+    pub pages: Vec<u64>,
+    /// The LaTeX engine that compiled the figure, or `None` if it failed or
+    /// was compiled by another process.
+    pub engine: Option<String>,
+    /// The warnings in the LaTeX log of the figure, like overfull boxes or
+    /// missing characters. Only known if the figure was compiled by this
+    /// process.
+    pub warnings: Vec<String>,
+    /// How long the stages of compiling the figure took, or of loading it
+    /// from the shared cache. Only known if this process did so.
+    pub timings: Option<StageTimings>,
+}
+
+/// Whether a figure was compiled and embedded, or translated, successfully.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockStatus {
+    /// The figure was replaced with its image.
+    Compiled,
+    /// LaTeX failed to compile the figure.
+    Failed,
+    /// The figure compiled, but its image cannot be embedded and was replaced
+    /// with a placeholder.
+    Unembeddable,
+    /// The figure was translated into CeTZ code instead of being compiled.
+    Translated,
+}
+
+/// A TikZ figure that was found without compiling it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedBlock {
+    /// The hash of the figure. It is serialized as a string, since JSON
+    /// numbers cannot represent all hashes exactly.
+    #[serde(serialize_with = "serialize_hash")]
+    pub hash: u64,
+    /// The LaTeX environment of the figure, e.g. `tikzcd`.
+    pub environment: String,
+    /// The byte range of the figure in the buffer.
+    pub range: Range<usize>,
+    /// Whether the image of the figure is already compiled, either in memory
+    /// or in the shared cache.
+    pub cached: bool,
+}
+
+/// A problem with a single TikZ figure.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockDiagnostic {
+    /// The hash of the figure.
+    #[serde(serialize_with = "serialize_hash")]
+    pub hash: u64,
+    /// The byte range of the figure in the original buffer.
+    pub range: Range<usize>,
+    /// The stage in which it went wrong.
+    pub stage: Stage,
+    /// What went wrong.
+    pub message: String,
+}
+
+/// The stage of processing a figure in which a problem arose.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// Compiling the figure with LaTeX and converting it into SVGs.
+    Compilation,
+    /// Embedding the SVGs into the rewritten buffer.
+    Embedding,
+}
+
+/// The progress of replacing the figures in a buffer.
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// The replacement started and found `total` figures in the buffer.
+    Started { total: usize },
+    /// Processing of the figure with the given index started. If the figure is
+    /// not `cached`, it is compiled next.
+    BlockStarted { index: usize, total: usize, hash: u64, cached: bool },
+    /// Processing of the figure with the given index finished.
+    BlockFinished {
+        index: usize,
+        total: usize,
+        hash: u64,
+        status: BlockStatus,
+        duration: Duration,
+    },
+    /// All figures were processed.
+    Finished { total: usize },
+}
+
+/// Limits for the size of the shared cache.
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    /// Entries that were not used for longer than this are removed.
+    pub max_age: Option<Duration>,
+    /// The least recently used entries are removed until the cache takes at
+    /// most this many bytes.
+    pub max_size: Option<u64>,
+}
+
+/// What was removed from the shared cache.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PruneStats {
+    /// The number of removed entries.
+    pub removed: usize,
+    /// The number of bytes that were freed.
+    pub freed: u64,
+}
+
+impl AddAssign for PruneStats {
+    fn add_assign(&mut self, other: Self) {
+        self.removed += other.removed;
+        self.freed += other.freed;
+    }
+}
+
+/// A callback that is notified about the progress of replacing figures.
+pub type ProgressCallback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+pub struct Tikz {
+    config: TikzConfig,
+    /// The hash of everything that affects all images in the same way.
+    fingerprint: u64,
+    /// The hash of the parts of the LaTeX document that all figures share.
+    document_fingerprint: u64,
+    /// The programs that jobs run.
+    toolchain: Vec<Tool>,
+    /// The engines that figures asked for besides those of the toolchain.
+    engines: Mutex<HashMap<String, Tool>>,
+    /// The figures that were already compiled again because of `force`.
+    forced: Mutex<HashSet<u64>>,
+    /// The images that were registered for figures with a `name`.
+    named: Mutex<HashMap<String, Vec<u8>>>,
+    /// The jobs of the replaced figures, if their PDFs or PNGs are exported,
+    /// by which `export` compiles those again whose files are missing, e.g.
+    /// since they were taken from the shared cache.
+    exported: Mutex<HashMap<u64, Job>>,
+    /// The figures that are compiled before all others, e.g. those that are
+    /// visible in an editor.
+    priority: Mutex<HashSet<u64>>,
+    /// The figures that each open session uses, by the key of the session.
+    sessions: Mutex<HashMap<u64, HashSet<u64>>>,
+    /// The key of the next session.
+    next_session: AtomicU64,
+    /// Whether figures are replaced with placeholders, since the toolchain
+    /// is missing.
+    placeholders: bool,
+    /// The factor by which the absolute widths of images are corrected.
+    calibration: f64,
+    /// The directory of the jobs, unless a renderer compiles the figures.
+    tempdir: Option<TempDir>,
+    /// Compiles the figures in place of the local LaTeX installation.
+    renderer: Option<Arc<dyn Renderer>>,
+    pool: Pool,
+    progress: Option<ProgressCallback>,
+}
+
+/// Measures how long something takes.
+///
+/// wasm32-unknown-unknown has no clock, and `Instant::now` panics there, so
+/// everything takes no time at all on it.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: Instant,
+}
+
+impl Stopwatch {
+    /// Start measuring.
+    pub fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: Instant::now(),
+        }
+    }
+
+    /// How much time passed since the start.
+    pub fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.start.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        Duration::ZERO
+    }
+}
+
+/// The width or the height of an SVG.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Dimension {
+    Width,
+    Height,
+}
+
+/// Determine a dimension of an SVG as a Typst length, scaled by `scale`,
+/// with absolute lengths also corrected by `calibration`. Both factors
+/// multiply, so absolute lengths are scaled by `scale * calibration`.
+fn svg_size(
+    image: &[u8],
+    dimension: Dimension,
+    scale: f64,
+    calibration: f64,
+) -> Result<Option<String>, String> {
+    Ok(svg_length(image, dimension)?.map(|(length, unit)| {
+        let factor =
+            if unit == "em" || unit == "%" { scale } else { scale * calibration };
+        format!("{}{}", length * factor, unit)
+    }))
+}
+
+/// Determine a dimension of an SVG as a number and a Typst unit.
+///
+/// Falls back to the view box if the SVG has no explicit width or height.
+/// Returns `Ok(None)` if neither is present.
+fn svg_length(
+    image: &[u8],
+    dimension: Dimension,
+) -> Result<Option<(f64, &'static str)>, String> {
+    let svg =
+        std::str::from_utf8(image).map_err(|_| "generated SVG is not valid UTF-8")?;
+    let metadata = Metadata::parse(svg).map_err(|_| "failed to parse generated SVG")?;
+    let explicit = match dimension {
+        Dimension::Width => metadata.width.map(|Width { width, unit }| (width, unit)),
+        Dimension::Height => {
+            metadata.height.map(|Height { height, unit }| (height, unit))
+        }
+    };
+
+    // Typst has no pixels or ex units, so these are approximated. One pixel
+    // (and one user unit) is 0.75pt, and an ex is assumed to be half an em,
+    // like browsers do whenever the x-height is unknown.
+    let (length, unit) = match explicit {
+        Some((length, unit)) => match unit {
+            Unit::Em => (length, "em"),
+            Unit::Ex => (length * EM_PER_EX, "em"),
+            Unit::Px => (length * PT_PER_PX, "pt"),
+            Unit::Pt => (length, "pt"),
+            Unit::Pc => (length * PT_PER_PC, "pt"),
+            Unit::Cm => (length, "cm"),
+            Unit::Mm => (length, "mm"),
+            Unit::In => (length, "in"),
+            Unit::Percent => (length, "%"),
+        },
+        None => match (metadata.view_box, dimension) {
+            (Some(view_box), Dimension::Width) => (view_box.width * PT_PER_PX, "pt"),
+            (Some(view_box), Dimension::Height) => (view_box.height * PT_PER_PX, "pt"),
+            (None, _) => return Ok(None),
+        },
+    };
+
+    Ok(Some((length, unit)))
+}
+
+/// A TikZ figure in a buffer.
+struct Block<'a> {
+    /// The byte range of the whole figure.
+    range: Range<usize>,
+    /// The LaTeX environment of the figure.
+    environment: &'a str,
+    /// The body of the figure, including the surrounding whitespace.
+    block: &'a str,
+    /// The TikZ code of the figure. It is generated for figures that are
+    /// imported from quiver.
+    tex_code: Cow<'a, str>,
+    /// The document class options of the figure itself.
+    options: Vec<String>,
+    /// The number of frames of an animated figure, which is one for other
+    /// figures.
+    frames: usize,
+    /// The LaTeX engine that the figure asks for in place of the configured
+    /// ones.
+    engine: Option<String>,
+    /// The language that the figure asks for in place of the configured one.
+    language: Option<String>,
+    /// The arrow preset that the figure asks for in place of the configured
+    /// one.
+    preset: Option<ArrowPreset>,
+    /// The background and margin that are added to the figure's SVGs.
+    frame: Frame,
+    /// Whether the figure is compiled again whenever it is replaced, instead
+    /// of being taken from a cache.
+    nocache: bool,
+    /// The name under which an image can be registered for the figure.
+    name: Option<String>,
+    /// The files that the figure references. Only filled in by `scan_in`.
+    inputs: Vec<InputFile>,
+    /// The CeTZ translation of the figure, once `Tikz::translate` tried it.
+    translation: OnceLock<Option<String>>,
+}
+
+/// The hash under which a page of a figure's image is stored. The first page
+/// is stored under the hash of the figure itself.
+pub(crate) fn page_hash(hash: u64, page: usize) -> u64 {
+    if page == 0 {
+        return hash;
+    }
+
+    let mut hasher = SipHasher13::new();
+    hash.hash(&mut hasher);
+    (page as u64).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Serialize a hash as a string.
+fn serialize_hash<S: Serializer>(hash: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(hash)
+}
+
+/// Serialize hashes as strings.
+fn serialize_hashes<S: Serializer>(
+    hashes: &[u64],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(hashes.iter().map(u64::to_string))
+}
+
+/// Serialize a duration as fractional seconds.
+pub(crate) fn serialize_seconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// The lines of LaTeX's output that matter most for a compact report, like
+/// an annotation in CI: The first error, which starts with `!`, up to the
+/// line of the document that LaTeX names for it, or the last lines of the
+/// output if it has no such error.
+pub fn excerpt(logs: &str) -> Vec<&str> {
+    let lines: Vec<&str> = logs.lines().collect();
+    match lines.iter().position(|line| line.starts_with('!')) {
+        Some(start) => {
+            let end = lines[start..]
+                .iter()
+                .position(|line| line.starts_with("l."))
+                .map_or(lines.len(), |offset| start + offset + 1);
+            lines[start..end.min(start + EXCERPT_LINES)].to_vec()
+        }
+        None => lines[lines.len().saturating_sub(EXCERPT_LINES)..].to_vec(),
+    }
+}
+
+/// Find the external files that TikZ code references.
+///
+/// pgfplots tables are only recognized if they are read from a file, i.e. if
+/// the braces contain no whitespace, unlike inline data.
+fn assets(tex_code: &str) -> Vec<PathBuf> {
+    REG_ASSET
+        .captures_iter(tex_code)
+        .filter_map(|capture| capture.name("path").or_else(|| capture.name("table")))
+        .map(|path| PathBuf::from(path.as_str().trim()))
+        .collect()
+}
+
+/// Read the files that TikZ code references, resolving relative paths against
+/// `dir`.
+///
+/// Like LaTeX, a path without an extension is also looked up with `.tex`.
+fn inputs(tex_code: &str, dir: &Path) -> Vec<InputFile> {
+    assets(tex_code)
+        .into_iter()
+        .map(|path| {
+            let candidates = match path.extension() {
+                Some(_) => vec![path],
+                None => vec![path.with_extension("tex"), path],
+            };
+
+            candidates
+                .iter()
+                .find_map(|path| {
+                    let content = fs::read(dir.join(path)).ok()?;
+                    Some(InputFile { path: path.clone(), content: Some(content) })
+                })
+                .unwrap_or_else(|| InputFile {
+                    path: candidates.last().unwrap().clone(),
+                    content: None,
+                })
+        })
+        .collect()
+}
+
+/// Read the files in a search path that LaTeX loads by name, i.e. style files
+/// and shared TeX code, sorted by their names.
+///
+/// Subdirectories are not read, even if the search path is recursive.
+fn search_path_files(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension().map_or(false, |ext| {
+                ["sty", "cls", "tex", "tikzstyles"].iter().any(|known| ext == *known)
+            })
+        })
+        .filter_map(|path| {
+            let content = fs::read(&path).ok()?;
+            Some((path, content))
+        })
+        .collect();
+
+    files.sort();
+    files
+}
+
+/// Split the lines that load packages and libraries off the top of TikZ
+/// code, since they belong into the preamble, and return them together with
+/// the rest of the code.
+///
+/// Blank lines and comments among them are dropped as well.
+fn hoist(tex_code: &str) -> (Vec<&str>, &str) {
+    let mut preamble = vec![];
+    let mut rest = tex_code;
+    while !rest.is_empty() {
+        let (line, next) = rest.split_once('\n').unwrap_or((rest, ""));
+        let trimmed = line.trim();
+        if REG_PREAMBLE.is_match(line) {
+            preamble.push(trimmed);
+        } else if !trimmed.is_empty() && !trimmed.starts_with('%') {
+            break;
+        }
+        rest = next;
+    }
+
+    (preamble, rest)
+}
+
+/// Translate the options of a figure, written like Typst arguments, into
+/// document class options.
+///
+/// `border: 2pt` becomes `border=2pt` and `varwidth: true` becomes `varwidth`.
+/// Quotes around values are removed, and arguments without a name are taken
+/// as they are.
+fn class_options(arguments: &str) -> Vec<String> {
+    arguments
+        .split(',')
+        .map(str::trim)
+        .filter(|argument| !argument.is_empty())
+        .map(|argument| {
+            let unquote = |text: &str| text.trim().trim_matches('"').to_string();
+            match argument.split_once(':') {
+                Some((key, value)) => match unquote(value).as_str() {
+                    "true" => unquote(key),
+                    value => format!("{}={}", unquote(key), value),
+                },
+                None => unquote(argument),
+            }
+        })
+        .collect()
+}
+
+/// Remove the option with the given key from the class options of a figure
+/// and return its value, if it has one.
+fn take_option(options: &mut Vec<String>, key: &str) -> Option<String> {
+    let prefix = format!("{}=", key);
+    let index = options.iter().position(|option| option.starts_with(&prefix))?;
+    Some(options.remove(index)[prefix.len()..].to_string())
+}
+
+/// Remove a boolean option, like `nocache: true`, from the class options of
+/// a figure and return whether it is set.
+fn take_flag(options: &mut Vec<String>, key: &str) -> bool {
+    match options.iter().position(|option| option == key) {
+        Some(index) => {
+            options.remove(index);
+            true
+        }
+        None => take_option(options, key).is_some_and(|value| value != "false"),
+    }
+}
+
+/// Strip each line of the code of a figure of its leading and trailing
+/// whitespace, and drop the empty lines at its start and end, so that
+/// reformatting a figure keeps its hash. Empty lines in between are kept,
+/// since they end paragraphs.
+fn normalize_whitespace(code: &str) -> String {
+    let lines: Vec<_> = code.lines().map(str::trim).collect();
+    let start = lines.iter().position(|line| !line.is_empty()).unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map_or(start, |end| end + 1);
+    lines[start..end].join("\n")
+}
+
+/// Remove the `frames` option from the class options of a figure and return
+/// the number of its frames.
+///
+/// Only TikZ environments can be animated, so a `displaymath` figure and an
+/// invalid number have a single frame.
+fn frames(options: &mut Vec<String>) -> usize {
+    take_option(options, "frames")
+        .and_then(|frames| frames.parse().ok())
+        .map_or(1, |frames: usize| frames.max(1))
+}
+
+/// Remove the `engine` option from the class options of a figure and return
+/// its value, or an error if it is not the name of a LaTeX engine.
+fn engine(options: &mut Vec<String>) -> Result<Option<String>, String> {
+    match take_option(options, "engine") {
+        Some(engine) if job::is_engine_name(&engine) => Ok(Some(engine)),
+        Some(engine) => Err(format!("{} is not a LaTeX engine", engine)),
+        None => Ok(None),
+    }
+}
+
+/// Remove the `preset` option from the class options of a figure and return
+/// the arrow preset it names, or an error if there is no such preset or the
+/// figure is not a `tikzcd` figure.
+fn preset(
+    options: &mut Vec<String>,
+    environment: &str,
+) -> Result<Option<ArrowPreset>, String> {
+    let preset = take_option(options, "preset")
+        .map(|preset| preset.parse())
+        .transpose()?;
+    if preset.is_some() && environment != "tikzcd" {
+        return Err(format!(
+            "arrow presets only apply to tikzcd figures, not to {}",
+            environment
+        ));
+    }
+    Ok(preset)
+}
+
+/// Remove the `fill` and `pad` options from the class options of a figure
+/// and return the frame they describe, or an error if the color is not a
+/// plain name or hex code, or the margin is not an absolute length.
+fn frame(options: &mut Vec<String>) -> Result<Frame, String> {
+    let fill = match take_option(options, "fill") {
+        Some(fill)
+            if !fill.is_empty()
+                && fill.chars().all(|c| c.is_ascii_alphanumeric() || c == '#') =>
+        {
+            Some(fill)
+        }
+        Some(fill) => return Err(format!("{} is not a color", fill)),
+        None => None,
+    };
+    let pad = match take_option(options, "pad") {
+        Some(pad) => match job::points(&pad) {
+            Some(points) if points >= 0.0 && pad.ends_with(char::is_alphabetic) => points,
+            _ => return Err(format!("{} is not a length", pad)),
+        },
+        None => 0.0,
+    };
+
+    Ok(Frame { fill, pad })
+}
+
+/// Find all TikZ figures in a buffer.
+///
+/// A `tikzcd` figure with a `url` option is imported from the quiver share
+/// link in it, and its body must be empty. A figure with an `engine` option,
+/// like `engine: "xelatex"`, is compiled by that engine alone. A figure with
+/// `nocache: true` is compiled again on every replacement, for figures that
+/// depend on something their hash cannot see, like the current date. A
+/// figure with a `language` option, like `language: "german"`, is set in
+/// that language. A `tikzcd` figure with a `preset` option, like
+/// `preset: "quiver"`, draws its arrows in that style. A figure with `fill`
+/// or `pad` options, like `fill: white, pad: 4pt`, gets a background and a
+/// margin once it is compiled. Other figures without code are ignored. A
+/// link that cannot be decoded, or an invalid option, becomes a LaTeX error,
+/// so that it is reported like any other error in the figure.
+fn scan(buffer: &str) -> Vec<Block<'_>> {
+    let found: Vec<_> = REG_TIKZ
+        .captures_iter(buffer)
+        .filter_map(|capture| {
+            let environment = capture.name("environment").unwrap().as_str();
+            let mut options =
+                capture.name("options").map_or(vec![], |m| class_options(m.as_str()));
+            let url = take_option(&mut options, "url");
+            let frames = frames(&mut options);
+            let engine = engine(&mut options);
+            let nocache = take_flag(&mut options, "nocache");
+            let name = take_option(&mut options, "name");
+            let language = take_option(&mut options, "language");
+            let frame = frame(&mut options);
+            let preset = preset(&mut options, environment);
+
+            let mut tex_code = match (capture.name("tex_code"), url) {
+                (Some(code), None) => Cow::Borrowed(code.as_str()),
+                (None, Some(url)) if environment == "tikzcd" => Cow::Owned(
+                    quiver::tikzcd(&url)
+                        .unwrap_or_else(|message| package_error(&message)),
+                ),
+                _ => return None,
+            };
+
+            let engine = match engine {
+                Ok(engine) => engine,
+                Err(message) => {
+                    tex_code = package_error(&message).into();
+                    None
+                }
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(message) => {
+                    tex_code = package_error(&message).into();
+                    Frame::default()
+                }
+            };
+            let preset = match preset {
+                Ok(preset) => preset,
+                Err(message) => {
+                    tex_code = package_error(&message).into();
+                    None
+                }
+            };
+
+            Some(Block {
+                range: capture.get(0).unwrap().range(),
+                environment,
+                block: capture.name("block").unwrap().as_str(),
+                tex_code,
+                options,
+                frames,
+                engine,
+                language,
+                preset,
+                frame,
+                nocache,
+                name,
+                inputs: vec![],
+                translation: OnceLock::new(),
+            })
+        })
+        .collect();
+
+    trace_event!(info, "Found {} TikZ figures", found.len());
+    found
+}
+
+/// The LaTeX code that fails a figure with the given message, so that it is
+/// reported like any other error in the figure.
+fn package_error(message: &str) -> String {
+    format!("\\PackageError{{typst-tikz}}{{{}}}{{}}", message)
+}
+
+/// Find all TikZ figures in a buffer together with the files they reference,
+/// resolved against `dir`.
+fn scan_in<'a>(buffer: &'a str, dir: &Path) -> Vec<Block<'a>> {
+    let mut found = scan(buffer);
+    for block in &mut found {
+        block.inputs = inputs(&block.tex_code, dir);
+    }
+    found
+}
+
+impl Tikz {
+    pub fn new(config: TikzConfig) -> std::io::Result<Self> {
+        if let Some(_url) = &config.remote {
+            #[cfg(feature = "remote")]
+            return Self::with_renderer(config.clone(), RemoteRenderer::connect(_url)?);
+
+            #[cfg(not(feature = "remote"))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compiling TikZ figures on a server requires the remote feature",
+            ));
+        }
+
+        Self::build(config, None)
+    }
+
+    /// Create a TikZ compiler that delegates the compilation of figures to a
+    /// renderer instead of running LaTeX itself.
+    ///
+    /// This needs neither a LaTeX installation nor a temporary directory, so
+    /// it also works on `wasm32`, where the renderer would ask the host to
+    /// compile the figures. The options that configure the local programs,
+    /// like the engines, are ignored.
+    pub fn with_renderer(
+        config: TikzConfig,
+        renderer: impl Renderer + 'static,
+    ) -> std::io::Result<Self> {
+        Self::build(config, Some(Arc::new(renderer)))
+    }
+
+    /// Create a TikZ compiler with an optional renderer.
+    fn build(
+        mut config: TikzConfig,
+        renderer: Option<Arc<dyn Renderer>>,
+    ) -> std::io::Result<Self> {
+        if config.filename.matches(HASH_PLACEHOLDER).count() != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the TikZ filename must contain {hash} exactly once",
+            ));
+        }
+
+        if let Some(template) = &config.template {
+            if !template.contains("{filename}") {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the TikZ template must contain {filename}",
+                ));
+            }
+        }
+
+        if config.engines.is_empty() && renderer.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "at least one LaTeX engine must be configured for TikZ figures",
+            ));
+        }
+
+        // Renderers always produce SVGs their own way.
+        if renderer.is_some() {
+            config.pipeline = Pipeline::Pdf;
+        }
+
+        // Exported PDFs and PNGs are those that LaTeX produces.
+        if config.pipeline == Pipeline::Dvi && export::needs_pdf(&config.export_formats) {
+            trace_event!(
+                warn,
+                "TikZ figures go through PDFs, since they are exported as such"
+            );
+            config.pipeline = Pipeline::Pdf;
+        }
+
+        let dvi = config.pipeline == Pipeline::Dvi;
+        let converter = match &config.converter {
+            Some(converter) => converter.as_str(),
+            None => job::default_converter(config.outline_text || dvi),
+        };
+
+        if config.outline_text && !job::is_dvisvgm(Path::new(converter)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "outlining the text of TikZ figures requires dvisvgm as the converter",
+            ));
+        }
+
+        if dvi && !job::is_dvisvgm(Path::new(converter)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the DVI pipeline for TikZ figures requires dvisvgm as the converter",
+            ));
+        }
+
+        let mut toolchain = match renderer {
+            Some(_) => vec![],
+            None => job::toolchain(&config.engines, converter),
+        };
+
+        // Without dvisvgm, the PDF pipeline works with pdf2svg instead, unless
+        // dvisvgm is needed for other reasons.
+        let missing = toolchain.last().map_or(false, |tool| tool.version.is_none());
+        if dvi && missing && config.converter.is_none() && !config.outline_text {
+            trace_event!(warn, "dvisvgm is missing, so TikZ figures go through PDFs");
+            config.pipeline = Pipeline::Pdf;
+            toolchain = job::toolchain(&config.engines, job::default_converter(false));
+        }
+
+        // LaTeX runs in the job directory, so relative paths would break.
+        if !config.search_paths.is_empty() {
+            let cwd = std::env::current_dir()?;
+            for path in &mut config.search_paths {
+                *path = cwd.join(&*path);
+            }
+        }
+
+        VERBOSITY.store(config.verbosity as u8, Ordering::Relaxed);
+
+        if let Some(dir) = &mut config.keep_intermediates {
+            *dir = std::env::current_dir()?.join(&*dir);
+            fs::create_dir_all(dir)?;
+        }
+
+        let tempdir = match renderer {
+            Some(_) => None,
+            None => {
+                let tempdir = tempfile::tempdir()?;
+                let mut file = File::create(tempdir.path().join("config.lua"))?;
+                writeln!(file, "{}", LUA_CONFIG)?;
+                Some(tempdir)
+            }
+        };
+
+        // Without a usable shared cache, figures are still cached in memory.
+        let cache = config.cache_dir.clone().and_then(|dir| match Cache::open(dir) {
+            Ok(cache) => Some(cache),
+            Err(_err) => {
+                trace_event!(warn, "Failed to open TikZ cache: {}", _err);
+                None
+            }
+        });
+
+        // Without TeX, figures are looked up under the toolchain that compiled
+        // an imported cache archive, so that they need not be compiled.
+        let installed =
+            toolchain.iter().any(|tool| tool.engine && tool.version.is_some());
+        if let Some(imported) = cache
+            .as_ref()
+            .filter(|_| renderer.is_none() && !installed)
+            .and_then(Cache::toolchain)
+        {
+            trace_event!(info, "Using the toolchain of the imported TikZ cache");
+            toolchain = imported;
+        }
+
+        let mut tikz = Self {
+            pool: Pool::new(config.jobs, cache),
+            toolchain,
+            engines: Mutex::new(HashMap::new()),
+            forced: Mutex::new(HashSet::new()),
+            named: Mutex::new(HashMap::new()),
+            exported: Mutex::new(HashMap::new()),
+            priority: Mutex::new(HashSet::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(0),
+            placeholders: false,
+            calibration: 1.0,
+            config,
+            fingerprint: 0,
+            document_fingerprint: 0,
+            tempdir,
+            renderer,
+            progress: None,
+        };
+
+        if tikz.config.placeholders && tikz.renderer.is_none() && tikz.verify().is_err() {
+            trace_event!(
+                warn,
+                "TeX is missing, so TikZ figures are shown as placeholders"
+            );
+            tikz.placeholders = true;
+        }
+
+        tikz.fingerprint = tikz.shared_digest();
+        tikz.document_fingerprint = tikz.document_digest();
+        tikz.calibration = match tikz.config.calibration {
+            Calibration::Factor(factor) => factor,
+            Calibration::Auto if tikz.placeholders => 1.0,
+            Calibration::Auto => tikz.calibrate().unwrap_or_else(|_err| {
+                trace_event!(warn, "Failed to calibrate TikZ figures: {}", _err);
+                1.0
+            }),
+        };
+        Ok(tikz)
+    }
+
+    /// Check that the programs needed to compile figures are installed.
+    ///
+    /// Construction succeeds without them, since listing figures and managing
+    /// the cache need no LaTeX installation. Call this before compiling to
+    /// fail early with a helpful error instead of failing on every figure.
+    /// Of several configured engines, only one needs to be installed.
+    pub fn verify(&self) -> Result<(), MissingPrograms> {
+        let engines = self.toolchain.iter().filter(|tool| tool.engine);
+        let mut missing = vec![];
+        if engines.clone().all(|tool| tool.version.is_none()) {
+            missing.extend(engines);
+        }
+
+        missing.extend(
+            self.toolchain
+                .iter()
+                .filter(|tool| !tool.engine && tool.version.is_none()),
+        );
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(MissingPrograms {
+            programs: missing
+                .into_iter()
+                .map(|tool| {
+                    (tool.program.clone(), job::install_hint(&tool.program).into())
+                })
+                .collect(),
+        })
+    }
+
+    /// Register a callback that is notified about the progress of `replace`,
+    /// e.g. to display a progress bar. Replaces any previous callback.
+    pub fn on_progress(&mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) {
+        self.progress = Some(Box::new(callback));
+    }
+
+    /// Notify the progress callback, if there is one.
+    fn notify(&self, progress: Progress) {
+        if let Some(callback) = &self.progress {
+            callback(&progress);
+        }
+    }
+
+    /// Render a single figure, e.g. to preview the figure under the cursor in
+    /// an editor, without rewriting any buffer.
+    ///
+    /// The options are those that follow the environment in a buffer, like
+    /// `border: 2pt`, and may be empty. Files that the figure references are
+    /// resolved against the working directory. The figure is cached like any
+    /// other, so rendering it again, or replacing a buffer that contains it,
+    /// is cheap.
+    pub fn render_block(
+        &self,
+        environment: &str,
+        code: &str,
+        options: &str,
+    ) -> Result<Svg, TikzError> {
+        if !matches!(environment, "tikzpicture" | "tikzcd" | "displaymath") {
+            return Err(TikzError::UnknownEnvironment(environment.into()));
+        }
+
+        let mut options = class_options(options);
+        let block = Block {
+            range: 0..0,
+            environment,
+            block: code,
+            tex_code: Cow::Borrowed(code),
+            frames: frames(&mut options),
+            engine: engine(&mut options).map_err(TikzError::Compilation)?,
+            language: take_option(&mut options, "language"),
+            preset: preset(&mut options, environment).map_err(TikzError::Compilation)?,
+            frame: frame(&mut options).map_err(TikzError::Compilation)?,
+            nocache: take_flag(&mut options, "nocache"),
+            name: take_option(&mut options, "name"),
+            options,
+            inputs: inputs(code, Path::new("")),
+            translation: OnceLock::new(),
+        };
+
+        let hash = self.digest(&block);
+        self.refresh(std::slice::from_ref(&block), &[hash]);
+        let image = match self.pool.get(hash) {
+            Some(image) => image,
+            None => self.pool.compile(&self.job(hash, &block)),
+        };
+
+        let data = image.map_err(TikzError::Compilation)?;
+        let width = self.width(&data).map_err(TikzError::Unembeddable)?;
+        Ok(Svg { hash, data, width })
+    }
+
+    /// Returns the result of compiling the figure with the given hash.
+    ///
+    /// Returns `None` if no such figure was compiled, e.g. because the hash
+    /// stems from a stale filename.
+    pub fn fetch(&self, hash: u64) -> Option<Result<Vec<u8>, String>> {
+        self.pool.get(hash)
+    }
+
+    /// Returns the image of the figure with the given hash, like `fetch`, but
+    /// with a dedicated error for hashes of unknown figures, e.g. from a
+    /// stale or hand-written filename.
+    ///
+    /// A figure whose image is gone from the shared cache, e.g. after the
+    /// cache was pruned, is compiled again from the LaTeX document and engine
+    /// that the cache keeps for it. Files that the figure referenced are not
+    /// kept, so such a figure may fail to compile.
+    pub fn resolve(&self, hash: u64) -> Result<Vec<u8>, TikzError> {
+        if let Some(image) = self.pool.get(hash) {
+            return image.map_err(TikzError::Compilation);
+        }
+
+        let Some(cache) = self.pool.cache() else {
+            return Err(TikzError::UnknownFigure(hash));
+        };
+        let Some(document) = cache.source(hash) else {
+            return Err(TikzError::UnknownFigure(hash));
+        };
+
+        trace_event!(info, "Compiling TikZ figure {} again from its cached source", hash);
+        let gnuplot = REG_GNUPLOT.is_match(&document);
+        let job = self.document_job(hash, document, vec![], cache.engine(hash), gnuplot);
+        self.pool.compile(&job).map_err(TikzError::Compilation)
+    }
+
+    /// The configuration of the compiler.
+    pub fn config(&self) -> &TikzConfig {
+        &self.config
+    }
+
+    /// Whether a figure with the given hash was compiled.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.pool.contains(hash)
+    }
+
+    /// Use the given SVG as the image of a figure instead of compiling it,
+    /// e.g. one that was touched up in Inkscape.
+    ///
+    /// The figure is identified by its hash, or by its `name` option, which
+    /// applies to all figures with that name from then on. The image is kept
+    /// in memory for the lifetime of the compiler, not in the shared cache,
+    /// and takes precedence over `nocache` and `force`.
+    pub fn register_image(&self, key: impl Into<ImageKey>, svg: Vec<u8>) {
+        match key.into() {
+            ImageKey::Hash(hash) => {
+                trace_event!(info, "Registered an image for TikZ figure {}", hash);
+                self.pool.pin(hash, &svg);
+            }
+            ImageKey::Name(name) => {
+                trace_event!(info, "Registered an image for TikZ figure {}", name);
+                self.named.lock().unwrap().insert(name, svg);
+            }
+        }
+    }
+
+    /// List all TikZ figures in a buffer without compiling any of them.
+    ///
+    /// Files that the figures reference are resolved against `dir`, like in
+    /// `replace_in`.
+    pub fn list(&self, buffer: &str, dir: &Path) -> Vec<ScannedBlock> {
+        scan_in(buffer, dir)
+            .into_iter()
+            .map(|block| {
+                let hash = self.digest(&block);
+                ScannedBlock {
+                    hash,
+                    environment: block.environment.into(),
+                    range: block.range,
+                    cached: self.contains(hash),
+                }
+            })
+            .collect()
+    }
+
+    /// The hashes of all TikZ figures in a buffer, e.g. to determine which
+    /// entries to keep when pruning the cache.
+    ///
+    /// Files that the figures reference are resolved against `dir`, like in
+    /// `replace_in`.
+    pub fn hashes(&self, buffer: &str, dir: &Path) -> Vec<u64> {
+        self.digests(&scan_in(buffer, dir))
+    }
+
+    /// Remove all figures that are not kept from the memory and from the
+    /// shared cache.
+    ///
+    /// Does nothing to the shared cache if there is none. The default cache
+    /// directory is shared by all projects, so the figures of the others
+    /// would be removed as well. Pruning it fails unless `force` is set;
+    /// projects that prune should configure a `cache_dir` of their own.
+    pub fn prune(&self, keep: &[u64], force: bool) -> std::io::Result<PruneStats> {
+        let shared = self.config.cache_dir.is_some()
+            && self.config.cache_dir == default_cache_dir();
+        if shared && !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "refusing to prune the default TikZ cache, which all projects share",
+            ));
+        }
+
+        let keep = keep.iter().flat_map(|&hash| self.pool.pages(hash)).collect();
+        self.pool.retain(&keep);
+        match self.pool.cache() {
+            Some(cache) => cache.prune(&keep),
+            None => Ok(PruneStats::default()),
+        }
+    }
+
+    /// Remove figures from the shared cache that exceed the limits of a
+    /// policy.
+    ///
+    /// Does nothing if there is no shared cache.
+    pub fn collect_garbage(&self, policy: &CachePolicy) -> std::io::Result<PruneStats> {
+        match self.pool.cache() {
+            Some(cache) => cache.collect_garbage(policy),
+            None => Ok(PruneStats::default()),
+        }
+    }
+
+    /// Write the shared cache into a zstd-compressed tarball, e.g. so that
+    /// machines without TeX can reuse the figures that CI compiled. Returns
+    /// the number of SVGs that were written.
+    #[cfg(feature = "archive")]
+    pub fn export_cache(&self, writer: impl Write) -> std::io::Result<usize> {
+        self.shared_cache()?.export(writer, &self.toolchain)
+    }
+
+    /// Add the SVGs in a tarball that `export_cache` wrote to the shared
+    /// cache. Returns the number of SVGs that were added.
+    ///
+    /// A compiler without TeX that is created afterwards finds the figures
+    /// under the toolchain that compiled them. Figures that are not in the
+    /// archive then fail to compile.
+    #[cfg(feature = "archive")]
+    pub fn import_cache(&self, reader: impl std::io::Read) -> std::io::Result<usize> {
+        self.shared_cache()?.import(reader)
+    }
+
+    /// The shared cache, or an error if there is none.
+    #[cfg(feature = "archive")]
+    fn shared_cache(&self) -> std::io::Result<&Cache> {
+        self.pool.cache().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "there is no shared cache")
+        })
+    }
+
+    /// Remove all compiled figures from the memory, the shared cache and the
+    /// temporary directory, e.g. after changes to the LaTeX installation that
+    /// the hashes of the figures do not reflect.
+    ///
+    /// The returned statistics count the files that were removed.
+    pub fn clear_cache(&self) -> std::io::Result<PruneStats> {
+        self.pool.retain(&HashSet::new());
+
+        let mut stats = match self.pool.cache() {
+            Some(cache) => cache.clear()?,
+            None => PruneStats::default(),
+        };
+
+        // Each job has its own directory, next to the Lua configuration.
+        let Some(tempdir) = &self.tempdir else { return Ok(stats) };
+        for item in fs::read_dir(tempdir.path())? {
+            let path = item?.path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            for entry in WalkDir::new(&path).into_iter().flatten() {
+                if entry.file_type().is_file() {
+                    stats.removed += 1;
+                    stats.freed += entry.metadata().map_or(0, |metadata| metadata.len());
+                }
+            }
+
+            fs::remove_dir_all(path)?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Replace all TikZ figures in a buffer with their compiled images.
+    ///
+    /// Figures that fail to compile are still replaced with an image, which
+    /// then fails to load. Figures whose images cannot be embedded are
+    /// replaced with a placeholder and reported in the diagnostics.
+    ///
+    /// New figures are compiled in parallel, on at most `jobs` threads. The
+    /// same figure is only compiled once, even if it is requested by several
+    /// concurrent calls.
+    ///
+    /// Files that the figures reference are resolved against the working
+    /// directory. Use `replace_in` to resolve them against the directory of
+    /// the buffer's file instead.
+    pub fn replace(&self, buffer: &str) -> Replacement {
+        self.replace_in(buffer, Path::new(""))
+    }
+
+    /// Like `replace`, but resolves relative paths in `\input`, pgfplots
+    /// tables and the like against `dir`.
+    ///
+    /// The referenced files are read relative to `dir`, and their contents
+    /// are part of the figure's hash, so that it is compiled again when they
+    /// change. Relative paths without `..` are copied next to the LaTeX
+    /// document of the figure. Absolute paths are left to LaTeX, which reads
+    /// them directly, and paths with `..` are not copied, so LaTeX, which
+    /// runs in a directory of its own, does not find them.
+    pub fn replace_in(&self, buffer: &str, dir: &Path) -> Replacement {
+        self.replace_found(buffer, scan_in(buffer, dir))
+    }
+
+    /// Like `replace_in`, but writes the rewritten buffer to `out` piece by
+    /// piece while the figures are replaced, instead of building it in
+    /// memory, e.g. for large generated documents.
+    ///
+    /// This saves the copy of the buffer, not the scan: the buffer is still
+    /// searched for all figures before the first piece is written, since
+    /// they are hashed and compiled in parallel up front. The figures borrow
+    /// their code from the buffer, so only their options are copied.
+    ///
+    /// The `text` of the returned replacement is empty. Fails if writing to
+    /// `out` fails, in which case the remaining figures are not replaced.
+    pub fn replace_into(
+        &self,
+        buffer: &str,
+        dir: &Path,
+        out: &mut impl Write,
+    ) -> std::io::Result<Replacement> {
+        self.stream_in(buffer, dir, &mut |chunk| out.write_all(chunk.as_bytes()))
+    }
+
+    /// Like `replace_in`, but only replaces the figures in the parts of the
+    /// buffer that changed since its `previous` version was `replaced`, e.g.
+    /// for a watcher or an editor that rewrites a large document after every
+    /// edit.
+    ///
+    /// Figures before and after the edited part are taken over from the
+    /// previous replacement as they are, unless they reference files, which
+    /// may have changed in the meantime. The previous replacement must contain
+    /// its text, i.e. it must not come from `replace_into`.
+    pub fn replace_incremental(
+        &self,
+        previous: &str,
+        replaced: &Replacement,
+        buffer: &str,
+        dir: &Path,
+    ) -> IncrementalReplacement {
+        // The edited part is what lies between the common prefix and suffix.
+        let (old, new) = (previous.as_bytes(), buffer.as_bytes());
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (edited, moved) = (buffer.len() - suffix, previous.len() - suffix);
+
+        // The metadata of figures after the edit contains their lines, which
+        // change if the edit adds or removes lines.
+        let count = |text: &[u8]| text.iter().filter(|&&byte| byte == b'\n').count();
+        let shifted = self.config.metadata
+            && count(&old[prefix..moved]) != count(&new[prefix..edited]);
+
+        // The range of a figure in the previous buffer, if it is unchanged.
+        let unchanged = |range: &Range<usize>| {
+            if range.end <= prefix {
+                Some(range.clone())
+            } else if range.start >= edited && !shifted {
+                Some(range.start - edited + moved..range.end - edited + moved)
+            } else {
+                None
+            }
+        };
+
+        let regions = &replaced.source_map.regions;
+        let mut reused = vec![];
+        let mut affected = vec![];
+        for mut block in scan(buffer) {
+            let index =
+                unchanged(&block.range).filter(|_| !block.nocache).and_then(|range| {
+                    regions
+                        .binary_search_by_key(&range.start, |region| {
+                            region.original.start
+                        })
+                        .ok()
+                        .filter(|&index| regions[index].original == range)
+                        .filter(|&index| replaced.report.blocks[index].assets.is_empty())
+                });
+
+            match index {
+                Some(index) => reused.push((block.range, index)),
+                None => {
+                    block.inputs = inputs(&block.tex_code, dir);
+                    affected.push(block);
+                }
+            }
+        }
+
+        trace_event!(
+            info,
+            "Reusing {} TikZ figures and replacing {} anew",
+            reused.len(),
+            affected.len()
+        );
+
+        let mut text = String::new();
+        let partial = self
+            .stream_found(buffer, affected, &mut |chunk| {
+                text.push_str(chunk);
+                Ok(())
+            })
+            .expect("writing to a string cannot fail");
+
+        let mut affected: HashSet<_> =
+            partial.report.blocks.iter().map(|report| report.hash).collect();
+        let kept: HashSet<_> = reused.iter().map(|&(_, index)| index).collect();
+        affected.extend(
+            replaced
+                .report
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !kept.contains(index))
+                .map(|(_, report)| report.hash),
+        );
+
+        // Merge the figures that were replaced anew with the reused ones.
+        let mut figures: Vec<_> = partial
+            .source_map
+            .regions
+            .iter()
+            .zip(partial.report.blocks)
+            .map(|(region, report)| (&text[region.rewritten.clone()], report))
+            .chain(reused.iter().map(|(range, index)| {
+                let (region, report) =
+                    (&regions[*index], &replaced.report.blocks[*index]);
+                let report = BlockReport {
+                    cached: true,
+                    duration: Duration::ZERO,
+                    range: range.clone(),
+                    timings: None,
+                    ..report.clone()
+                };
+                (&replaced.text[region.rewritten.clone()], report)
+            }))
+            .collect();
+        figures.sort_by_key(|(_, report)| report.range.start);
+
+        let mut diagnostics = partial.diagnostics;
+        for (range, index) in &reused {
+            let original = &regions[*index].original;
+            diagnostics.extend(
+                replaced
+                    .diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.range == *original)
+                    .map(|diagnostic| BlockDiagnostic {
+                        range: range.clone(),
+                        ..diagnostic.clone()
+                    }),
+            );
+        }
+        diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+
+        let mut output = String::with_capacity(buffer.len());
+        let mut regions = vec![];
+        let mut blocks = vec![];
+        for (replacement, report) in figures {
+            let previous =
+                regions.last().map_or(0, |region: &Region| region.original.end);
+            output.push_str(&buffer[previous..report.range.start]);
+            let rewritten = output.len()..output.len() + replacement.len();
+            output.push_str(replacement);
+            regions.push(Region { rewritten, original: report.range.clone() });
+            blocks.push(report);
+        }
+        let previous = regions.last().map_or(0, |region| region.original.end);
+        output.push_str(&buffer[previous..]);
+
+        IncrementalReplacement {
+            replacement: Replacement {
+                text: output,
+                diagnostics,
+                report: ReplaceReport { blocks },
+                source_map: SourceMap { regions },
+            },
+            affected,
+        }
+    }
+
+    /// Replace the figures in a buffer, passing the rewritten buffer to
+    /// `emit` in order.
+    fn stream_in(
+        &self,
+        buffer: &str,
+        dir: &Path,
+        emit: &mut dyn FnMut(&str) -> std::io::Result<()>,
+    ) -> std::io::Result<Replacement> {
+        self.stream_found(buffer, scan_in(buffer, dir), emit)
+    }
+
+    /// Replace the given figures of a buffer, passing the rewritten buffer to
+    /// `emit` in order.
+    fn stream_found(
+        &self,
+        buffer: &str,
+        found: Vec<Block>,
+        emit: &mut dyn FnMut(&str) -> std::io::Result<()>,
+    ) -> std::io::Result<Replacement> {
+        let hashes = self.digests(&found);
+        self.refresh(&found, &hashes);
+        let cached = self.cached(&hashes);
+        let jobs = self.jobs(&found, &hashes, &cached);
+
+        // With a single job at a time, the figures are compiled one by one
+        // during the replacement, without any extra threads. This also keeps
+        // the replacement working on platforms without threads.
+        let workers = match self.config.jobs {
+            0 | 1 => 0,
+            limit => limit.min(jobs.len()),
+        };
+        let queue = Mutex::new(jobs.into_iter());
+
+        // The workers compile the figures ahead of time, while the figures are
+        // replaced in order. A figure that no worker started yet is compiled
+        // right away, so the pool's limit applies to both.
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Some(job) = queue.lock().unwrap().next() else { break };
+                    self.pool.compile(&job);
+                });
+            }
+
+            self.replace_with(buffer, found, &hashes, &cached, emit)
+        })
+    }
+
+    /// Compile the figures with the given hashes before all others whenever
+    /// several new figures are compiled, e.g. those that are visible in an
+    /// editor, so that a preview updates where the user is looking first.
+    ///
+    /// The hashes replace those of earlier calls. Figures are still replaced
+    /// in order, so this only changes which figures the workers start with.
+    pub fn prioritize(&self, hashes: impl IntoIterator<Item = u64>) {
+        *self.priority.lock().unwrap() = hashes.into_iter().collect();
+    }
+
+    /// Compile all figures in a buffer that are not compiled yet, without
+    /// replacing them, and return their hashes.
+    ///
+    /// This warms the cache, so that a later `replace` of the same figures
+    /// is fast. Call it on another thread to start long compilations early,
+    /// e.g. while the user is still editing. A figure that `replace` needs
+    /// while it is being precompiled is waited for instead of compiled twice.
+    /// New figures are compiled in parallel, on at most `jobs` threads.
+    pub fn precompile(&self, buffer: &str) -> Vec<u64> {
+        self.precompile_in(buffer, Path::new(""))
+    }
+
+    /// Like `precompile`, but resolves relative paths against `dir`, like
+    /// `replace_in`.
+    pub fn precompile_in(&self, buffer: &str, dir: &Path) -> Vec<u64> {
+        let found = scan_in(buffer, dir);
+        let hashes = self.digests(&found);
+        self.refresh(&found, &hashes);
+        let cached = self.cached(&hashes);
+        self.compile_jobs(self.jobs(&found, &hashes, &cached), false);
+        hashes
+    }
+
+    /// Like `replace_in`, but reports the figures that fail to compile
+    /// according to the failure policy, together with their LaTeX logs.
+    ///
+    /// With `FailurePolicy::Defer`, this never fails. With `FailFast`, no new
+    /// figures are compiled after the first failure, and only that failure is
+    /// reported. With `CollectAll`, all figures are compiled and every
+    /// failure is reported.
+    pub fn try_replace_in(
+        &self,
+        buffer: &str,
+        dir: &Path,
+    ) -> Result<Replacement, Vec<BlockDiagnostic>> {
+        let found = scan_in(buffer, dir);
+        let replaced = match self.config.failure_policy {
+            FailurePolicy::Defer => return Ok(self.replace_found(buffer, found)),
+            FailurePolicy::FailFast => {
+                let hashes = self.digests(&found);
+                self.refresh(&found, &hashes);
+                let cached = self.cached(&hashes);
+                if self.compile_jobs(self.jobs(&found, &hashes, &cached), true) {
+                    // The first failure in the document is the one to fix first.
+                    let failure = found.iter().zip(&hashes).find_map(|(block, &hash)| {
+                        let Some(Err(logs)) = self.pool.get(hash) else { return None };
+                        let range = block.range.clone();
+                        Some(BlockDiagnostic {
+                            hash,
+                            range,
+                            stage: Stage::Compilation,
+                            message: logs,
+                        })
+                    });
+                    return Err(failure.into_iter().collect());
+                }
+
+                self.replace_found(buffer, found)
+            }
+            FailurePolicy::CollectAll => self.replace_found(buffer, found),
+        };
+
+        let errors = self.errors(&replaced.report);
+        if errors.is_empty() {
+            Ok(replaced)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The figures of a replacement that failed to compile, with their LaTeX
+    /// logs.
+    pub fn errors(&self, report: &ReplaceReport) -> Vec<BlockDiagnostic> {
+        report
+            .blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::Failed)
+            .map(|block| BlockDiagnostic {
+                hash: block.hash,
+                range: block.range.clone(),
+                stage: Stage::Compilation,
+                message: match self.fetch(block.hash) {
+                    Some(Err(logs)) => logs,
+                    _ => "failed to compile TikZ figure".into(),
+                },
+            })
+            .collect()
+    }
+
+    /// Replace the given figures of a buffer into a new string.
+    fn replace_found(&self, buffer: &str, found: Vec<Block>) -> Replacement {
+        let mut text = String::with_capacity(buffer.len());
+        let replaced = self.stream_found(buffer, found, &mut |chunk| {
+            text.push_str(chunk);
+            Ok(())
+        });
+
+        Replacement {
+            text,
+            ..replaced.expect("writing to a string cannot fail")
+        }
+    }
+
+    /// Compile jobs in parallel, on at most `jobs` threads, and return
+    /// whether any of them failed. With `fail_fast`, no new jobs are started
+    /// after a failure.
+    fn compile_jobs(&self, jobs: Vec<Job>, fail_fast: bool) -> bool {
+        let failed = AtomicBool::new(false);
+        let work = |job: &Job| {
+            if self.pool.compile(job).is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            !(fail_fast && failed.load(Ordering::Relaxed))
+        };
+
+        let workers = self.config.jobs.clamp(1, jobs.len().max(1));
+        if workers == 1 {
+            for job in &jobs {
+                if !work(job) {
+                    break;
+                }
+            }
+            return failed.into_inner();
+        }
+
+        let queue = Mutex::new(jobs.into_iter());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Some(job) = queue.lock().unwrap().next() else { break };
+                    if !work(&job) {
+                        break;
+                    }
+                });
+            }
+        });
+
+        failed.into_inner()
+    }
+
+    /// Make sure that the figures with `nocache`, and with `force` all figures
+    /// that were not compiled again yet, are compiled anew, unless an image
+    /// was registered for them.
+    fn refresh(&self, found: &[Block], hashes: &[u64]) {
+        let mut forced = self.forced.lock().unwrap();
+        let stale: Vec<_> = found
+            .iter()
+            .zip(hashes)
+            .filter(|&(block, &hash)| {
+                block.nocache || (self.config.force && forced.insert(hash))
+            })
+            .map(|(_, &hash)| hash)
+            .collect();
+        drop(forced);
+
+        if !stale.is_empty() {
+            trace_event!(info, "Compiling {} TikZ figures again", stale.len());
+            self.pool.refresh(stale);
+        }
+
+        // Registered images replace those of the figures with their names.
+        let named = self.named.lock().unwrap();
+        for (block, &hash) in found.iter().zip(hashes) {
+            if let Some(svg) = block.name.as_ref().and_then(|name| named.get(name)) {
+                self.pool.pin(hash, svg);
+            }
+        }
+    }
+
+    /// The jobs of the figures that are neither compiled nor translated,
+    /// each figure only once, with the prioritized figures first.
+    fn jobs(&self, found: &[Block], hashes: &[u64], cached: &HashSet<u64>) -> Vec<Job> {
+        let mut queued = HashSet::new();
+        let mut jobs: Vec<_> = found
+            .iter()
+            .zip(hashes)
+            .filter(|&(_, &hash)| !cached.contains(&hash) && queued.insert(hash))
+            .filter(|&(block, _)| self.translate(block).is_none())
+            .map(|(block, &hash)| self.job(hash, block))
+            .collect();
+
+        let priority = self.priority.lock().unwrap();
+        jobs.sort_by_key(|job| !priority.contains(&job.hash));
+        jobs
+    }
+
+    /// Replace the figures in a Typst file and write the result to `output`.
+    ///
+    /// Referenced files are resolved against the directory of `input`. The
+    /// output is written to a temporary file first, synced to disk and then
+    /// renamed, so it is never left half-written. It keeps the permissions of
+    /// the file it replaces, or of `input` if there was none. Without an
+    /// `output`, the input is replaced in place after it was copied next to
+    /// itself with an extra `.bak` extension.
+    ///
+    /// The output is written while the figures are replaced, like with
+    /// `replace_into`, so the `text` of the returned replacement is empty.
+    pub fn replace_file(
+        &self,
+        input: &Path,
+        output: Option<&Path>,
+    ) -> std::io::Result<Replacement> {
+        let text = fs::read_to_string(input)?;
+        let dir = input.parent().unwrap_or(Path::new(""));
+        let permissions = fs::metadata(output.unwrap_or(input))
+            .or_else(|_| fs::metadata(input))?
+            .permissions();
+
+        let output = match output {
+            Some(output) => output,
+            None => {
+                let mut backup = input.as_os_str().to_owned();
+                backup.push(".bak");
+                fs::copy(input, backup)?;
+                input
+            }
+        };
+
+        // The temporary file must be on the same file system for the rename.
+        let target = output.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let file = tempfile::NamedTempFile::new_in(target.unwrap_or(Path::new(".")))?;
+        let mut writer = std::io::BufWriter::new(file);
+        let replaced = self.replace_into(&text, dir, &mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.as_file().set_permissions(permissions)?;
+        file.as_file().sync_all()?;
+        file.persist(output).map_err(|err| err.error)?;
+        Ok(replaced)
+    }
+
+    /// Like `replace`, but compiles all figures concurrently without blocking
+    /// the executor.
+    ///
+    /// Must be called from within a Tokio runtime. If another call compiles
+    /// one of the figures at the same time, this call blocks until it is done.
+    #[cfg(feature = "async")]
+    pub async fn replace_async(&self, buffer: &str) -> Replacement {
+        self.replace_async_in(buffer, Path::new("")).await
+    }
+
+    /// Like `replace_in`, but compiles all figures concurrently without
+    /// blocking the executor.
+    #[cfg(feature = "async")]
+    pub async fn replace_async_in(&self, buffer: &str, dir: &Path) -> Replacement {
+        let found = scan_in(buffer, dir);
+        let hashes = self.digests(&found);
+        self.refresh(&found, &hashes);
+        let cached = self.cached(&hashes);
+
+        let limit = self.config.jobs.max(1);
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut claimed = HashSet::new();
+
+        for (block, &hash) in found.iter().zip(&hashes) {
+            if self.translate(block).is_some() || !self.pool.claim(hash) {
+                continue;
+            }
+
+            while tasks.len() >= limit {
+                if let Some(Ok((hash, pages))) = tasks.join_next().await {
+                    claimed.remove(&hash);
+                    self.pool.complete(hash, pages);
+                }
+            }
+
+            claimed.insert(hash);
+            let job = self.job(hash, block);
+            tasks.spawn(async move { (job.hash, job.run_async().await) });
+        }
+
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok((hash, pages)) = joined {
+                claimed.remove(&hash);
+                self.pool.complete(hash, pages);
+            }
+        }
+
+        // Jobs that panicked must still be finished, or everyone waiting for
+        // them would block forever.
+        for hash in claimed {
+            let message = "compilation of TikZ figure panicked".to_string();
+            self.pool.finish(hash, Err(message));
+        }
+
+        let mut text = String::with_capacity(buffer.len());
+        let replaced = self.replace_with(buffer, found, &hashes, &cached, &mut |chunk| {
+            text.push_str(chunk);
+            Ok(())
+        });
+
+        Replacement {
+            text,
+            ..replaced.expect("writing to a string cannot fail")
+        }
+    }
+
+    /// Compile all TikZ figures in a buffer and send the outcome of each
+    /// figure as soon as it is known, e.g. for a language server to show
+    /// errors in the editor while other figures are still compiling.
+    ///
+    /// Cached figures are sent first, the others in the order in which they
+    /// finish. Blocks until all figures are sent, so run it on another thread
+    /// and read from the receiving end of the channel, which is closed once
+    /// all figures are done.
+    pub fn check(&self, buffer: &str, sender: Sender<Checked>) {
+        self.check_in(buffer, Path::new(""), sender)
+    }
+
+    /// Like `check`, but resolves the files that the figures reference
+    /// against `dir`, like `replace_in`.
+    pub fn check_in(&self, buffer: &str, dir: &Path, sender: Sender<Checked>) {
+        let found = scan_in(buffer, dir);
+        let hashes = self.digests(&found);
+        self.refresh(&found, &hashes);
+        let cached = self.cached(&hashes);
+
+        let outcome = |block: &Block,
+                       hash: u64,
+                       image: Result<Vec<u8>, String>|
+         -> Result<DiagramInfo, TikzError> {
+            let svg = image.map_err(TikzError::Compilation)?;
+            let width = self.width(&svg).map_err(TikzError::Unembeddable)?;
+            Ok(DiagramInfo {
+                hash,
+                environment: block.environment.into(),
+                cached: cached.contains(&hash),
+                width,
+            })
+        };
+
+        // Identical figures are compiled once and reported together.
+        let mut pending: Vec<(u64, Vec<&Block>)> = vec![];
+        for (block, &hash) in found.iter().zip(&hashes) {
+            match pending.iter_mut().find(|(other, _)| *other == hash) {
+                Some((_, blocks)) => blocks.push(block),
+                None => pending.push((hash, vec![block])),
+            }
+        }
+
+        // Each thread sends with its own sender.
+        let send = |sender: &Sender<Checked>,
+                    hash: u64,
+                    blocks: Vec<&Block>,
+                    image: Result<Vec<u8>, String>| {
+            for block in blocks {
+                let checked = (block.range.clone(), outcome(block, hash, image.clone()));
+                sender.send(checked).ok();
+            }
+        };
+
+        // Loading the cached figures is quick, so they are sent right away.
+        let (done, pending): (Vec<_>, Vec<_>) =
+            pending.into_iter().partition(|(hash, _)| cached.contains(hash));
+        for (hash, blocks) in done {
+            let image = self.pool.compile(&self.job(hash, blocks[0]));
+            send(&sender, hash, blocks, image);
+        }
+
+        let workers = self.config.jobs.max(1).min(pending.len());
+        let queue = Mutex::new(pending.into_iter());
+        let work = |sender: Sender<Checked>| loop {
+            let Some((hash, blocks)) = queue.lock().unwrap().next() else { break };
+            let image = self.pool.compile(&self.job(hash, blocks[0]));
+            send(&sender, hash, blocks, image);
+        };
+
+        // Like in `replace_in`, a single job at a time needs no extra threads.
+        if workers <= 1 {
+            return work(sender);
+        }
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let (work, sender) = (&work, sender.clone());
+                scope.spawn(move || work(sender));
+            }
+        });
+    }
+
+    /// The hashes of the given figures.
+    fn digests(&self, found: &[Block]) -> Vec<u64> {
+        found.iter().map(|block| self.digest(block)).collect()
+    }
+
+    /// The hashes whose images were compiled before.
+    fn cached(&self, hashes: &[u64]) -> HashSet<u64> {
+        hashes.iter().copied().filter(|&hash| self.contains(hash)).collect()
+    }
+
+    /// Replace the figures found in a buffer, given their hashes and the
+    /// hashes that were already compiled before this replacement, and pass
+    /// the rewritten buffer to `emit` as soon as each figure is replaced.
+    ///
+    /// Compiles any figure that is not compiled yet. The returned replacement
+    /// has no text.
+    #[cfg_attr(
+        feature = "tikz-tracing",
+        tracing::instrument(skip_all, fields(len = buffer.len()))
+    )]
+    fn replace_with(
+        &self,
+        buffer: &str,
+        found: Vec<Block>,
+        hashes: &[u64],
+        cached: &HashSet<u64>,
+        emit: &mut dyn FnMut(&str) -> std::io::Result<()>,
+    ) -> std::io::Result<Replacement> {
+        // The length of the rewritten buffer so far.
+        let mut written = 0;
+        let mut regions = vec![];
+        let mut diagnostics = vec![];
+        let mut blocks = vec![];
+
+        // The line of the last figure, which is only needed for metadata.
+        let (mut line, mut counted) = (1, 0);
+
+        let total = found.len();
+        self.notify(Progress::Started { total });
+
+        for (index, (figure, &hash)) in found.iter().zip(hashes).enumerate() {
+            let (environment, block) = (figure.environment, figure.block);
+            let range = figure.range.clone();
+            let start = Stopwatch::start();
+            let lines = "\n".repeat(block.split('\n').count() - 1);
+
+            #[cfg(feature = "tikz-tracing")]
+            let _span = tracing::debug_span!("block", hash, environment).entered();
+
+            let cached = cached.contains(&hash);
+            self.notify(Progress::BlockStarted { index, total, hash, cached });
+
+            let translation = self.translate(figure).map(str::to_owned);
+            if translation.is_none() && export::needs_pdf(&self.config.export_formats) {
+                let job = self.job(hash, figure);
+                self.exported.lock().unwrap().insert(hash, job);
+            }
+
+            let image = match (&translation, self.pool.get(hash)) {
+                (Some(_), _) => {
+                    trace_event!(info, "Translated TikZ figure {} into CeTZ", hash);
+                    Ok(vec![])
+                }
+                (None, Some(image)) => {
+                    trace_event!(info, "Took TikZ figure {} from the cache", hash);
+                    image
+                }
+                (None, None) => {
+                    trace_event!(
+                        info,
+                        "Compiling TikZ figure {}, since no figure with the same code, \
+                         options, inputs and toolchain was compiled before",
+                        hash
+                    );
+                    self.pool.compile(&self.job(hash, figure))
+                }
+            };
+
+            // Translated figures have no images.
+            let pages = match translation {
+                Some(_) => vec![],
+                None => self.pool.pages(hash),
+            };
+
+            let (code, status) = match (translation, &image) {
+                (Some(code), _) => {
+                    (self.wrap(code, hash, environment), BlockStatus::Translated)
+                }
+                (None, Ok(image)) => match self.embed(image, &pages, figure.frames > 1) {
+                    Ok(embedded) => {
+                        (self.wrap(embedded, hash, environment), BlockStatus::Compiled)
+                    }
+                    Err(message) => {
+                        trace_event!(
+                            warn,
+                            "Failed to embed TikZ figure {}: {}",
+                            hash,
+                            message
+                        );
+                        let range = range.clone();
+                        let stage = Stage::Embedding;
+                        diagnostics.push(BlockDiagnostic { hash, range, stage, message });
+                        (PLACEHOLDER.to_string(), BlockStatus::Unembeddable)
+                    }
+                },
+                (None, Err(_)) => {
+                    trace_event!(warn, "Failed to compile TikZ figure {}", hash);
+                    (format!(r#"image("{}")"#, self.filename(hash)), BlockStatus::Failed)
+                }
+            };
+
+            let code = if self.config.metadata {
+                line += buffer[counted..range.start].matches('\n').count();
+                counted = range.start;
+                self.annotate(code, hash, environment, line)
+            } else {
+                code
+            };
+            let replacement = format!("{}{}", code, lines);
+
+            let duration = start.elapsed();
+            self.notify(Progress::BlockFinished { index, total, hash, status, duration });
+
+            let previous =
+                regions.last().map_or(0, |region: &Region| region.original.end);
+            let unchanged = &buffer[previous..range.start];
+            emit(unchanged)?;
+            emit(&replacement)?;
+            written += unchanged.len();
+            let rewritten = written..written + replacement.len();
+            written = rewritten.end;
+            regions.push(Region { rewritten, original: range.clone() });
+
+            blocks.push(BlockReport {
+                hash,
+                identity: self.identity(figure),
+                environment: environment.into(),
+                name: figure.name.clone(),
+                status,
+                cached,
+                duration,
+                range,
+                assets: figure.inputs.iter().map(|input| input.path.clone()).collect(),
+                pages,
+                engine: self.pool.engine(hash),
+                warnings: self.pool.warnings(hash),
+                timings: self.pool.timings(hash),
+            });
+        }
+
+        self.notify(Progress::Finished { total });
+
+        let previous = regions.last().map_or(0, |region| region.original.end);
+        emit(&buffer[previous..])?;
+
+        Ok(Replacement {
+            text: String::new(),
+            diagnostics,
+            report: ReplaceReport { blocks },
+            source_map: SourceMap { regions },
+        })
+    }
+
+    /// The Typst code that embeds the pages of a compiled figure, given the
+    /// SVG of its first page. A figure with several pages becomes a stack of
+    /// images, one per page, unless it is `animated` and its pages are shown
+    /// one after another.
+    fn embed(
+        &self,
+        first: &[u8],
+        pages: &[u64],
+        animated: bool,
+    ) -> Result<String, String> {
+        let mut images = vec![self.image(pages[0], first)?];
+        for &page in &pages[1..] {
+            let svg = self
+                .pool
+                .get(page)
+                .unwrap_or_else(|| Err("failed to load page of TikZ figure".into()))?;
+            images.push(self.image(page, &svg)?);
+        }
+
+        Ok(match images.len() {
+            1 => images.remove(0),
+            _ if animated => format!("{}({})", self.config.reveal, images.join(", ")),
+            _ => format!("stack(spacing: 1em, {})", images.join(", ")),
+        })
+    }
+
+    /// The CeTZ code that draws a figure, if translation is enabled and the
+    /// figure is simple enough.
+    ///
+    /// Figures with class options or external files are always compiled,
+    /// since the translation cannot honor them. Each figure is only
+    /// translated once, however often this is called.
+    fn translate<'b>(&self, block: &'b Block) -> Option<&'b str> {
+        let translation = block.translation.get_or_init(|| {
+            if !self.config.cetz
+                || block.environment != "tikzpicture"
+                || !block.options.is_empty()
+                || !block.inputs.is_empty()
+            {
+                return None;
+            }
+
+            cetz::translate(&block.tex_code)
+        });
+        translation.as_deref()
+    }
+
+    /// Pass the embedded images of a figure through the helper module, if
+    /// there is one.
+    ///
+    /// The module is imported within a code block, which is valid both in
+    /// markup and in code, and keeps the import from leaking into the
+    /// surrounding scope.
+    fn wrap(&self, embedded: String, hash: u64, environment: &str) -> String {
+        let Some(helper) = &self.config.helper else { return embedded };
+        let call = format!(
+            r#"tikz-figure({}, hash: "{}", environment: "{}")"#,
+            embedded, hash, environment
+        );
+        format!(r#"{{import "{}": tikz-figure; {}}}"#, helper, call)
+    }
+
+    /// The width of an SVG as a Typst length, scaled and calibrated.
+    fn width(&self, svg: &[u8]) -> Result<Option<String>, String> {
+        svg_size(svg, Dimension::Width, self.config.scale, self.calibration)
+    }
+
+    /// The height of an SVG as a Typst length, scaled and calibrated.
+    fn height(&self, svg: &[u8]) -> Result<Option<String>, String> {
+        svg_size(svg, Dimension::Height, self.config.scale, self.calibration)
+    }
+
+    /// Measure the factor by which the absolute widths of images must be
+    /// corrected, so that a length in TeX points becomes the same length in
+    /// Typst points, by compiling a figure of a known width.
+    ///
+    /// Pass the factor as `TikzConfig::calibration` to apply it. It only
+    /// changes with the LaTeX engine, the converter or the pipeline.
+    pub fn calibrate(&self) -> Result<f64, TikzError> {
+        let svg = self.render_block("tikzpicture", CALIBRATION_FIGURE, "border: 0pt")?;
+        let measured = match svg_length(&svg.data, Dimension::Width)
+            .map_err(TikzError::Unembeddable)?
+        {
+            Some((width, "pt")) => width,
+            Some((width, "cm")) => width * PT_PER_CM,
+            Some((width, "mm")) => width * PT_PER_CM / 10.0,
+            Some((width, "in")) => width * 72.0,
+            _ => {
+                let message = "the calibration figure has no absolute width";
+                return Err(TikzError::Unembeddable(message.into()));
+            }
+        };
+
+        if measured <= 0.0 {
+            let message = "the calibration figure is empty";
+            return Err(TikzError::Unembeddable(message.into()));
+        }
+
+        let factor = CALIBRATION_WIDTH / measured;
+        trace_event!(info, "Calibrated TikZ figures with the factor {}", factor);
+        Ok(factor)
+    }
+
+    /// Follow the code of a figure with its metadata, which `typst query`
+    /// finds by its label.
+    ///
+    /// Labels can only be attached in markup, so both are put into a content
+    /// block, which is valid wherever the code of the figure is.
+    fn annotate(
+        &self,
+        code: String,
+        hash: u64,
+        environment: &str,
+        line: usize,
+    ) -> String {
+        format!(
+            r#"[#{}#metadata((hash: "{}", env: "{}", line: {})) <{}>]"#,
+            code, hash, environment, line, METADATA_LABEL
+        )
+    }
+
+    /// The Typst code that embeds a single SVG.
+    fn image(&self, hash: u64, svg: &[u8]) -> Result<String, String> {
+        let width = self.width(svg)?;
+        let Some(template) = &self.config.template else {
+            // Without any size information, the image keeps its natural size.
+            return Ok(match width {
+                Some(width) => {
+                    format!(r#"image("{}", width: {})"#, self.filename(hash), width)
+                }
+                None => format!(r#"image("{}")"#, self.filename(hash)),
+            });
+        };
+
+        let height = self.height(svg)?;
+        let filled =
+            REG_TEMPLATE_PLACEHOLDER.replace_all(template, |capture: &Captures| {
+                match &capture[1] {
+                    "filename" => self.filename(hash),
+                    "hash" => hash.to_string(),
+                    "width" => width.clone().unwrap_or_else(|| "auto".into()),
+                    "height" => height.clone().unwrap_or_else(|| "auto".into()),
+                    _ => width
+                        .as_ref()
+                        .map_or(String::new(), |width| format!("width: {}", width)),
+                }
+            });
+        Ok(filled.into_owned())
+    }
+
+    /// Compute the hash that identifies the image of a TikZ figure.
+    ///
+    /// The hash must be stable across builds, since it keys the shared cache.
+    fn digest(&self, block: &Block) -> u64 {
+        self.digest_with(self.fingerprint, block, true)
+    }
+
+    /// Compute the hash that identifies a TikZ figure regardless of the
+    /// programs that compile it: that of its code, its options and the LaTeX
+    /// document around it. It stays the same when the TeX distribution is
+    /// updated, so that the images from before and after can be compared.
+    fn identity(&self, block: &Block) -> u64 {
+        self.digest_with(self.document_fingerprint, block, false)
+    }
+
+    /// Compute the hash of a figure on top of a hash of what all figures
+    /// share, with or without the version of the engine that it asks for.
+    fn digest_with(&self, shared: u64, block: &Block, toolchain: bool) -> u64 {
+        let mut hasher = SipHasher13::new();
+        shared.hash(&mut hasher);
+        block.environment.hash(&mut hasher);
+        if self.config.normalize_whitespace {
+            normalize_whitespace(&block.tex_code).hash(&mut hasher);
+        } else {
+            block.tex_code.hash(&mut hasher);
+        }
+        block.options.hash(&mut hasher);
+        block.inputs.hash(&mut hasher);
+
+        // Figures without frames keep the hashes they had before animations,
+        // and those without an engine the hashes they had before engines.
+        if block.frames > 1 {
+            block.frames.hash(&mut hasher);
+        }
+        if let Some(engine) = &block.engine {
+            engine.hash(&mut hasher);
+            // The shared digest only covers the configured engines, so an
+            // update of the requested one must change the hash, too.
+            if toolchain && self.renderer.is_none() {
+                self.engine_tool(engine).version.hash(&mut hasher);
+            }
+        }
+
+        // Likewise for figures in LaTeX's default language.
+        let language = self.language_preamble(block);
+        if !language.is_empty() {
+            language.hash(&mut hasher);
+        }
+        let preset = self.preset_preamble(block);
+        if !preset.is_empty() {
+            preset.hash(&mut hasher);
+        }
+        if !block.frame.is_empty() {
+            block.frame.fill.hash(&mut hasher);
+            block.frame.pad.to_bits().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Compute the hash of the parts of the LaTeX document that all figures
+    /// share, which is part of `shared_digest`, but without the programs.
+    fn document_digest(&self) -> u64 {
+        let mut hasher = SipHasher13::new();
+        LATEX_DOCUMENT_CLASS.hash(&mut hasher);
+        self.config.class_options.hash(&mut hasher);
+        self.config.preamble.hash(&mut hasher);
+        self.package_preamble().hash(&mut hasher);
+        self.font_preamble().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Compute the hash of everything besides the figure itself that affects
+    /// its image: The options, the versions of the programs, and the parts of
+    /// the LaTeX document that all figures share.
+    ///
+    /// Only options that affect the generated image are part of the hash.
+    fn shared_digest(&self) -> u64 {
+        let mut hasher = SipHasher13::new();
+        CACHE_VERSION.hash(&mut hasher);
+        self.config.outline_text.hash(&mut hasher);
+        self.toolchain.hash(&mut hasher);
+        if self.config.reproducible {
+            self.config.reproducible.hash(&mut hasher);
+        }
+        if self.placeholders {
+            self.placeholders.hash(&mut hasher);
+        }
+        if self.config.raster.enabled() {
+            self.config.raster.hash(&mut hasher);
+        }
+        if self.config.pipeline != Pipeline::default() {
+            self.config.pipeline.hash(&mut hasher);
+        }
+
+        // Without a renderer, the converter is part of the toolchain.
+        if let Some(renderer) = &self.renderer {
+            renderer.name().hash(&mut hasher);
+            self.converter().hash(&mut hasher);
+        }
+        LUA_CONFIG.hash(&mut hasher);
+        LATEX_DOCUMENT_CLASS.hash(&mut hasher);
+        self.config.class_options.hash(&mut hasher);
+        self.config.preamble.hash(&mut hasher);
+        self.package_preamble().hash(&mut hasher);
+        self.font_preamble().hash(&mut hasher);
+        self.config.search_paths.hash(&mut hasher);
+        for path in &self.config.search_paths {
+            search_path_files(path).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// The converter that the configuration asks for, or implies.
+    fn converter(&self) -> &str {
+        match &self.config.converter {
+            Some(converter) => converter,
+            None => job::default_converter(
+                self.config.outline_text || self.config.pipeline == Pipeline::Dvi,
+            ),
+        }
+    }
+
+    /// Prepare the compilation of a figure.
+    fn job(&self, hash: u64, block: &Block) -> Job {
+        let mut job = self.document_job(
+            hash,
+            self.document(block),
+            block.inputs.clone(),
+            block.engine.clone(),
+            REG_GNUPLOT.is_match(&block.tex_code),
+        );
+        job.placeholder = self.placeholders.then(|| block.environment.to_string());
+        job.frame = block.frame.clone();
+        job
+    }
+
+    /// Prepare the compilation of a complete LaTeX document.
+    fn document_job(
+        &self,
+        hash: u64,
+        document: String,
+        inputs: Vec<InputFile>,
+        engine: Option<String>,
+        gnuplot: bool,
+    ) -> Job {
+        Job {
+            hash,
+            dir: self.job_path(hash),
+            document,
+            inputs,
+            search_paths: self.config.search_paths.clone(),
+            engines: match &engine {
+                Some(engine) => vec![self.engine_tool(engine)],
+                None => {
+                    self.toolchain.iter().filter(|tool| tool.engine).cloned().collect()
+                }
+            },
+            engine,
+            // A renderer has no toolchain, but still needs to know which
+            // converter the configuration asks for.
+            converter: self
+                .toolchain
+                .iter()
+                .find(|tool| !tool.engine)
+                .map_or_else(|| self.converter().into(), |tool| tool.path.clone()),
+            lua_config: self.job_dir().join("config.lua"),
+            renderer: self.renderer.clone(),
+            outline_text: self.config.outline_text,
+            gnuplot,
+            timeout: self.config.timeout,
+            limits: self.config.limits.clone(),
+            raster: self.config.raster.clone(),
+            reproducible: self.config.reproducible,
+            placeholder: None,
+            frame: Frame::default(),
+            dvi: self.config.pipeline == Pipeline::Dvi,
+            pngs: self.config.export_formats.contains(&ExportFormat::Png),
+            pdf: self.config.export_formats.contains(&ExportFormat::Pdf),
+        }
+    }
+
+    /// The engine that a figure asks for, which is looked up once.
+    ///
+    /// The engine is probed without holding the lock, so that figures that
+    /// ask for other engines are not held up. If several figures probe the
+    /// same engine at once, the first result is kept.
+    fn engine_tool(&self, engine: &str) -> Tool {
+        if let Some(tool) = self.toolchain.iter().find(|tool| tool.program == engine) {
+            return tool.clone();
+        }
+
+        if let Some(tool) = self.engines.lock().unwrap().get(engine) {
+            return tool.clone();
+        }
+
+        let tool = job::tool(engine, true);
+        let mut engines = self.engines.lock().unwrap();
+        engines.entry(engine.into()).or_insert(tool).clone()
+    }
+
+    /// The directory in which the job of the figure with the given hash
+    /// creates its files.
+    fn job_path(&self, hash: u64) -> PathBuf {
+        match &self.config.keep_intermediates {
+            Some(dir) if self.renderer.is_none() => dir.join(hash.to_string()),
+            _ => self.job_dir().join(hash.to_string()),
+        }
+    }
+
+    /// The directory in which the jobs create their files. It is empty if a
+    /// renderer compiles the figures.
+    fn job_dir(&self) -> &Path {
+        self.tempdir.as_ref().map_or(Path::new(""), |dir| dir.path())
+    }
+
+    /// The complete LaTeX document for a figure.
+    ///
+    /// The body of a `displaymath` figure is set as a displayed equation
+    /// with amsmath, which only works in paragraph mode, i.e. with the
+    /// `varwidth` option instead of the `tikz` option.
+    fn document(&self, block: &Block) -> String {
+        let math = block.environment == "displaymath";
+        let class_option =
+            if math { LATEX_CLASS_OPTION_MATH } else { LATEX_CLASS_OPTION_TIKZ };
+
+        // Later options take precedence, so those of the figure come last.
+        let driver = match self.config.pipeline {
+            Pipeline::Dvi => Some(LATEX_CLASS_OPTION_DVI),
+            Pipeline::Pdf => None,
+        };
+
+        let options: Vec<&str> = [class_option]
+            .into_iter()
+            .chain(driver)
+            .chain(self.config.class_options.iter().map(String::as_str))
+            .chain(block.options.iter().map(String::as_str))
+            .collect();
+
+        let (begin, end) = match block.environment {
+            "displaymath" => (r"\[".to_string(), r"\]".to_string()),
+            environment => (
+                format!("\\begin{{{}}}", environment),
+                format!("\\end{{{}}}", environment),
+            ),
+        };
+
+        // Packages and libraries that the figure loads itself come last.
+        let (packages, code) = hoist(&block.tex_code);
+        let code = code.trim();
+
+        // Each frame of an animated figure becomes a page of its own.
+        let body = if block.frames > 1 && !math {
+            let frames = (1..=block.frames).map(|frame| {
+                let definition = format!("\\def\\{}{{{}}}", FRAME_MACRO, frame);
+                [definition.as_str(), begin.as_str(), code, end.as_str()].join("\n")
+            });
+            frames.collect::<Vec<_>>().join("\n")
+        } else {
+            [begin.as_str(), code, end.as_str()].join("\n")
+        };
+
+        [
+            format!("\\documentclass[{}]{{{}}}", options.join(","), LATEX_DOCUMENT_CLASS),
+            self.config.preamble.clone(),
+            if math { LATEX_MATH_PACKAGE.into() } else { String::new() },
+            self.package_preamble(),
+            self.font_preamble(),
+            self.language_preamble(block),
+            self.preset_preamble(block),
+            packages.join("\n"),
+            LATEX_DOCUMENT_BEGIN.into(),
+            body,
+            LATEX_DOCUMENT_END.into(),
+        ]
+        .join("\n")
+    }
+
+    /// The preamble lines that load the configured packages and libraries.
+    fn package_preamble(&self) -> String {
+        let mut lines = vec![];
+
+        for package in &self.config.packages {
+            let package = package.trim();
+            let (options, name) = match package.strip_prefix('[') {
+                Some(rest) => match rest.split_once(']') {
+                    Some((options, name)) => (format!("[{}]", options), name.trim()),
+                    None => (String::new(), package),
+                },
+                None => (String::new(), package),
+            };
+            lines.push(format!("\\usepackage{}{{{}}}", options, name));
+        }
+
+        let mut libraries: Vec<&str> = vec![];
+        for library in self.config.libraries.iter().flat_map(|entry| entry.split(',')) {
+            let library = library.trim();
+            if !library.is_empty() && !libraries.contains(&library) {
+                libraries.push(library);
+            }
+        }
+        if !libraries.is_empty() {
+            lines.push(format!("\\usetikzlibrary{{{}}}", libraries.join(",")));
+        }
+
+        lines.join("\n")
+    }
+
+    /// The preamble lines that select the configured fonts.
+    fn font_preamble(&self) -> String {
+        let mut lines = vec![];
+
+        if let Some(font) = &self.config.main_font {
+            lines.push(r#"\usepackage{fontspec}"#.to_string());
+            lines.push(font.declaration("setmainfont"));
+        }
+
+        if let Some(font) = &self.config.math_font {
+            lines.push(r#"\usepackage{unicode-math}"#.to_string());
+            lines.push(font.declaration("setmathfont"));
+        }
+
+        lines.join("\n")
+    }
+
+    /// The preamble lines that set up the language of a figure, which is
+    /// empty for figures in LaTeX's default language.
+    fn language_preamble(&self, block: &Block) -> String {
+        let Some(language) = block.language.as_ref().or(self.config.language.as_ref())
+        else {
+            return String::new();
+        };
+
+        let language = language.trim();
+        let (options, name) = match language.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((options, name)) => (options.trim(), name.trim()),
+                None => ("", language),
+            },
+            None => ("", language),
+        };
+        if name.is_empty() {
+            return String::new();
+        }
+
+        match self.config.language_package {
+            LanguagePackage::Babel => {
+                let options: Vec<&str> =
+                    [options, name].into_iter().filter(|o| !o.is_empty()).collect();
+                [
+                    format!("\\usepackage[{}]{{babel}}", options.join(",")),
+                    r"\usetikzlibrary{babel}".to_string(),
+                ]
+                .join("\n")
+            }
+            LanguagePackage::Polyglossia => {
+                let options = if options.is_empty() {
+                    String::new()
+                } else {
+                    format!("[{}]", options)
+                };
+                [
+                    r"\usepackage{polyglossia}".to_string(),
+                    format!("\\setdefaultlanguage{}{{{}}}", options, name),
+                ]
+                .join("\n")
+            }
+        }
+    }
+
+    /// The preamble lines that set up the arrow preset of a `tikzcd` figure,
+    /// which are empty for other figures and those without a preset.
+    fn preset_preamble(&self, block: &Block) -> String {
+        match block.preset.or(self.config.tikzcd_preset) {
+            Some(preset) if block.environment == "tikzcd" => {
+                [r"\usetikzlibrary{cd}", preset.definition()].join("\n")
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Compile the complete LaTeX document of a figure that another compiler
+    /// delegated to this one, e.g. through a `RemoteRenderer`, and return one
+    /// SVG per page.
+    ///
+    /// The document is cached like any figure, under a hash of the document,
+    /// its inputs and the engine that it asks for, while the hash in the
+    /// request is ignored. The preamble and the other
+    /// options of this compiler do not apply, since the document is already
+    /// complete.
+    pub fn render_document(
+        &self,
+        request: &RenderRequest,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        if request.outline_text != self.config.outline_text {
+            return Err(if self.config.outline_text {
+                "this compiler always outlines the text of TikZ figures".into()
+            } else {
+                "this compiler does not outline the text of TikZ figures".into()
+            });
+        }
+
+        if self.placeholders {
+            return Err("TeX is not installed, so TikZ figures cannot be compiled".into());
+        }
+
+        let engine = match request.engine {
+            Some(engine) if !job::is_engine_name(engine) => {
+                return Err(format!("{} is not a LaTeX engine", engine))
+            }
+            engine => engine.map(String::from),
+        };
+
+        let inputs: Vec<_> = request
+            .inputs
+            .iter()
+            .map(|&(path, content)| InputFile {
+                path: path.into(),
+                content: Some(content.to_vec()),
+            })
+            .collect();
+
+        let mut hasher = SipHasher13::new();
+        self.fingerprint.hash(&mut hasher);
+        request.document.hash(&mut hasher);
+        inputs.hash(&mut hasher);
+        engine.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let gnuplot = REG_GNUPLOT.is_match(request.document);
+        let job =
+            self.document_job(hash, request.document.into(), inputs, engine, gnuplot);
+        self.pool.get(hash).unwrap_or_else(|| self.pool.compile(&job))?;
+
+        self.pool
+            .pages(hash)
+            .into_iter()
+            .map(|page| {
+                self.pool.get(page).unwrap_or_else(|| Err("a page is missing".into()))
+            })
+            .collect()
+    }
+
+    /// The hash of everything besides the figures themselves that affects
+    /// their images, like the versions of the programs, e.g. so that a
+    /// server can tell its clients when their cached figures are outdated.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Returns the complete LaTeX log of the figure with the given hash.
+    ///
+    /// Errors only contain LaTeX's terminal output, which often hides the
+    /// actual cause, like a missing package or an earlier error. The log is
+    /// only available for figures that were compiled locally by this
+    /// compiler, and not for those taken from the shared cache.
+    pub fn log(&self, hash: u64) -> Option<String> {
+        self.tempdir.as_ref()?;
+        let log = fs::read(self.job_path(hash).join("tikz.log")).ok()?;
+        Some(String::from_utf8_lossy(&log).into_owned())
+    }
+
+    /// Returns the hash of the figure that failed to compile and whose image
+    /// thus failed to load in `error`.
+    ///
+    /// The error must point at the path of the image, but may be worded in
+    /// any way. Only an error within the helper module may instead come from
+    /// a call with the path. Returns `None` if the error is unrelated to any
+    /// figure that failed to compile.
+    pub fn is_error(&self, world: &dyn World, error: &SourceError) -> Option<u64> {
+        self.referenced(world, error)
+            .into_iter()
+            .find(|&hash| matches!(self.fetch(hash), Some(Err(_))))
+    }
+
+    /// Returns the hash in the path of an image that failed to load in
+    /// `error`, if the path is named like the image of a figure but no such
+    /// figure was compiled, e.g. because it is a stale reference.
+    pub fn is_unknown(&self, world: &dyn World, error: &SourceError) -> Option<u64> {
+        self.referenced(world, error)
+            .into_iter()
+            .find(|&hash| !self.contains(hash))
+    }
+
+    /// The hashes in the paths of images that an error points at.
+    fn referenced(&self, world: &dyn World, error: &SourceError) -> Vec<u64> {
+        // The helper module loads images on behalf of the calls in the trace.
+        let helper = self.is_helper(&compat::path(error));
+        compat::strings(world, error, helper)
+            .iter()
+            .filter_map(|path| self.is_filename(path))
+            .collect()
+    }
+
+    /// The file in the shared cache that holds the image of the figure with
+    /// the given hash, if there is one.
+    pub fn cache_entry(&self, hash: u64) -> Option<PathBuf> {
+        let path = self.pool.cache()?.entry(hash);
+        path.exists().then_some(path)
+    }
+
+    /// The path under which the rewritten buffer refers to the image of the
+    /// figure with the given hash.
+    pub fn filename(&self, hash: u64) -> String {
+        self.config.filename.replacen(HASH_PLACEHOLDER, &hash.to_string(), 1)
+    }
+
+    /// The generated helper module, if one is configured.
+    pub fn helper(&self) -> Option<&'static str> {
+        self.config.helper.as_ref().map(|_| HELPER_SOURCE)
+    }
+
+    /// Whether a path refers to the helper module.
+    ///
+    /// Like for `is_filename`, the path may be relative to the rewritten
+    /// buffer or to anything above it.
+    pub fn is_helper(&self, path: &str) -> bool {
+        let Some(helper) = &self.config.helper else { return false };
+        let path = path.replace('\\', "/");
+        let helper = helper.trim_start_matches("./");
+        path.strip_suffix(helper)
+            .map_or(false, |before| before.is_empty() || before.ends_with('/'))
+    }
+
+    /// Write the generated helper module into a directory, next to rewritten
+    /// buffers, unless a file already exists under its path.
+    ///
+    /// Returns the path of the written module, or `None` if none is
+    /// configured or the existing file was kept.
+    pub fn write_helper(&self, dir: &Path) -> std::io::Result<Option<PathBuf>> {
+        let (Some(helper), Some(source)) = (&self.config.helper, self.helper()) else {
+            return Ok(None);
+        };
+
+        let path = dir.join(helper);
+        if path.exists() {
+            return Ok(None);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, source)?;
+        Ok(Some(path))
+    }
+
+    /// Returns the hash of the figure whose image a path refers to.
+    ///
+    /// The path may be relative to the rewritten buffer or to anything above
+    /// it, e.g. the project root, as long as it ends with the filename.
+    pub fn is_filename(&self, path: &str) -> Option<u64> {
+        let path = path.replace('\\', "/");
+        let (prefix, suffix) = self.config.filename.split_once(HASH_PLACEHOLDER)?;
+
+        let rest = path.strip_suffix(suffix)?;
+        let digits =
+            rest.len() - rest.bytes().rev().take_while(u8::is_ascii_digit).count();
+        let hash = rest[digits..].parse().ok()?;
+
+        // The prefix must start at a directory boundary.
+        let before = rest[..digits].strip_suffix(prefix)?;
+        (before.is_empty() || before.ends_with('/')).then_some(hash)
+    }
+}
@@ -0,0 +1,352 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::cache::Cache;
+use super::job::{Compiled, Job, StageTimings};
+use super::page_hash;
+
+/// A single compiled image: Either the SVG or the LaTeX logs.
+pub(crate) type Image = Result<Vec<u8>, String>;
+
+/// The result of compiling a figure: Either its pages or the LaTeX logs.
+pub(crate) type Pages = Result<Compiled, String>;
+
+/// Stores the compiled images and schedules the compilation of new ones.
+///
+/// At most `limit` jobs run at the same time, and each hash is compiled only
+/// once, even when it is requested by multiple threads concurrently. Images
+/// that are not in memory are looked up in the shared cache, if there is one.
+///
+/// The SVGs are kept compressed, since documents with hundreds of figures
+/// would otherwise hold megabytes of SVGs for the lifetime of the process.
+///
+/// Figures with several pages are stored as one image per page. The first
+/// page is stored under the hash of the figure, the others under the hashes
+/// returned by `page_hash`.
+pub(crate) struct Pool {
+    /// The maximum number of jobs that run at the same time.
+    limit: usize,
+    /// The cache that is shared with other processes.
+    cache: Option<Cache>,
+    /// The images and the jobs in flight.
+    state: Mutex<State>,
+    /// Notified whenever a job finishes.
+    finished: Condvar,
+}
+
+/// The mutable state of a pool.
+#[derive(Default)]
+struct State {
+    /// The compiled images.
+    images: HashMap<u64, Arc<Stored>>,
+    /// The hashes of the jobs in flight.
+    running: HashSet<u64>,
+    /// The LaTeX engines that compiled the figures in this process.
+    engines: HashMap<u64, String>,
+    /// The LaTeX warnings of the figures that were compiled in this process.
+    warnings: HashMap<u64, Vec<String>>,
+    /// How long the figures that were compiled in this process took.
+    timings: HashMap<u64, StageTimings>,
+    /// The hashes that must be compiled again instead of being taken from a
+    /// cache.
+    stale: HashSet<u64>,
+    /// The hashes whose images were registered instead of compiled.
+    pinned: HashSet<u64>,
+}
+
+impl Pool {
+    /// Create an empty pool that runs at most `limit` jobs at the same time.
+    pub fn new(limit: usize, cache: Option<Cache>) -> Self {
+        Self {
+            limit: limit.max(1),
+            cache,
+            state: Mutex::default(),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Returns the image with the given hash if it was compiled.
+    pub fn get(&self, hash: u64) -> Option<Image> {
+        self.stored(hash).map(|stored| stored.decompress())
+    }
+
+    /// Whether the image with the given hash was compiled.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.stored(hash).is_some()
+    }
+
+    /// The hashes of all pages of a compiled figure, starting with the hash
+    /// of the figure itself.
+    pub fn pages(&self, hash: u64) -> Vec<u64> {
+        let mut pages = vec![hash];
+        while self.contains(page_hash(hash, pages.len())) {
+            pages.push(page_hash(hash, pages.len()));
+        }
+        pages
+    }
+
+    /// The LaTeX engine that compiled the figure with the given hash, if it
+    /// was compiled by this process.
+    pub fn engine(&self, hash: u64) -> Option<String> {
+        self.lock().engines.get(&hash).cloned()
+    }
+
+    /// The LaTeX warnings of the figure with the given hash, if it was
+    /// compiled by this process.
+    pub fn warnings(&self, hash: u64) -> Vec<String> {
+        self.lock().warnings.get(&hash).cloned().unwrap_or_default()
+    }
+
+    /// How long the figure with the given hash took to compile or to load
+    /// from the shared cache, if this process did so.
+    pub fn timings(&self, hash: u64) -> Option<StageTimings> {
+        self.lock().timings.get(&hash).copied()
+    }
+
+    /// Returns the image of a job's first page, compiling it if necessary.
+    ///
+    /// Blocks while the same hash is compiled by someone else or while the
+    /// maximum number of jobs is running.
+    pub fn compile(&self, job: &Job) -> Image {
+        let mut state = self.lock();
+        loop {
+            if let Some(stored) = state.images.get(&job.hash).cloned() {
+                drop(state);
+                return stored.decompress();
+            }
+
+            if !state.running.contains(&job.hash) && state.running.len() < self.limit {
+                break;
+            }
+
+            state = self.finished.wait(state).unwrap();
+        }
+
+        state.running.insert(job.hash);
+        let fresh = state.stale.contains(&job.hash);
+        drop(state);
+
+        // A panicking job must still release its hash, or everyone waiting
+        // for it would wait forever.
+        let pages = panic::catch_unwind(AssertUnwindSafe(|| match &self.cache {
+            Some(cache) => cache.compile(job, fresh),
+            None => job.run(),
+        }))
+        .unwrap_or_else(|payload| {
+            Err(format!(
+                "compiling TikZ figure {} panicked: {}",
+                job.hash,
+                message(&*payload)
+            ))
+        });
+
+        self.finish(job.hash, pages)
+    }
+
+    /// Claim a hash for compilation without blocking.
+    ///
+    /// Returns `false` if the image is already compiled or in flight. If this
+    /// returns `true`, the caller must compile the image and pass it to
+    /// `complete` or `finish`, but is itself responsible for respecting the
+    /// limit.
+    #[cfg(feature = "async")]
+    pub fn claim(&self, hash: u64) -> bool {
+        let mut state = self.lock();
+        !state.images.contains_key(&hash) && state.running.insert(hash)
+    }
+
+    /// Like `finish`, but also stores the pages in the shared cache.
+    #[cfg(feature = "async")]
+    pub fn complete(&self, hash: u64, pages: Pages) -> Image {
+        if let (Some(cache), Ok(compiled)) = (&self.cache, &pages) {
+            if let Err(_err) = cache.store_pages(hash, compiled) {
+                trace_event!(warn, "Failed to store TikZ figure in cache: {}", _err);
+            }
+        }
+
+        self.finish(hash, pages)
+    }
+
+    /// Store the pages of a claimed hash and wake up everyone waiting for it.
+    ///
+    /// Returns the image of the first page. If an image was pinned under the
+    /// hash while it was compiled, the pinned image is kept and returned.
+    pub fn finish(&self, hash: u64, pages: Pages) -> Image {
+        let stored: Vec<_> = match &pages {
+            Ok(compiled) => compiled
+                .pages
+                .iter()
+                .enumerate()
+                .map(|(page, svg)| {
+                    (page_hash(hash, page), Stored::compress(Ok(svg.as_slice())))
+                })
+                .collect(),
+            Err(logs) => vec![(hash, Stored::compress(Err(logs.as_str())))],
+        };
+
+        let mut state = self.lock();
+        if state.pinned.contains(&hash) {
+            state.running.remove(&hash);
+            let pinned = state.images.get(&hash).cloned();
+            drop(state);
+
+            self.finished.notify_all();
+            return match pinned {
+                Some(pinned) => pinned.decompress(),
+                None => pages.map(|compiled| {
+                    compiled.pages.into_iter().next().unwrap_or_default()
+                }),
+            };
+        }
+
+        for (page, stored) in stored {
+            state.images.insert(page, Arc::new(stored));
+        }
+        if let Ok(Compiled { engine: Some(engine), .. }) = &pages {
+            state.engines.insert(hash, engine.clone());
+        }
+        match &pages {
+            Ok(compiled) if !compiled.warnings.is_empty() => {
+                state.warnings.insert(hash, compiled.warnings.clone());
+            }
+            _ => {
+                state.warnings.remove(&hash);
+            }
+        }
+        match &pages {
+            Ok(compiled) => state.timings.insert(hash, compiled.timings),
+            Err(_) => state.timings.remove(&hash),
+        };
+        state.running.remove(&hash);
+        state.stale.remove(&hash);
+        drop(state);
+
+        self.finished.notify_all();
+        pages.map(|compiled| compiled.pages.into_iter().next().unwrap_or_default())
+    }
+
+    /// Store an image with a single page under the given hash in place of
+    /// compiling it. It is neither refreshed nor forgotten afterwards.
+    pub fn pin(&self, hash: u64, svg: &[u8]) {
+        let stored = Arc::new(Stored::compress(Ok(svg)));
+        let mut state = self.lock();
+        let mut page = 1;
+        while state.images.remove(&page_hash(hash, page)).is_some() {
+            page += 1;
+        }
+        state.images.insert(hash, stored);
+        state.engines.remove(&hash);
+        state.warnings.remove(&hash);
+        state.timings.remove(&hash);
+        state.stale.remove(&hash);
+        state.pinned.insert(hash);
+    }
+
+    /// Forget the images with the given hashes and bypass the shared cache
+    /// for them, so that they are compiled again when they are requested
+    /// next. Hashes that are being compiled right now or that are pinned are
+    /// left alone.
+    pub fn refresh(&self, hashes: impl IntoIterator<Item = u64>) {
+        let mut state = self.lock();
+        for hash in hashes {
+            if state.running.contains(&hash) || state.pinned.contains(&hash) {
+                continue;
+            }
+
+            let mut page = 0;
+            while state.images.remove(&page_hash(hash, page)).is_some() {
+                page += 1;
+            }
+            state.engines.remove(&hash);
+            state.warnings.remove(&hash);
+            state.timings.remove(&hash);
+            state.stale.insert(hash);
+        }
+    }
+
+    /// Forget all images whose hashes are not kept, except for pinned ones.
+    pub fn retain(&self, keep: &HashSet<u64>) {
+        let mut state = self.lock();
+        let State { images, pinned, .. } = &mut *state;
+        images.retain(|hash, _| keep.contains(hash) || pinned.contains(hash));
+        state.engines.retain(|hash, _| keep.contains(hash));
+        state.warnings.retain(|hash, _| keep.contains(hash));
+        state.timings.retain(|hash, _| keep.contains(hash));
+    }
+
+    /// The shared cache, if there is one.
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    /// Returns the stored image with the given hash, loading it from the
+    /// shared cache if it is not in memory.
+    fn stored(&self, hash: u64) -> Option<Arc<Stored>> {
+        let state = self.lock();
+        if let Some(stored) = state.images.get(&hash) {
+            return Some(stored.clone());
+        } else if state.stale.contains(&hash) {
+            return None;
+        }
+        drop(state);
+
+        let cache = self.cache.as_ref()?;
+        let svg = cache.load(hash)?;
+        let warnings = cache.warnings(hash);
+        let stored = Arc::new(Stored::compress(Ok(svg.as_slice())));
+
+        // The warnings of a figure are reported again whenever it is used.
+        let mut state = self.lock();
+        if !warnings.is_empty() {
+            state.warnings.entry(hash).or_insert(warnings);
+        }
+        Some(state.images.entry(hash).or_insert(stored).clone())
+    }
+
+    /// Lock the state of the pool.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// The message of a panic, if it has one.
+fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error")
+}
+
+/// An image as it is kept in memory, with the SVG deflated.
+struct Stored(Image);
+
+impl Stored {
+    /// Compress an image for storage.
+    fn compress(image: Result<&[u8], &str>) -> Self {
+        Self(image.map_err(str::to_string).and_then(|svg| {
+            let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+            encoder
+                .write_all(svg)
+                .and_then(|_| encoder.finish())
+                .map_err(|err| format!("failed to compress generated SVG: {}", err))
+        }))
+    }
+
+    /// Restore the original image.
+    fn decompress(&self) -> Image {
+        let compressed = self.0.as_ref().map_err(Clone::clone)?;
+        let mut svg = vec![];
+        DeflateDecoder::new(compressed.as_slice())
+            .read_to_end(&mut svg)
+            .map_err(|err| format!("failed to decompress generated SVG: {}", err))?;
+        Ok(svg)
+    }
+}
@@ -0,0 +1,244 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::{TikzConfig, Verbosity};
+
+/// The name of the file that configures the TikZ figures of a project.
+pub const PROJECT_FILE: &str = "typst-tikz.toml";
+
+/// The prefix of the environment variables that configure TikZ figures.
+const ENV_PREFIX: &str = "TYPST_TIKZ_";
+
+/// The configuration of a project, read from a `typst-tikz.toml` next to the
+/// document or in one of its parent directories.
+///
+/// Every setting is optional and falls back to the defaults of
+/// `TikzConfig`, while options on the command line take precedence:
+///
+/// ```toml
+/// engine = ["lualatex", "xelatex"]
+/// converter = "dvisvgm"
+/// preamble = "\\newcommand{\\C}{\\mathcal{C}}"
+/// packages = ["[compat=1.18]pgfplots"]
+/// libraries = ["arrows.meta", "calc"]
+/// cache-dir = ".tikz-cache"
+/// timeout = 30
+/// filename = "figures/{hash}.svg"
+/// verbosity = "verbose"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// The file that the configuration was read from, or an empty path if it
+    /// was read from the environment.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The LaTeX engine, or several engines to try in order.
+    pub engine: Option<Engines>,
+    /// The program that converts the PDFs into SVGs.
+    pub converter: Option<String>,
+    /// LaTeX code that is appended to the preamble.
+    pub preamble: Option<String>,
+    /// Extra packages, like `TikzConfig::packages`.
+    pub packages: Vec<String>,
+    /// Extra TikZ libraries, like `TikzConfig::libraries`.
+    pub libraries: Vec<String>,
+    /// The shared cache, relative to the configuration file.
+    pub cache_dir: Option<PathBuf>,
+    /// How many seconds LaTeX and the converter may run for a figure.
+    pub timeout: Option<f64>,
+    /// The path of the images, like `TikzConfig::filename`.
+    pub filename: Option<String>,
+    /// How much is logged, like `TikzConfig::verbosity`.
+    pub verbosity: Option<Verbosity>,
+}
+
+/// One or several LaTeX engines.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Engines {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ProjectConfig {
+    /// The configuration of documents in the given directory: the defaults
+    /// of `TikzConfig`, overridden by the closest configuration file and then
+    /// by the environment variables, like on the command line.
+    pub fn load(dir: &Path) -> Result<TikzConfig, String> {
+        let mut config = TikzConfig::default();
+        for layer in Self::layers(dir)? {
+            layer.apply(&mut config);
+        }
+        Ok(config)
+    }
+
+    /// The configurations that apply to documents in the given directory, in
+    /// the order in which they are applied: the closest configuration file,
+    /// if there is one, and then the environment variables.
+    pub fn layers(dir: &Path) -> Result<Vec<Self>, String> {
+        let mut layers: Vec<_> = Self::discover(dir)?.into_iter().collect();
+        layers.push(Self::from_env()?);
+        Ok(layers)
+    }
+
+    /// Find and read the configuration file that applies to documents in
+    /// the given directory, which is the closest one in the directory itself
+    /// or any of its parents. Returns `None` if there is none.
+    pub fn discover(dir: &Path) -> Result<Option<Self>, String> {
+        let dir = env::current_dir()
+            .map_err(|err| format!("failed to determine the working directory: {}", err))?
+            .join(dir);
+
+        match dir
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file())
+        {
+            Some(path) => Self::read(&path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a configuration file.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let mut project: Self = toml::from_str(&text)
+            .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+
+        check_timeout(project.timeout)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        // The cache is shared by the whole project, wherever it is compiled.
+        let root = path.parent().unwrap_or(Path::new(""));
+        project.cache_dir = project.cache_dir.map(|dir| root.join(dir));
+        project.path = path.into();
+        Ok(project)
+    }
+
+    /// Read the same settings from environment variables, so that CI systems
+    /// and container images can configure figures without touching the
+    /// project: `TYPST_TIKZ_ENGINE`, `TYPST_TIKZ_CONVERTER`,
+    /// `TYPST_TIKZ_PREAMBLE`, `TYPST_TIKZ_PACKAGES`, `TYPST_TIKZ_LIBRARIES`,
+    /// `TYPST_TIKZ_CACHE_DIR`, `TYPST_TIKZ_TIMEOUT`, `TYPST_TIKZ_FILENAME`
+    /// and `TYPST_TIKZ_VERBOSITY`.
+    ///
+    /// Lists are separated by commas, except for those within the options of
+    /// a package, e.g. `TYPST_TIKZ_ENGINE=lualatex,xelatex`. Variables that
+    /// are empty are ignored.
+    ///
+    /// The environment is applied after the configuration file by `load` and
+    /// `layers`, and before explicit options, which thus take precedence.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| {
+            env::var(format!("{}{}", ENV_PREFIX, name))
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+
+        let timeout = match var("TIMEOUT") {
+            Some(value) => Some(value.trim().parse::<f64>().map_err(|err| {
+                format!("{}TIMEOUT is not a number of seconds: {}", ENV_PREFIX, err)
+            })?),
+            None => None,
+        };
+        check_timeout(timeout)
+            .map_err(|err| format!("{}TIMEOUT: {}", ENV_PREFIX, err))?;
+
+        let verbosity = match var("VERBOSITY") {
+            Some(value) => Some(
+                value
+                    .parse()
+                    .map_err(|err| format!("{}VERBOSITY: {}", ENV_PREFIX, err))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            path: PathBuf::new(),
+            engine: var("ENGINE").map(|value| Engines::Many(list(&value))),
+            converter: var("CONVERTER"),
+            preamble: var("PREAMBLE"),
+            packages: var("PACKAGES").map_or(vec![], |value| list(&value)),
+            libraries: var("LIBRARIES").map_or(vec![], |value| list(&value)),
+            cache_dir: var("CACHE_DIR").map(PathBuf::from),
+            timeout,
+            filename: var("FILENAME"),
+            verbosity,
+        })
+    }
+
+    /// Apply the settings of the project to a configuration.
+    pub fn apply(&self, config: &mut TikzConfig) {
+        match &self.engine {
+            Some(Engines::One(engine)) => config.engines = vec![engine.clone()],
+            Some(Engines::Many(engines)) => config.engines = engines.clone(),
+            None => {}
+        }
+
+        if let Some(preamble) = &self.preamble {
+            config.preamble.push('\n');
+            config.preamble.push_str(preamble);
+        }
+
+        config.packages.extend(self.packages.iter().cloned());
+        config.libraries.extend(self.libraries.iter().cloned());
+        config.converter = self.converter.clone().or(config.converter.take());
+        config.cache_dir = self.cache_dir.clone().or(config.cache_dir.take());
+        // Timeouts that `read` and `from_env` reject cannot be represented.
+        let timeout =
+            self.timeout.and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+        config.timeout = timeout.or(config.timeout);
+        if let Some(filename) = &self.filename {
+            config.filename = filename.clone();
+        }
+        if let Some(verbosity) = self.verbosity {
+            config.verbosity = verbosity;
+        }
+    }
+}
+
+/// Check that a timeout in seconds is positive and not too large to wait
+/// for.
+fn check_timeout(timeout: Option<f64>) -> Result<(), String> {
+    match timeout {
+        Some(timeout) if !timeout.is_finite() || timeout <= 0.0 => {
+            Err("the timeout must be positive".into())
+        }
+        Some(timeout) => Duration::try_from_secs_f64(timeout)
+            .map(|_| ())
+            .map_err(|err| format!("the timeout {} is invalid: {}", timeout, err)),
+        None => Ok(()),
+    }
+}
+
+/// Split a list that is separated by commas, except for those in brackets.
+fn list(value: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut depth = 0usize;
+    for c in value.chars() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    items.push(item);
+
+    items
+        .into_iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
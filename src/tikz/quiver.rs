@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use serde_json::Value;
+
+/// Decodes the diagrams in share links, which may or may not be padded.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// The height of a curved arrow per step of curvature in quiver.
+const CURVE_HEIGHT_PT: f64 = 6.0;
+
+/// The most rows or columns that a diagram may span. quiver's editor never
+/// produces more than a few hundred, while a forged link could ask for a
+/// grid that does not fit into memory.
+const MAX_SPAN: i64 = 1000;
+
+/// Translate a quiver share link, like `https://q.uiver.app/#q=...`, into the
+/// body of a `tikzcd` environment, like quiver's own LaTeX export.
+///
+/// The diagram is decoded from the link itself, so nothing is fetched. Its
+/// arrows use the styles of the bundled `quiver.sty`. Returns an error if the
+/// link contains no diagram that can be decoded.
+pub(crate) fn tikzcd(url: &str) -> Result<String, String> {
+    let data = url
+        .split_once("q=")
+        .map(|(_, data)| data.split('&').next().unwrap_or_default())
+        .ok_or("the quiver link contains no diagram")?;
+
+    let bytes = BASE64
+        .decode(percent_decode(data))
+        .map_err(|err| format!("failed to decode the quiver link: {}", err))?;
+    let json: Value = serde_json::from_slice(&bytes)
+        .map_err(|err| format!("failed to parse the quiver diagram: {}", err))?;
+
+    let cells = json.as_array().ok_or("the quiver diagram is not a list")?;
+    // The count comes from the link, so it may be arbitrarily large.
+    let count = cells.get(1).and_then(Value::as_u64).unwrap_or(0);
+    let end = usize::try_from(count).ok().and_then(|count| count.checked_add(2));
+    let (Some(vertices), Some(edges)) =
+        (end.and_then(|end| cells.get(2..end)), end.and_then(|end| cells.get(end..)))
+    else {
+        return Err("the quiver diagram is incomplete".into());
+    };
+
+    Ok(Diagram::new(vertices, edges)?.render())
+}
+
+/// A decoded quiver diagram.
+struct Diagram {
+    /// The vertices with their rows and columns, counted from one.
+    vertices: Vec<(usize, usize, String)>,
+    /// The edges, each referring to other cells by their indices, where the
+    /// vertices come first and the edges after them.
+    edges: Vec<Edge>,
+}
+
+/// An arrow between two cells.
+struct Edge {
+    source: usize,
+    target: usize,
+    /// The options of the `\arrow` command besides its ends.
+    options: Vec<String>,
+}
+
+impl Diagram {
+    /// Read the vertices and edges of a diagram.
+    fn new(vertices: &[Value], edges: &[Value]) -> Result<Self, String> {
+        let position = |vertex: &Value, index: usize| {
+            vertex.get(index).and_then(Value::as_i64).ok_or("invalid vertex")
+        };
+
+        let mut positions = vec![];
+        for vertex in vertices {
+            let label = vertex.get(2).and_then(Value::as_str).unwrap_or_default();
+            positions.push((
+                position(vertex, 0)?,
+                position(vertex, 1)?,
+                label.to_string(),
+            ));
+        }
+
+        // quiver allows negative positions, while tikz-cd counts from one.
+        let left = positions.iter().map(|&(x, _, _)| x).min().unwrap_or(0);
+        let top = positions.iter().map(|&(_, y, _)| y).min().unwrap_or(0);
+        let offset = |position: i64, start: i64| {
+            position
+                .checked_sub(start)
+                .filter(|&offset| offset < MAX_SPAN)
+                .map(|offset| offset as usize + 1)
+                .ok_or("invalid vertex")
+        };
+        let vertices = positions
+            .into_iter()
+            .map(|(x, y, label)| Ok((offset(y, top)?, offset(x, left)?, label)))
+            .collect::<Result<_, String>>()?;
+
+        let edges = edges.iter().map(Edge::new).collect::<Result<_, _>>()?;
+        Ok(Self { vertices, edges })
+    }
+
+    /// The body of the `tikzcd` environment.
+    fn render(&self) -> String {
+        let rows = self.vertices.iter().map(|&(row, _, _)| row).max().unwrap_or(0);
+        let columns =
+            self.vertices.iter().map(|&(_, column, _)| column).max().unwrap_or(0);
+
+        let mut grid = vec![vec![String::new(); columns]; rows];
+        for (row, column, label) in &self.vertices {
+            grid[row - 1][column - 1] = label.clone();
+        }
+
+        let mut lines: Vec<_> = grid.iter().map(|cells| cells.join(" & ")).collect();
+        let last = lines.pop().unwrap_or_default();
+        let mut body: Vec<_> =
+            lines.into_iter().map(|line| format!("{} \\\\", line)).collect();
+        body.push(last);
+
+        // Edges between edges refer to their ends by name.
+        let named: HashSet<usize> = self
+            .edges
+            .iter()
+            .flat_map(|edge| [edge.source, edge.target])
+            .collect();
+
+        let count = self.vertices.len();
+        for (index, edge) in self.edges.iter().enumerate() {
+            let mut options = edge.options.clone();
+            options.push(format!("from={}", self.end(edge.source)));
+            options.push(format!("to={}", self.end(edge.target)));
+            if named.contains(&(count + index)) {
+                let name = count + index;
+                options
+                    .push(format!(r#"""{{name={}, anchor=center, inner sep=0}}"#, name));
+            }
+            body.push(format!("\\arrow[{}]", options.join(", ")));
+        }
+
+        body.join("\n")
+    }
+
+    /// How an arrow refers to one of its ends: A vertex by its position and
+    /// an edge by its name.
+    fn end(&self, cell: usize) -> String {
+        match self.vertices.get(cell) {
+            Some((row, column, _)) => format!("{}-{}", row, column),
+            None => cell.to_string(),
+        }
+    }
+}
+
+impl Edge {
+    /// Read an edge, which is a list of its source, its target, and
+    /// optionally its label, the alignment of the label, its options, and
+    /// its color.
+    fn new(edge: &Value) -> Result<Self, String> {
+        let cell = |index: usize| {
+            edge.get(index)
+                .and_then(Value::as_u64)
+                .map(|cell| cell as usize)
+                .ok_or("invalid edge")
+        };
+
+        let (source, target) = (cell(0)?, cell(1)?);
+        let label = edge.get(2).and_then(Value::as_str).unwrap_or_default();
+        let alignment = edge.get(3).and_then(Value::as_u64).unwrap_or(0);
+        let settings = edge.get(4).cloned().unwrap_or(Value::Null);
+        let number = |key: &str| settings.get(key).and_then(Value::as_f64);
+        let style = |pointer: &str| {
+            settings
+                .pointer(pointer)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let mut options = vec![];
+        if !label.is_empty() {
+            options.push(match alignment {
+                1 => format!(r#""{}" description"#, label),
+                2 => format!(r#""{}"'"#, label),
+                _ => format!(r#""{}""#, label),
+            });
+        }
+
+        if let Some(position) = number("label_position").filter(|&p| p != 50.0) {
+            options.push(format!("pos={}", position / 100.0));
+        }
+
+        if let Some(curve) = number("curve").filter(|&c| c != 0.0) {
+            options.push(format!("curve={{height={}pt}}", -curve * CURVE_HEIGHT_PT));
+        }
+
+        if let Some(offset) = number("offset").filter(|&o| o != 0.0) {
+            let side = if offset > 0.0 { "right" } else { "left" };
+            options.push(format!("shift {}={}", side, offset.abs()));
+        }
+
+        if number("level").map_or(false, |level| level >= 2.0) {
+            options.push("Rightarrow".into());
+        }
+
+        match style("/style/name").as_str() {
+            "adjunction" => options.extend([
+                r#""\dashv"{anchor=center, rotate=-90}"#.into(),
+                "draw=none".into(),
+            ]),
+            "corner" => options.extend([
+                r#""\lrcorner"{anchor=center, pos=0.125}"#.into(),
+                "draw=none".into(),
+            ]),
+            _ => {}
+        }
+
+        match style("/style/body/name").as_str() {
+            "dashed" => options.push("dashed".into()),
+            "dotted" => options.push("dotted".into()),
+            "squiggly" => options.push("squiggly".into()),
+            "barred" => options.push(r#""\shortmid"{marking}"#.into()),
+            "none" => options.push("draw=none".into()),
+            _ => {}
+        }
+
+        match style("/style/head/name").as_str() {
+            "none" => options.push("no head".into()),
+            "epi" => options.push("two heads".into()),
+            "harpoon" => options.push("harpoon".into()),
+            _ => {}
+        }
+
+        match style("/style/tail/name").as_str() {
+            "mono" => options.push("tail".into()),
+            "maps to" => options.push("maps to".into()),
+            "hook" => options.push("hook".into()),
+            _ => {}
+        }
+
+        Ok(Self { source, target, options })
+    }
+}
+
+/// Undo the percent-encoding of a part of a URL.
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
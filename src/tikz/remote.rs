@@ -0,0 +1,136 @@
+use std::io::{self, ErrorKind, Read};
+use std::time::Duration;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::render::{RenderRequest, Renderer};
+
+/// How long to wait for the server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the server to answer, which includes the time that
+/// LaTeX takes to compile the figure there.
+const READ_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long to wait for the server to take a request.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A renderer that sends the documents of figures to a `typst-tikz serve`
+/// instance, so that machines without TeX can compile figures.
+///
+/// The server compiles the documents with its own LaTeX installation and
+/// returns the SVGs, which are cached locally like any other figure. It is
+/// only contacted when a figure is not cached, so documents whose figures
+/// are all cached build without it. The address of the server is part of
+/// the name, but its TeX installation is not, so figures are not compiled
+/// again when only the installation changes.
+#[derive(Debug, Clone)]
+pub struct RemoteRenderer {
+    /// The address of the server, without a trailing slash.
+    url: String,
+    /// The client through which requests are sent.
+    agent: ureq::Agent,
+}
+
+/// The body of a request to compile a document.
+#[derive(Serialize)]
+struct DocumentRequest<'a> {
+    document: &'a str,
+    inputs: Vec<DocumentInput>,
+    outline_text: bool,
+    engine: Option<&'a str>,
+    dvi: bool,
+    dvisvgm: bool,
+    raster_max_size: Option<u64>,
+    raster_max_elements: Option<usize>,
+    raster_dpi: u32,
+}
+
+/// A file that a document references, with its content in base64.
+#[derive(Serialize)]
+struct DocumentInput {
+    path: String,
+    content: String,
+}
+
+/// The body of the response to a document that compiled.
+#[derive(Deserialize)]
+struct DocumentResponse {
+    /// The SVGs, one per page.
+    pages: Vec<String>,
+}
+
+impl RemoteRenderer {
+    /// Prepare to compile figures on the server at the given address, e.g.
+    /// `http://tex.example.org:8471`. The server is not contacted until the
+    /// first figure needs to be compiled.
+    pub fn connect(url: &str) -> io::Result<Self> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("the address of the TikZ server {} is not an HTTP URL", url),
+            ));
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .timeout_write(WRITE_TIMEOUT)
+            .build();
+        Ok(Self { url: url.trim_end_matches('/').into(), agent })
+    }
+}
+
+impl Renderer for RemoteRenderer {
+    fn render(&self, request: &RenderRequest) -> Result<Vec<Vec<u8>>, String> {
+        let body = DocumentRequest {
+            document: request.document,
+            inputs: request
+                .inputs
+                .iter()
+                .map(|(path, content)| DocumentInput {
+                    path: path.to_string_lossy().into_owned(),
+                    content: base64::engine::general_purpose::STANDARD.encode(content),
+                })
+                .collect(),
+            outline_text: request.outline_text,
+            engine: request.engine,
+            dvi: request.dvi,
+            dvisvgm: request.dvisvgm,
+            raster_max_size: request.raster.max_size,
+            raster_max_elements: request.raster.max_elements,
+            raster_dpi: request.raster.dpi,
+        };
+        let body = serde_json::to_string(&body).map_err(|err| err.to_string())?;
+
+        // The server answers documents that fail to compile with their logs.
+        let response = match self
+            .agent
+            .post(&format!("{}/document", self.url))
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(422, response)) => {
+                return Err(response.into_string().unwrap_or_default())
+            }
+            Err(err) => {
+                return Err(format!("the TikZ server at {} failed: {}", self.url, err))
+            }
+        };
+
+        let mut text = String::new();
+        response.into_reader().read_to_string(&mut text).map_err(|err| {
+            format!("failed to read the response of {}: {}", self.url, err)
+        })?;
+        let response: DocumentResponse = serde_json::from_str(&text)
+            .map_err(|err| format!("invalid response from {}: {}", self.url, err))?;
+
+        Ok(response.pages.into_iter().map(String::into_bytes).collect())
+    }
+
+    fn name(&self) -> String {
+        format!("remote {}", self.url)
+    }
+}
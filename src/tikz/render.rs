@@ -0,0 +1,54 @@
+use std::fmt::{self, Debug, Formatter};
+use std::path::Path;
+
+use super::RasterFallback;
+
+/// Compiles the LaTeX documents of figures into SVGs in place of a local
+/// LaTeX installation, e.g. a remote service or the host of a WASM module.
+///
+/// Scanning, hashing, caching and replacement work as usual, only the
+/// compilation of new figures is delegated. Pass a renderer to
+/// `Tikz::with_renderer` to use it.
+pub trait Renderer: Send + Sync {
+    /// Compile the document of a figure and return one SVG per page, or the
+    /// LaTeX logs if it fails.
+    fn render(&self, request: &RenderRequest) -> Result<Vec<Vec<u8>>, String>;
+
+    /// Identifies the renderer and its toolchain, e.g. the address of a
+    /// service together with its TeX version.
+    ///
+    /// It is part of the hashes of all figures, so it should change whenever
+    /// the renderer would produce different images.
+    fn name(&self) -> String;
+}
+
+impl Debug for dyn Renderer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Renderer({})", self.name())
+    }
+}
+
+/// A figure that a renderer should compile.
+#[derive(Debug, Clone)]
+pub struct RenderRequest<'a> {
+    /// The hash of the figure.
+    pub hash: u64,
+    /// The complete LaTeX document that contains the figure.
+    pub document: &'a str,
+    /// The files that the figure references, under the paths by which the
+    /// document refers to them. Files that could not be read are left out.
+    pub inputs: Vec<(&'a Path, &'a [u8])>,
+    /// Whether to outline all glyphs in the generated SVGs.
+    pub outline_text: bool,
+    /// The LaTeX engine that the figure asks for, or `None` if the renderer
+    /// may choose.
+    pub engine: Option<&'a str>,
+    /// Whether LaTeX should produce a DVI file instead of a PDF, for which
+    /// the document is written.
+    pub dvi: bool,
+    /// Whether dvisvgm converts the output of LaTeX into SVGs, rather than
+    /// pdf2svg, which draws them differently.
+    pub dvisvgm: bool,
+    /// When SVGs that are too large are replaced by raster images.
+    pub raster: &'a RasterFallback,
+}
@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use super::{ReplaceReport, Replacement, Tikz};
+
+/// A document that shares its `Tikz` with other documents, e.g. in a build
+/// of all lectures of a course, or in a server that compiles for several
+/// editors at once.
+///
+/// The `Tikz` holds everything that documents share: the toolchain, the
+/// images in memory and the shared cache, so that a figure that several
+/// documents contain is compiled and stored once, even if the documents are
+/// compiled in parallel. A session only remembers which files of its
+/// document were replaced and what happened to their figures, so it is cheap
+/// to open one per document.
+///
+/// The figures that open sessions use are kept in memory by
+/// `Tikz::prune_sessions`. A session stops counting once it is dropped.
+pub struct Session<'a> {
+    tikz: &'a Tikz,
+    /// The key of the session in the `Tikz`.
+    id: u64,
+    /// The main file of the document.
+    document: PathBuf,
+    /// The last replacement of each file of the document.
+    files: Mutex<BTreeMap<PathBuf, File>>,
+}
+
+/// A file of a document as it was last replaced.
+#[derive(Clone)]
+struct File {
+    /// The text of the file.
+    text: String,
+    /// The replacement of the text.
+    replacement: Replacement,
+}
+
+impl Tikz {
+    /// Open a session for a document, named by its main file.
+    pub fn session(&self, document: impl Into<PathBuf>) -> Session<'_> {
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().unwrap().insert(id, HashSet::new());
+        Session {
+            tikz: self,
+            id,
+            document: document.into(),
+            files: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Remove all figures that no open session uses from the memory, e.g.
+    /// to keep a long-running server small.
+    ///
+    /// The shared cache is left alone, since other projects and processes
+    /// may use it as well; use `prune` or `collect_garbage` for that. Figures
+    /// of documents whose sessions were dropped are removed too. Does nothing
+    /// while no session is open.
+    pub fn prune_sessions(&self) {
+        let sessions = self.sessions.lock().unwrap();
+        if sessions.is_empty() {
+            return;
+        }
+
+        let keep: Vec<_> = sessions.values().flatten().copied().collect();
+        drop(sessions);
+        let keep = keep.iter().flat_map(|&hash| self.pool.pages(hash)).collect();
+        self.pool.retain(&keep);
+    }
+}
+
+impl Session<'_> {
+    /// The main file of the document.
+    pub fn document(&self) -> &Path {
+        &self.document
+    }
+
+    /// Replace the figures in a file of the document, like
+    /// `Tikz::replace_in` with the directory of the file.
+    ///
+    /// If the file was replaced before, only the parts that changed since are
+    /// replaced again, like with `Tikz::replace_incremental`. The report
+    /// replaces that of the earlier replacement, so figures that were removed
+    /// from the file no longer count as used.
+    pub fn replace_file(&self, path: &Path, buffer: &str) -> Replacement {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let previous = self.files.lock().unwrap().get(path).cloned();
+        let replacement = match previous {
+            Some(previous) => {
+                self.tikz
+                    .replace_incremental(
+                        &previous.text,
+                        &previous.replacement,
+                        buffer,
+                        dir,
+                    )
+                    .replacement
+            }
+            None => self.tikz.replace_in(buffer, dir),
+        };
+
+        let file = File {
+            text: buffer.into(),
+            replacement: replacement.clone(),
+        };
+        self.record(path, Some(file));
+        replacement
+    }
+
+    /// Forget a file that is no longer part of the document, together with
+    /// its figures.
+    pub fn forget(&self, path: &Path) {
+        self.record(path, None);
+    }
+
+    /// The reports of the replaced files, sorted by their paths.
+    pub fn reports(&self) -> BTreeMap<PathBuf, ReplaceReport> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, file)| (path.clone(), file.replacement.report.clone()))
+            .collect()
+    }
+
+    /// The hashes of the figures that the document uses.
+    pub fn hashes(&self) -> HashSet<u64> {
+        self.files
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|file| &file.replacement.report.blocks)
+            .map(|block| block.hash)
+            .collect()
+    }
+
+    /// Store or remove the last replacement of a file, and update the figures
+    /// that the document uses.
+    fn record(&self, path: &Path, file: Option<File>) {
+        let mut files = self.files.lock().unwrap();
+        match file {
+            Some(file) => files.insert(path.to_path_buf(), file),
+            None => files.remove(path),
+        };
+
+        let hashes = files
+            .values()
+            .flat_map(|file| &file.replacement.report.blocks)
+            .map(|block| block.hash)
+            .collect();
+        self.tikz.sessions.lock().unwrap().insert(self.id, hashes);
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        self.tikz.sessions.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,104 @@
+use std::ops::Range;
+
+/// Maps byte offsets between a rewritten buffer and its original.
+///
+/// Outside of the regions, both buffers are identical, only shifted by the
+/// difference in length of all the regions before.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// The replaced regions, in the order in which they appear.
+    pub regions: Vec<Region>,
+}
+
+/// A range of the rewritten buffer that replaced a range of the original.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Region {
+    /// The range in the rewritten buffer.
+    pub rewritten: Range<usize>,
+    /// The range in the original buffer.
+    pub original: Range<usize>,
+}
+
+impl SourceMap {
+    /// Translate an offset in the rewritten buffer into the original buffer.
+    ///
+    /// Offsets within a replaced region map to the start of its original.
+    pub fn to_original(&self, offset: usize) -> usize {
+        self.translate(offset, false, Side::Rewritten)
+    }
+
+    /// Translate a range in the rewritten buffer into the original buffer.
+    ///
+    /// A range that touches a replaced region covers all of its original.
+    pub fn to_original_range(&self, range: Range<usize>) -> Range<usize> {
+        self.translate(range.start, false, Side::Rewritten)
+            ..self.translate(range.end, true, Side::Rewritten)
+    }
+
+    /// Translate an offset in the original buffer into the rewritten buffer.
+    ///
+    /// Offsets within a replaced region map to the start of its replacement.
+    pub fn to_rewritten(&self, offset: usize) -> usize {
+        self.translate(offset, false, Side::Original)
+    }
+
+    /// Translate a range in the original buffer into the rewritten buffer.
+    ///
+    /// A range that touches a replaced region covers all of its replacement.
+    pub fn to_rewritten_range(&self, range: Range<usize>) -> Range<usize> {
+        self.translate(range.start, false, Side::Original)
+            ..self.translate(range.end, true, Side::Original)
+    }
+
+    /// Translate an offset from one side of the regions to the other.
+    ///
+    /// An offset within a region maps to the start of the region on the other
+    /// side, or to its end if `end` is set.
+    fn translate(&self, offset: usize, end: bool, from: Side) -> usize {
+        // The last region that starts before the offset, if any.
+        let index = self.regions.partition_point(|region| {
+            let start = from.of(region).start;
+            start < offset || (!end && start == offset)
+        });
+
+        let Some(region) = index.checked_sub(1).map(|index| &self.regions[index]) else {
+            return offset;
+        };
+
+        let (source, target) = (from.of(region), from.other().of(region));
+        if offset < source.end || (end && offset == source.end) {
+            if end {
+                target.end
+            } else {
+                target.start
+            }
+        } else {
+            target.end + (offset - source.end)
+        }
+    }
+}
+
+/// One of the two buffers of a source map.
+#[derive(Copy, Clone)]
+enum Side {
+    Rewritten,
+    Original,
+}
+
+impl Side {
+    /// The range of a region in this buffer.
+    fn of(self, region: &Region) -> &Range<usize> {
+        match self {
+            Self::Rewritten => &region.rewritten,
+            Self::Original => &region.original,
+        }
+    }
+
+    /// The other buffer.
+    fn other(self) -> Self {
+        match self {
+            Self::Rewritten => Self::Original,
+            Self::Original => Self::Rewritten,
+        }
+    }
+}
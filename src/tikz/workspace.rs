@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use glob::{MatchOptions, Pattern};
+use walkdir::WalkDir;
+
+use super::{compat, scan_in, BlockReport, Replacement, Tikz};
+
+/// The Typst files of a project that share their TikZ figures, e.g. one file
+/// per chapter of a lecture.
+///
+/// Since all files are processed by the same `Tikz`, a figure that appears in
+/// several files is only compiled once.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    /// The directory whose files are processed.
+    root: PathBuf,
+    /// Which of its files are processed.
+    selection: Selection,
+}
+
+/// Which files of a workspace are processed.
+#[derive(Debug, Clone)]
+enum Selection {
+    /// The files whose paths, relative to the root, match.
+    Pattern(Pattern),
+    /// A main file and the files that it includes or imports.
+    Includes(PathBuf),
+}
+
+/// What happened to the files of a workspace.
+#[derive(Debug, Clone, Default)]
+pub struct WorkspaceReport {
+    /// The processed files, sorted by their paths.
+    pub files: Vec<WorkspaceFile>,
+    /// How long it took to process all files.
+    pub duration: Duration,
+}
+
+/// A processed file of a workspace.
+#[derive(Debug, Clone)]
+pub struct WorkspaceFile {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The file with its figures replaced.
+    pub replacement: Replacement,
+}
+
+impl Workspace {
+    /// A workspace with the files below `root` whose relative paths match a
+    /// glob pattern, e.g. `chapters/*.typ` or `**/*.typ`.
+    ///
+    /// In the pattern, `*` matches any part of a file or directory name, `?`
+    /// matches a single character, `[...]` matches one of the characters in
+    /// the brackets, and `**/` matches any number of directories. Paths are
+    /// always separated by `/`. Fails if the pattern is invalid, e.g. if a
+    /// bracket is not closed.
+    pub fn new(root: impl Into<PathBuf>, pattern: &str) -> Result<Self, String> {
+        let pattern = Pattern::new(pattern)
+            .map_err(|err| format!("invalid glob pattern {pattern:?}: {err}"))?;
+        Ok(Self {
+            root: root.into(),
+            selection: Selection::Pattern(pattern),
+        })
+    }
+
+    /// A workspace with a main file and the Typst files that it includes or
+    /// imports, directly or through other files, e.g. the chapters of a
+    /// lecture that are pulled in with `#include "ch1.typ"`.
+    ///
+    /// Paths are resolved like Typst does: relative to the including file,
+    /// or to `root` if they start with `/`. Packages, computed paths and
+    /// files that do not exist are skipped.
+    pub fn with_includes(root: impl Into<PathBuf>, main: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            selection: Selection::Includes(main.into()),
+        }
+    }
+
+    /// The files of the workspace, sorted by their paths.
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let pattern = match &self.selection {
+            Selection::Pattern(pattern) => pattern,
+            Selection::Includes(main) => return self.included(main),
+        };
+
+        // As documented in `new`, `*` and `?` never match a `/`.
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        let mut files = vec![];
+        for entry in WalkDir::new(&self.root).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let Ok(relative) = entry.path().strip_prefix(&self.root) else { continue };
+            let relative: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            if pattern.matches_with(&relative.join("/"), options) {
+                files.push(entry.into_path());
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// The main file and the files that it includes or imports, sorted by
+    /// their paths. Each file is visited once, even if includes form a cycle.
+    fn included(&self, main: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut seen = HashSet::new();
+        let mut queue = vec![main.to_path_buf()];
+        while let Some(path) = queue.pop() {
+            if !seen.insert(fs::canonicalize(&path)?) {
+                continue;
+            }
+
+            let text = fs::read_to_string(&path)?;
+            let dir = path.parent().unwrap_or(Path::new(""));
+            for target in compat::includes(&text) {
+                if target.starts_with('@') {
+                    continue;
+                }
+
+                let resolved = match target.strip_prefix('/') {
+                    Some(rest) => self.root.join(rest),
+                    None => dir.join(&target),
+                };
+                if resolved.is_file() {
+                    queue.push(resolved);
+                }
+            }
+
+            files.push(path);
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Replace the figures in all files of the workspace.
+    ///
+    /// The new figures of all files are compiled first, on up to `jobs`
+    /// threads in total, and then the files are replaced one by one. Files
+    /// that the figures reference are resolved against the directory of their
+    /// file, like in `Tikz::replace_in`. The files themselves are not changed.
+    pub fn replace(&self, tikz: &Tikz) -> io::Result<WorkspaceReport> {
+        let start = Instant::now();
+        let mut sources = vec![];
+        for path in self.files()? {
+            let text = fs::read_to_string(&path)?;
+            sources.push((path, text));
+        }
+
+        let mut scanned = vec![];
+        for (path, text) in &sources {
+            let found = scan_in(text, path.parent().unwrap_or(Path::new("")));
+            let hashes = tikz.digests(&found);
+            tikz.refresh(&found, &hashes);
+            let cached = tikz.cached(&hashes);
+            scanned.push((found, hashes, cached));
+        }
+
+        // Figures that several files share are compiled once.
+        let mut queued = HashSet::new();
+        let jobs = scanned
+            .iter()
+            .flat_map(|(found, hashes, cached)| tikz.jobs(found, hashes, cached))
+            .filter(|job| queued.insert(job.hash))
+            .collect();
+        tikz.compile_jobs(jobs, false);
+
+        let mut files = Vec::with_capacity(sources.len());
+        for ((path, buffer), (found, hashes, cached)) in sources.iter().zip(scanned) {
+            let mut text = String::with_capacity(buffer.len());
+            let replaced =
+                tikz.replace_with(buffer, found, &hashes, &cached, &mut |chunk| {
+                    text.push_str(chunk);
+                    Ok(())
+                });
+            let replacement = Replacement {
+                text,
+                ..replaced.expect("writing to a string cannot fail")
+            };
+            files.push(WorkspaceFile { path: path.clone(), replacement });
+        }
+
+        Ok(WorkspaceReport { files, duration: start.elapsed() })
+    }
+}
+
+impl WorkspaceReport {
+    /// The number of figures in all files.
+    pub fn figures(&self) -> usize {
+        self.blocks().count()
+    }
+
+    /// The number of distinct figures in all files. Each of them was compiled
+    /// at most once.
+    pub fn unique_figures(&self) -> usize {
+        self.blocks().map(|block| block.hash).collect::<HashSet<_>>().len()
+    }
+
+    /// The number of figures whose images were already compiled before they
+    /// were needed.
+    pub fn cache_hits(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.replacement.report.cache_hits())
+            .sum()
+    }
+
+    /// The number of figures that failed to compile or to embed.
+    pub fn failures(&self) -> usize {
+        self.files.iter().map(|file| file.replacement.report.failures()).sum()
+    }
+
+    /// The figures of all files.
+    fn blocks(&self) -> impl Iterator<Item = &BlockReport> {
+        self.files.iter().flat_map(|file| &file.replacement.report.blocks)
+    }
+}
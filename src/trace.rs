@@ -6,6 +6,7 @@ use inferno::flamegraph::Options;
 use tracing::metadata::LevelFilter;
 use tracing_error::ErrorLayer;
 use tracing_flame::{FlameLayer, FlushGuard};
+use tracing_subscriber::filter::Targets;
 use tracing_subscriber::fmt;
 use tracing_subscriber::prelude::*;
 
@@ -77,14 +78,16 @@ pub fn init_tracing(args: &CliArguments) -> Result<Option<TracingGuard>, Error>
     if flamegraph.is_none() && args.verbosity == 0 {
         tracing_subscriber::fmt()
             .without_time()
-            .with_max_level(level_filter(args))
+            .with_max_level(LevelFilter::TRACE)
+            .finish()
+            .with(targets(args))
             .init();
 
         return Ok(None);
     }
 
     // Build the FMT layer printing to the console.
-    let fmt_layer = fmt::Layer::default().without_time().with_filter(level_filter(args));
+    let fmt_layer = fmt::Layer::default().without_time().with_filter(targets(args));
 
     // Error layer for building backtraces
     let error_layer = ErrorLayer::default();
@@ -124,6 +127,14 @@ pub fn init_tracing(args: &CliArguments) -> Result<Option<TracingGuard>, Error>
     }))
 }
 
+/// Returns the filter for the given verbosity level. The TikZ support is let
+/// through completely, since it filters its events by its own verbosity.
+fn targets(args: &CliArguments) -> Targets {
+    Targets::new()
+        .with_default(level_filter(args))
+        .with_target("typst_tikz::tikz", LevelFilter::TRACE)
+}
+
 /// Returns the log level filter for the given verbosity level.
 fn level_filter(args: &CliArguments) -> LevelFilter {
     match args.verbosity {